@@ -0,0 +1,153 @@
+#![no_main]
+
+use arbitrary::Arbitrary;
+use intel8080::{Bus, CPU, CycleKind, Flags, Instruction, MemoryMap, Register};
+use libfuzzer_sys::fuzz_target;
+
+/// Instructions executed per input.
+const STEPS: usize = 1_000;
+
+#[derive(Debug, Arbitrary)]
+struct Input {
+    pc: u16,
+    sp: u16,
+    registers: [u8; 7],
+    flags: u8,
+    memory: Vec<u8>,
+    /// Bytes returned to port reads and interrupt acknowledges.
+    data: Vec<u8>,
+    /// Wait states returned to the bus hook.
+    wait_states: Vec<u8>,
+    /// Instruction supplied through `CPU::interrupt`, or latched on INT.
+    interrupt: Vec<u8>,
+    /// Whether interrupts start enabled, with the CPU halted, HOLD active or
+    /// INT asserted, one bit each.
+    state: u8,
+    /// Step at which HOLD goes inactive.
+    hold: u16,
+    /// Layout of the address space, one region per page.
+    pages: Vec<u8>,
+}
+
+/// A bus returning fuzzed data and requesting interrupts at will.
+struct FuzzBus<'a> {
+    data: &'a [u8],
+    wait_states: &'a [u8],
+    reads: usize,
+}
+
+impl FuzzBus<'_> {
+    fn next(&mut self, values: fn(&Self) -> &[u8]) -> u8 {
+        let values = values(self);
+        let value = match values.len() {
+            0 => 0xff,
+            len => values[self.reads % len],
+        };
+        self.reads = self.reads.wrapping_add(1);
+        value
+    }
+}
+
+impl Bus for FuzzBus<'_> {
+    fn read(&mut self, _cpu: &CPU, _port: u8) -> u8 {
+        self.next(|bus| bus.data)
+    }
+
+    fn write(&mut self, _cpu: &CPU, _port: u8, _data: u8) {}
+
+    fn wait_states(&mut self, _cpu: &CPU, _kind: CycleKind, _addr: u16) -> u8 {
+        self.next(|bus| bus.wait_states)
+    }
+
+    fn interrupt_requested(&mut self, _cpu: &CPU) -> bool {
+        self.next(|bus| bus.data) & 1 != 0
+    }
+
+    fn acknowledge(&mut self, _cpu: &CPU) -> u8 {
+        self.next(|bus| bus.data)
+    }
+}
+
+/// Builds a memory map from one byte per page, covering every kind of region.
+fn memory_map(pages: &[u8]) -> MemoryMap {
+    let mut map = MemoryMap::new();
+
+    for (page, &kind) in pages.iter().take(256).enumerate() {
+        let start = (page * 0x100) as u16;
+        let range = start..=start | 0xff;
+
+        map = match kind % 6 {
+            0 => map.ram(range),
+            1 => map.rom(range),
+            2 => map.unmapped(range),
+            3 => map.mirror(range, 0x0000..=0x00ff),
+            4 => map.banked(range, kind / 6, kind),
+            _ => map.wait_states(range, kind),
+        };
+    }
+
+    map
+}
+
+fuzz_target!(|input: Input| {
+    let registers = [
+        Register::B,
+        Register::C,
+        Register::D,
+        Register::E,
+        Register::H,
+        Register::L,
+        Register::A,
+    ];
+
+    let mut builder = CPU::builder()
+        .pc(input.pc)
+        .sp(input.sp)
+        .flags(Flags::from_bits(input.flags))
+        .load(0, &input.memory)
+        .memory_map(memory_map(&input.pages));
+    for (reg, value) in registers.into_iter().zip(input.registers) {
+        builder = builder.register(reg, value);
+    }
+
+    // Banked windows without banks are rejected rather than mapped
+    let Ok(mut cpu) = builder.build() else {
+        return;
+    };
+
+    let mut bus = FuzzBus {
+        data: &input.data,
+        wait_states: &input.wait_states,
+        reads: 0,
+    };
+
+    if input.state & 1 != 0 {
+        let _ = cpu.execute(&mut bus, Instruction::Ei);
+    }
+    if input.state & 2 != 0 {
+        let _ = cpu.execute(&mut bus, Instruction::Hlt);
+    }
+    if input.state & 4 != 0 {
+        cpu.set_hold(true);
+    }
+    if input.state & 8 != 0 {
+        cpu.assert_interrupt(&input.interrupt);
+    }
+
+    // Interrupts are only taken once enabled, so keep offering one until then
+    let mut interrupted = false;
+    for step in 0..STEPS {
+        if step == input.hold as usize {
+            cpu.set_hold(false);
+        }
+        if !interrupted {
+            interrupted = cpu.interrupt(&input.interrupt);
+        }
+
+        if step % 2 == 0 {
+            cpu.cycle(&mut bus);
+        } else {
+            cpu.step(&mut bus);
+        }
+    }
+});
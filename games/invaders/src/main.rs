@@ -1,7 +1,9 @@
-use intel8080::{Bus, CPU, MEM_SIZE, RATE};
+use intel8080::{
+    Bus, CPU, Device, MemoryMap, RATE, Recorder, Recording, Replay, Rewind, Scheduler, StateError,
+};
 use pixels::{Pixels, SurfaceTexture};
 use rodio::{Decoder, OutputStream, OutputStreamHandle, Sink};
-use std::fs::{File, read};
+use std::fs::{File, read, write};
 use std::io::{BufReader, Cursor};
 use std::sync::Arc;
 use winit::dpi::LogicalSize;
@@ -13,6 +15,11 @@ use winit::window::WindowBuilder;
 use winit_input_helper::WinitInputHelper;
 
 const FPS: u32 = 60;
+const HALF_FRAME: u64 = (RATE / FPS / 2) as u64;
+
+// Interrupts raised by the video hardware
+const MID_SCREEN: u8 = 0xcf;
+const VBLANK: u8 = 0xd7;
 const XSCALE: f64 = 2.5;
 const YSCALE: f64 = 2.5;
 const WIDTH: u32 = 224;
@@ -29,6 +36,15 @@ const START1: KeyCode = KeyCode::Digit1;
 const START2: KeyCode = KeyCode::Digit2;
 const TILT: KeyCode = KeyCode::Space;
 const SPEED: KeyCode = KeyCode::Tab;
+const SAVE: KeyCode = KeyCode::F5;
+const LOAD: KeyCode = KeyCode::F9;
+const REWIND: KeyCode = KeyCode::Backspace;
+const RECORD: KeyCode = KeyCode::F6;
+
+const SAVE_STATE: &str = "games/invaders/invaders.state";
+const RECORDING: &str = "games/invaders/invaders.replay";
+// Ten seconds of frames
+const REWIND_FRAMES: usize = FPS as usize * 10;
 
 fn main() -> Result<(), Error> {
     let event_loop = EventLoop::new()?;
@@ -68,31 +84,47 @@ fn main() -> Result<(), Error> {
         Pixels::new(WIDTH, HEIGHT, surface_texture)?
     };
 
-    let mut cpu = load_rom();
-    let mut controls = Invaders::new(&stream_handle)?;
+    // Passing `--replay <file>` plays back a recording made with the record key
+    let recording = match std::env::args().skip_while(|arg| arg != "--replay").nth(1) {
+        Some(path) => Some(Recording::load(&read(path)?)?),
+        None => None,
+    };
+
+    let mut cpu = match &recording {
+        Some(recording) => recording.start()?,
+        None => load_rom(),
+    };
+    let mut replay = recording.map(Replay::new);
+    let mut controls = Recorder::new(Invaders::new(&stream_handle)?);
     let mut speed = 5;
-    let mut count = 0;
-    let mut interrupt = 0xcf;
+    let mut scheduler = restart_video(&cpu, controls.bus_mut());
+    let mut rewind = Rewind::new(2 * HALF_FRAME, REWIND_FRAMES);
 
     event_loop.run(|event, elwt| {
-        let cpf = RATE / FPS;
-        let mut cycles = 0;
-
-        while cycles < speed * (RATE / 1000) {
-            let spent = cpu.cycle(&mut controls) as u32;
-            cycles += spent;
-            count += spent;
+        let end = cpu.cycles() + u64::from(speed * (RATE / 1000));
 
-            if count >= cpf / 2 {
-                count -= cpf / 2;
+        if let Some(replay) = &mut replay {
+            // Recorded inputs drive the CPU in place of the controls, so the
+            // replay is silent
+            let start = cpu.cycles();
+            replay.run(&mut cpu, end);
 
-                cpu.interrupt(interrupt);
+            if start / (2 * HALF_FRAME) != cpu.cycles() / (2 * HALF_FRAME) {
+                window.request_redraw();
+            }
 
-                if interrupt != 0xcf {
-                    window.request_redraw();
+            if start < replay.end() && cpu.cycles() >= replay.end() {
+                match replay.diverged() {
+                    Some(cycle) => eprintln!("Replay diverged from the recording at cycle {cycle}"),
+                    None => eprintln!("Replay finished"),
                 }
+            }
+        } else {
+            scheduler.run(&mut cpu, &mut controls, end);
+            rewind.record(&cpu, &controls.bus().hardware);
 
-                interrupt = if interrupt == 0xcf { 0xd7 } else { 0xcf };
+            if std::mem::take(&mut controls.bus_mut().frame) {
+                window.request_redraw();
             }
         }
 
@@ -102,8 +134,8 @@ fn main() -> Result<(), Error> {
             ..
         } = event
         {
-            let display = &cpu.memory()[0x2400..=0x3fff];
-            map_display(display, pixels.frame_mut());
+            let display = cpu.memory().read_range(0x2400..=0x3fff);
+            map_display(&display, pixels.frame_mut());
 
             if let Err(error) = pixels.render() {
                 eprintln!("{error}");
@@ -129,64 +161,71 @@ fn main() -> Result<(), Error> {
                 }
             }
 
+            // Controls have no effect on a replay
+            if replay.is_some() {
+                return;
+            }
+
+            let invaders = controls.bus_mut();
+
             if input.key_pressed(KeyCode::KeyC) {
-                controls.input1 |= 1;
+                invaders.input1 |= 1;
             } else {
-                controls.input1 &= 0b1111_1110;
+                invaders.input1 &= 0b1111_1110;
             }
 
             if input.key_pressed(START1) {
-                controls.input1 |= 1 << 2;
+                invaders.input1 |= 1 << 2;
             } else {
-                controls.input1 &= 0b1111_1011;
+                invaders.input1 &= 0b1111_1011;
             }
 
             if input.key_pressed(START2) {
-                controls.input1 |= 1 << 1;
+                invaders.input1 |= 1 << 1;
             } else {
-                controls.input1 &= 0b1111_1101;
+                invaders.input1 &= 0b1111_1101;
             }
 
             if input.key_pressed(LEFT1) || input.key_held(LEFT1) {
-                controls.input1 |= 1 << 5;
+                invaders.input1 |= 1 << 5;
             } else {
-                controls.input1 &= 0b1101_1111;
+                invaders.input1 &= 0b1101_1111;
             }
 
             if input.key_pressed(LEFT2) || input.key_held(LEFT2) {
-                controls.input2 |= 1 << 5;
+                invaders.input2 |= 1 << 5;
             } else {
-                controls.input2 &= 0b1101_1111;
+                invaders.input2 &= 0b1101_1111;
             }
 
             if input.key_pressed(RIGHT1) || input.key_held(RIGHT1) {
-                controls.input1 |= 1 << 6;
+                invaders.input1 |= 1 << 6;
             } else {
-                controls.input1 &= 0b1011_1111;
+                invaders.input1 &= 0b1011_1111;
             }
 
             if input.key_pressed(RIGHT2) || input.key_held(RIGHT2) {
-                controls.input2 |= 1 << 6;
+                invaders.input2 |= 1 << 6;
             } else {
-                controls.input2 &= 0b1011_1111;
+                invaders.input2 &= 0b1011_1111;
             }
 
             if input.key_pressed(SHOOT1) || input.key_held(SHOOT1) {
-                controls.input1 |= 1 << 4;
+                invaders.input1 |= 1 << 4;
             } else {
-                controls.input1 &= 0b1110_1111;
+                invaders.input1 &= 0b1110_1111;
             }
 
             if input.key_pressed(SHOOT2) || input.key_held(SHOOT2) {
-                controls.input2 |= 1 << 4;
+                invaders.input2 |= 1 << 4;
             } else {
-                controls.input2 &= 0b1110_1111;
+                invaders.input2 &= 0b1110_1111;
             }
 
             if input.key_pressed(TILT) || input.key_held(TILT) {
-                controls.input2 |= 1 << 2;
+                invaders.input2 |= 1 << 2;
             } else {
-                controls.input2 &= 0b1111_1011;
+                invaders.input2 &= 0b1111_1011;
             }
 
             if input.key_pressed(SPEED) && !input.held_shift() {
@@ -197,6 +236,67 @@ fn main() -> Result<(), Error> {
                 speed = (speed - 1).max(3);
             }
 
+            if input.key_pressed(SAVE) {
+                // The hardware is saved ahead of the CPU
+                let mut state = Vec::with_capacity(Hardware::LEN);
+                controls.bus().hardware.save(&mut state);
+                state.extend(cpu.save_state());
+
+                if let Err(error) = write(SAVE_STATE, state) {
+                    eprintln!("Error when saving state");
+                    eprintln!("{error}");
+                }
+            }
+
+            if input.key_pressed(LOAD) {
+                let loaded = read(SAVE_STATE)
+                    .map_err(|error| error.to_string())
+                    .and_then(|state| {
+                        let (hardware, state) =
+                            Hardware::load(&state).map_err(|error| error.to_string())?;
+                        cpu.load_state(state).map_err(|error| error.to_string())?;
+
+                        Ok(hardware)
+                    });
+
+                match loaded {
+                    Ok(hardware) => {
+                        controls.bus_mut().hardware = hardware;
+                        scheduler = controls.bus().video();
+                        rewind.clear();
+
+                        // A recording in progress carries on from the loaded
+                        // state
+                        if controls.is_recording() {
+                            controls.record(&cpu);
+                        }
+                    }
+                    Err(error) => {
+                        eprintln!("Error when loading state");
+                        eprintln!("{error}");
+                    }
+                }
+            }
+
+            if (input.key_pressed(REWIND) || input.key_held(REWIND))
+                && rewind.rewind(&mut cpu, &mut controls.bus_mut().hardware, 1) > 0
+            {
+                scheduler = controls.bus().video();
+                controls.rewind(&cpu);
+            }
+
+            if input.key_pressed(RECORD) {
+                match controls.stop(&cpu) {
+                    Some(recording) => {
+                        if let Err(error) = write(RECORDING, recording.save()) {
+                            eprintln!("Error when saving recording");
+                            eprintln!("{error}");
+                        }
+                    }
+                    None => controls.record(&cpu),
+                }
+            }
+
             window.request_redraw();
         }
     })?;
@@ -204,21 +304,31 @@ fn main() -> Result<(), Error> {
     Ok(())
 }
 
-fn load_rom() -> CPU {
-    let mut program = [0u8; MEM_SIZE];
-    let f1 = include_bytes!("../invaders/invaders.h");
-    program[0..0x0800].copy_from_slice(f1);
+/// Returns a [`Scheduler`] raising video interrupts from the current cycle of
+/// `cpu`, dropping any interrupt still pending.
+fn restart_video(cpu: &CPU, controls: &mut Invaders<'_>) -> Scheduler<u8> {
+    controls.hardware.interrupt = None;
+    controls.hardware.video = (cpu.cycles() + HALF_FRAME, MID_SCREEN);
 
-    let f2 = include_bytes!("../invaders/invaders.g");
-    program[0x0800..0x1000].copy_from_slice(f2);
-
-    let f3 = include_bytes!("../invaders/invaders.f");
-    program[0x1000..0x1800].copy_from_slice(f3);
-
-    let f4 = include_bytes!("../invaders/invaders.e");
-    program[0x1800..0x2000].copy_from_slice(f4);
+    controls.video()
+}
 
-    CPU::new(&program)
+fn load_rom() -> CPU {
+    // 8K of ROM followed by 8K of RAM which is mirrored in the upper address
+    // space.
+    let map = MemoryMap::new()
+        .rom(0x0000..=0x1fff)
+        .ram(0x2000..=0x3fff)
+        .mirror(0x4000..=0xffff, 0x2000..=0x3fff);
+
+    CPU::builder()
+        .load(0x0000, include_bytes!("../invaders/invaders.h"))
+        .load(0x0800, include_bytes!("../invaders/invaders.g"))
+        .load(0x1000, include_bytes!("../invaders/invaders.f"))
+        .load(0x1800, include_bytes!("../invaders/invaders.e"))
+        .memory_map(map)
+        .build()
+        .expect("Space Invaders memory map should be page aligned")
 }
 
 fn load_audio(handle: &OutputStreamHandle) -> Result<[Sfx<'_>; 9], Error> {
@@ -310,12 +420,61 @@ struct Invaders<'a> {
     ///DIP3-6: Number of starting lives
     input2: u8,
 
-    shift_data: u16,
-    shift_offset: u8,
+    hardware: Hardware,
 
     sfx: [Sfx<'a>; 9],
+    /// Set once a frame has been completed
+    frame: bool,
+}
+
+/// State of the hardware which is saved and rewound along with the CPU.
+#[derive(Debug, Clone, Copy)]
+struct Hardware {
+    shift_data: u16,
+    shift_offset: u8,
     port3: u8,
     port5: u8,
+    /// RST supplied by the pending video interrupt
+    interrupt: Option<u8>,
+    /// Cycle of the next video interrupt and its RST
+    video: (u64, u8),
+}
+
+impl Hardware {
+    /// Number of bytes written by [`Hardware::save`].
+    const LEN: usize = 15;
+
+    /// Appends the state of the hardware to `state`.
+    fn save(&self, state: &mut Vec<u8>) {
+        state.extend_from_slice(&self.shift_data.to_le_bytes());
+        state.extend_from_slice(&[self.shift_offset, self.port3, self.port5]);
+        // RSTs are never 0
+        state.push(self.interrupt.unwrap_or(0));
+        state.extend_from_slice(&self.video.0.to_le_bytes());
+        state.push(self.video.1);
+    }
+
+    /// Reads the state of the hardware saved by [`Hardware::save`] off the
+    /// start of `state`, returning the rest.
+    fn load(state: &[u8]) -> Result<(Self, &[u8]), StateError> {
+        let (hardware, rest) = state
+            .split_first_chunk::<{ Self::LEN }>()
+            .ok_or(StateError::Truncated)?;
+
+        let mut video = [0; 8];
+        video.copy_from_slice(&hardware[6..14]);
+
+        let hardware = Self {
+            shift_data: u16::from_le_bytes([hardware[0], hardware[1]]),
+            shift_offset: hardware[2] & 7,
+            port3: hardware[3],
+            port5: hardware[4],
+            interrupt: Some(hardware[5]).filter(|&rst| rst != 0),
+            video: (u64::from_le_bytes(video), hardware[14]),
+        };
+
+        Ok((hardware, rest))
+    }
 }
 
 impl<'a> Invaders<'a> {
@@ -323,18 +482,59 @@ impl<'a> Invaders<'a> {
         let sfx = load_audio(handle)?;
 
         Ok(Self {
-            shift_data: 0,
             input1: 0b0000_1000,
             input2: 0b0000_0001,
-            shift_offset: 0,
-            port3: 0,
-            port5: 0,
+            hardware: Hardware {
+                shift_data: 0,
+                shift_offset: 0,
+                port3: 0,
+                port5: 0,
+                interrupt: None,
+                video: (HALF_FRAME, MID_SCREEN),
+            },
             sfx,
+            frame: false,
         })
     }
+
+    /// Returns a [`Scheduler`] with the next video interrupt.
+    fn video(&self) -> Scheduler<u8> {
+        let (at, rst) = self.hardware.video;
+
+        let mut scheduler = Scheduler::new();
+        scheduler.schedule(at, rst);
+
+        scheduler
+    }
+}
+
+impl Device for Invaders<'_> {
+    /// RST raised by the video hardware
+    type Event = u8;
+
+    fn fire(&mut self, _cpu: &mut CPU, scheduler: &mut Scheduler<u8>, at: u64, rst: u8) {
+        self.hardware.interrupt = Some(rst);
+        self.frame |= rst == VBLANK;
+
+        let next = if rst == MID_SCREEN {
+            VBLANK
+        } else {
+            MID_SCREEN
+        };
+        self.hardware.video = (at + HALF_FRAME, next);
+        scheduler.schedule(at + HALF_FRAME, next);
+    }
 }
 
 impl Bus for Invaders<'_> {
+    fn interrupt_requested(&mut self, _cpu: &CPU) -> bool {
+        self.hardware.interrupt.is_some()
+    }
+
+    fn acknowledge(&mut self, _cpu: &CPU) -> u8 {
+        self.hardware.interrupt.take().unwrap_or(0xff)
+    }
+
     fn read(&mut self, _cpu: &CPU, port: u8) -> u8 {
         match port {
             0 => {
@@ -353,7 +553,7 @@ impl Bus for Invaders<'_> {
             1 => self.input1,
             2 => self.input2,
             3 => {
-                let value = (self.shift_data >> (8 - self.shift_offset)) & 0xff;
+                let value = (self.hardware.shift_data >> (8 - self.hardware.shift_offset)) & 0xff;
                 value as u8
             }
             unknown => {
@@ -365,84 +565,84 @@ impl Bus for Invaders<'_> {
     fn write(&mut self, _cpu: &CPU, port: u8, data: u8) {
         match port {
             2 => {
-                self.shift_offset = data & 7;
+                self.hardware.shift_offset = data & 7;
             }
             4 => {
                 let data = (data as u16) << 8;
-                self.shift_data = (self.shift_data >> 8) | data;
+                self.hardware.shift_data = (self.hardware.shift_data >> 8) | data;
             }
             3 => {
-                if data == self.port3 {
+                if data == self.hardware.port3 {
                     return;
                 }
 
-                if (data & 1 != 0) && (self.port3 & 1 == 0) {
+                if (data & 1 != 0) && (self.hardware.port3 & 1 == 0) {
                     if let Err(error) = self.sfx[0].play() {
                         eprintln!("Error when playing sfx 0");
                         eprintln!("{error}");
                     }
                 }
 
-                if (data & 0b10 != 0) && (self.port3 & 0b10 == 0) {
+                if (data & 0b10 != 0) && (self.hardware.port3 & 0b10 == 0) {
                     if let Err(error) = self.sfx[1].play() {
                         eprintln!("Error when playing sfx 1");
                         eprintln!("{error}");
                     }
                 }
 
-                if (data & 0b100 != 0) && (self.port3 & 0b100 == 0) {
+                if (data & 0b100 != 0) && (self.hardware.port3 & 0b100 == 0) {
                     if let Err(error) = self.sfx[2].play() {
                         eprintln!("Error when playing sfx 2");
                         eprintln!("{error}");
                     }
                 }
 
-                if (data & 0b1000 != 0) && (self.port3 & 0b1000 == 0) {
+                if (data & 0b1000 != 0) && (self.hardware.port3 & 0b1000 == 0) {
                     if let Err(error) = self.sfx[3].play() {
                         eprintln!("Error when playing sfx 3");
                         eprintln!("{error}");
                     }
                 }
 
-                self.port3 = data;
+                self.hardware.port3 = data;
             }
             5 => {
-                if (data & 1 != 0) && (self.port5 & 1 == 0) {
+                if (data & 1 != 0) && (self.hardware.port5 & 1 == 0) {
                     if let Err(error) = self.sfx[4].play() {
                         eprintln!("Error when playing sfx 4");
                         eprintln!("{error}");
                     }
                 }
 
-                if (data & 0b10 != 0) && (self.port5 & 0b10 == 0) {
+                if (data & 0b10 != 0) && (self.hardware.port5 & 0b10 == 0) {
                     if let Err(error) = self.sfx[5].play() {
                         eprintln!("Error when playing sfx 5");
                         eprintln!("{error}");
                     }
                 }
 
-                if (data & 0b100 != 0) && (self.port5 & 0b100 == 0) {
+                if (data & 0b100 != 0) && (self.hardware.port5 & 0b100 == 0) {
                     if let Err(error) = self.sfx[6].play() {
                         eprintln!("Error when playing sfx 6");
                         eprintln!("{error}");
                     }
                 }
 
-                if (data & 0b1000 != 0) && (self.port5 & 0b1000 == 0) {
+                if (data & 0b1000 != 0) && (self.hardware.port5 & 0b1000 == 0) {
                     if let Err(error) = self.sfx[7].play() {
                         eprintln!("Error when playing sfx 7");
                         eprintln!("{error}");
                     }
                 }
 
-                if (data & 0b10000 != 0) && (self.port5 & 0b10000 == 0) {
+                if (data & 0b10000 != 0) && (self.hardware.port5 & 0b10000 == 0) {
                     if let Err(error) = self.sfx[8].play() {
                         eprintln!("Error when playing sfx 8");
                         eprintln!("{error}");
                     }
                 }
 
-                self.port5 = data;
+                self.hardware.port5 = data;
             }
             //Watchdog ... read or write to reset
             6 => {}
@@ -514,6 +714,7 @@ pub enum Error {
     Stream(rodio::StreamError),
     OS(OsError),
     EventLoop(EventLoopError),
+    State(StateError),
 }
 
 impl From<rodio::DevicesError> for Error {
@@ -558,6 +759,12 @@ impl From<OsError> for Error {
     }
 }
 
+impl From<StateError> for Error {
+    fn from(value: StateError) -> Self {
+        Self::State(value)
+    }
+}
+
 impl From<EventLoopError> for Error {
     fn from(value: EventLoopError) -> Self {
         Self::EventLoop(value)
@@ -575,6 +782,7 @@ impl std::fmt::Display for Error {
             Self::Decoder(error) => error.fmt(f),
             Self::OS(error) => error.fmt(f),
             Self::EventLoop(error) => error.fmt(f),
+            Self::State(error) => error.fmt(f),
         }
     }
 }
@@ -590,6 +798,7 @@ impl std::error::Error for Error {
             Self::Decoder(error) => Some(error),
             Self::OS(error) => Some(error),
             Self::EventLoop(error) => Some(error),
+            Self::State(error) => Some(error),
         }
     }
 }
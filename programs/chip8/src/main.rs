@@ -1,9 +1,12 @@
 #![allow(unused_imports, dead_code)]
-use intel8080::{Bus, CPU, MEM_SIZE, RATE};
+use intel8080::{
+    Bus, CPU, Device, MEM_SIZE, Memory, RATE, Recorder, Recording, Register, Replay, Rewind,
+    Scheduler, StateError,
+};
 use minifb::{Key, KeyRepeat, Scale, Window, WindowOptions};
 use pixels::{Pixels, SurfaceTexture};
 use rodio::{Decoder, OutputStream, OutputStreamHandle, Sink};
-use std::fs::{File, read};
+use std::fs::{File, read, write};
 use std::io::{self, BufWriter, Read, Write};
 use std::io::{BufReader, Cursor};
 use std::sync::Arc;
@@ -15,6 +18,15 @@ const HEIGHT: usize = 32;
 const SCALE: usize = 10;
 const I: usize = 0x10;
 const GFX: usize = 4116;
+/// T-states between decrements of the delay and sound timers
+const TIMER_PERIOD: u64 = RATE as u64 * 4_500 / 1_000_000;
+/// T-states between rewind snapshots, one per 60Hz frame
+const REWIND_PERIOD: u64 = RATE as u64 / 60;
+/// Ten seconds of rewind snapshots
+const REWIND_FRAMES: usize = 600;
+const REWIND: Key = Key::Backspace;
+const RECORD: Key = Key::F6;
+const RECORDING: &str = "programs/chip8/chip8.replay";
 
 #[rustfmt::skip]
 const CHIP_FONTSET: [u8; 80] = [
@@ -1604,7 +1616,7 @@ fn test_main() -> Result<(), Error> {
     let chip8 = [0xf5, 0x55, 0xa2, 0x58, 0xf5, 0x65];
 
     let mut cpu = load_rom(&chip8);
-    let mut chip = Chip::new();
+    let mut chip = Chip::new(cpu.cycles() + TIMER_PERIOD);
     let mut count = 0;
 
     // let start = 6364;
@@ -1620,7 +1632,7 @@ fn test_main() -> Result<(), Error> {
     // println!("{:02x}", cpu.memory()[0x1d3e]);
     // println!("{:02x}", cpu.memory()[0x1d3f]);
 
-    // println!("PC: 0x{:02x}{:02x}", cpu.register(4), cpu.register(5));
+    // println!("PC: 0x{:02x}{:02x}", cpu.register(Register::H), cpu.register(Register::L));
     // 12362
     // 23217
     // 22387 // SP still 0
@@ -1653,17 +1665,17 @@ fn test_main() -> Result<(), Error> {
             }
             Some("d") => {
                 debug_chip(&cpu);
-                println!("{:?}", &cpu.memory()[6164..6168]);
+                println!("{:?}", cpu.memory().read_range(6164..6168));
             }
             Some("f") => {
                 let start = 0x14;
-                println!("{:?}", &cpu.memory()[start..start + 5])
+                println!("{:?}", cpu.memory().read_range(start..start + 5))
             }
             Some("g") => {
-                println!("{:?}", &cpu.memory()[4116..4120]);
+                println!("{:?}", cpu.memory().read_range(4116..4120));
             }
             Some("m") => {
-                println!("{:?}", &cpu.memory()[532..541]);
+                println!("{:?}", cpu.memory().read_range(532..541));
             }
             Some("s") => {
                 println!("0x{:02x}", &cpu.memory()[18]);
@@ -1688,8 +1700,21 @@ fn main() -> Result<(), Error> {
     let _ = args.next();
     let path = args.next().expect("Missing path to Chip8 ROM");
     let chip8 = read(path)?;
-    let mut cpu = load_rom(&chip8);
-    let mut chip = Chip::new();
+
+    // Passing `--replay <file>` plays back a recording made with the record key
+    let recording = match args.skip_while(|arg| arg != "--replay").nth(1) {
+        Some(path) => Some(Recording::load(&read(path)?)?),
+        None => None,
+    };
+
+    let mut cpu = match &recording {
+        Some(recording) => recording.start()?,
+        None => load_rom(&chip8),
+    };
+    let mut replay = recording.map(Replay::new);
+    let mut chip = Recorder::new(Chip::new(cpu.cycles() + TIMER_PERIOD));
+    let mut scheduler = chip.bus().timers();
+    let mut rewind = Rewind::new(REWIND_PERIOD, REWIND_FRAMES);
 
     let mut window = Window::new(
         "CHIP-8 Emulator",
@@ -1705,7 +1730,7 @@ fn main() -> Result<(), Error> {
     // for _ in 0..23217 {
     //     cpu.cycle(&mut chip);
     // }
-    // draw(&mut window, &cpu.memory()[4116..=6163])?;
+    // draw(&mut window, &cpu.memory().read_range(4116..=6163))?;
 
     // let (_stream, stream_handle) = OutputStream::try_default()?;
     // let sink = Sink::try_new(&stream_handle)?;
@@ -1723,20 +1748,51 @@ fn main() -> Result<(), Error> {
             .filter_map(map_key)
             .map(|key| (key, false));
 
+        let end = cpu.cycles() + u64::from(5 * (RATE / 10_000));
+
+        if let Some(replay) = &mut replay {
+            // Recorded inputs drive the CPU in place of the keys and timers
+            let start = cpu.cycles();
+            replay.run(&mut cpu, end);
+
+            if start < replay.end() && cpu.cycles() >= replay.end() {
+                match replay.diverged() {
+                    Some(cycle) => eprintln!("Replay diverged from the recording at cycle {cycle}"),
+                    None => eprintln!("Replay finished"),
+                }
+            }
+
+            draw(&mut window, &cpu.memory().read_range(4116..=6163))?;
+            continue;
+        }
+
         for (key, pressed) in pressed.chain(released) {
-            chip.set_key(key, pressed)
+            chip.bus_mut().set_key(key, pressed)
         }
 
-        let mut cycles = 0;
+        if window.is_key_pressed(RECORD, KeyRepeat::No) {
+            match chip.stop(&cpu) {
+                Some(recording) => write(RECORDING, recording.save())?,
+                None => chip.record(&cpu),
+            }
+        }
 
-        while cycles < 5 * (RATE / 10_000) {
-            let spent = cpu.cycle(&mut chip) as u32;
-            cycles += spent;
-            chip.step();
+        let keys = (chip.bus().keys, chip.bus().keypress);
+        if window.is_key_down(REWIND) && rewind.rewind(&mut cpu, chip.bus_mut(), 1) > 0 {
+            // The chip and its timers resume from the snapshot, except for the
+            // keys which stay as they are held now
+            (chip.bus_mut().keys, chip.bus_mut().keypress) = keys;
+            scheduler = chip.bus().timers();
+            chip.rewind(&cpu);
+            draw(&mut window, &cpu.memory().read_range(4116..=6163))?;
+            continue;
         }
 
-        if chip.draw {
-            draw(&mut window, &cpu.memory()[4116..=6163])?;
+        scheduler.run(&mut cpu, &mut chip, end);
+        rewind.record(&cpu, chip.bus());
+
+        if chip.bus().draw {
+            draw(&mut window, &cpu.memory().read_range(4116..=6163))?;
         } else {
             window.update();
         }
@@ -1754,18 +1810,17 @@ fn main() -> Result<(), Error> {
 }
 
 fn load_rom(chip8: &[u8]) -> CPU {
-    let mut program = [0u8; MEM_SIZE];
-    let len = EMULATOR.len();
     let clen = chip8.len().min(4096);
 
-    program[20..100].copy_from_slice(&CHIP_FONTSET);
-    program[532..532 + clen].copy_from_slice(chip8);
-
-    program[6196..6196 + len].copy_from_slice(&EMULATOR);
-    program[0..=6].copy_from_slice(&[2, 4, 6, 8, 10, 12, 14]);
-    program[I + 1] = 0x14;
-
-    CPU::new_from_start(program, START)
+    CPU::builder()
+        .load(0, &[2, 4, 6, 8, 10, 12, 14])
+        .load(I as u16 + 1, &[0x14])
+        .load(20, &CHIP_FONTSET)
+        .load(532, &chip8[..clen])
+        .load(6196, &EMULATOR)
+        .pc(START)
+        .build()
+        .expect("Full RAM should be page aligned")
 }
 
 fn draw(window: &mut Window, display: &[u8]) -> Result<(), Error> {
@@ -1819,18 +1874,23 @@ fn map_key(k: Key) -> Option<u8> {
 fn debug_chip(cpu: &CPU) {
     let mem = cpu.memory();
     let mut registers = String::new();
-    for (idx, reg) in mem[0..16].iter().enumerate() {
+    for (idx, reg) in mem.read_range(0..16).iter().enumerate() {
         registers.push_str(&format!("Reg {idx}: 0x{reg:02x}\n"));
     }
     println!("{registers}");
 
     println!("I: 0x{:02x}{:02x}", mem[16], mem[17]);
 
-    println!("PC: 0x{:02x}{:02x}", cpu.register(4), cpu.register(5));
+    println!(
+        "PC: 0x{:02x}{:02x}",
+        cpu.register(Register::H),
+        cpu.register(Register::L)
+    );
     println!("Stack pointer: 0x{:02x}", mem[18]);
     println!("Key flag: 0x{:02x}", mem[19]);
 }
 
+#[derive(Clone)]
 struct Chip {
     unknown: [u8; 2],
     unknown_curr: u8,
@@ -1846,11 +1906,12 @@ struct Chip {
     end_draw: u8,
     pixels: Vec<(usize, u8)>,
     px_idx: Option<usize>,
-    last_update: Option<Instant>,
+    /// Cycle of the next decrement of the timers.
+    timer: u64,
 }
 
 impl Chip {
-    fn new() -> Self {
+    fn new(timer: u64) -> Self {
         Self {
             unknown: [0, 0],
             unknown_curr: 0,
@@ -1865,27 +1926,16 @@ impl Chip {
             end_draw: 0x01,
             pixels: vec![],
             px_idx: None,
-            last_update: None,
+            timer,
         }
     }
 
-    fn step(&mut self) {
-        if let Some(last_update) = self.last_update.as_ref() {
-            if last_update.elapsed() >= Duration::from_micros(4_500) {
-                self.delay_timer = self.delay_timer.saturating_sub(1);
-                self.sound_timer = self.sound_timer.saturating_sub(1);
+    /// Returns a [`Scheduler`] with the next decrement of the timers.
+    fn timers(&self) -> Scheduler<Timer> {
+        let mut scheduler = Scheduler::new();
+        scheduler.schedule(self.timer, Timer);
 
-                if self.sound_timer == 0 {
-                    self.sound = false
-                } else {
-                    self.sound = true
-                }
-
-                self.last_update = Some(Instant::now());
-            }
-        } else {
-            self.last_update = Some(Instant::now());
-        }
+        scheduler
     }
 
     fn set_key(&mut self, key: u8, pressed: bool) {
@@ -1898,7 +1948,7 @@ impl Chip {
         self.keys[key as usize] = u8::from(pressed);
     }
 
-    fn draw(&mut self, memory: &[u8]) {
+    fn draw(&mut self, memory: &Memory) {
         let x = self.draw_px.0 as usize % WIDTH;
         let y = self.draw_px.1 as usize % HEIGHT;
         let height = self.draw_px.2 as usize;
@@ -1933,6 +1983,22 @@ impl Chip {
     }
 }
 
+/// Decrement of the delay and sound timers
+struct Timer;
+
+impl Device for Chip {
+    type Event = Timer;
+
+    fn fire(&mut self, _cpu: &mut CPU, scheduler: &mut Scheduler<Timer>, at: u64, timer: Timer) {
+        self.delay_timer = self.delay_timer.saturating_sub(1);
+        self.sound_timer = self.sound_timer.saturating_sub(1);
+        self.sound = self.sound_timer != 0;
+
+        self.timer = at + TIMER_PERIOD;
+        scheduler.schedule(self.timer, timer);
+    }
+}
+
 impl Bus for Chip {
     fn read(&mut self, _cpu: &CPU, port: u8) -> u8 {
         match port {
@@ -2038,6 +2104,7 @@ pub enum Error {
     Stream(rodio::StreamError),
     IO(std::io::Error),
     Mini(minifb::Error),
+    State(StateError),
 }
 
 impl From<rodio::DevicesError> for Error {
@@ -2070,6 +2137,12 @@ impl From<rodio::decoder::DecoderError> for Error {
     }
 }
 
+impl From<StateError> for Error {
+    fn from(value: StateError) -> Self {
+        Self::State(value)
+    }
+}
+
 impl From<minifb::Error> for Error {
     fn from(value: minifb::Error) -> Self {
         Self::Mini(value)
@@ -2086,6 +2159,7 @@ impl std::fmt::Display for Error {
             Self::Decoder(error) => error.fmt(f),
             Self::IO(error) => error.fmt(f),
             Self::Mini(error) => error.fmt(f),
+            Self::State(error) => error.fmt(f),
         }
     }
 }
@@ -2100,6 +2174,7 @@ impl std::error::Error for Error {
             Self::Decoder(error) => Some(error),
             Self::IO(error) => Some(error),
             Self::Mini(error) => Some(error),
+            Self::State(error) => Some(error),
         }
     }
 }
@@ -0,0 +1,386 @@
+use crate::Flag;
+
+/// Sign, zero and parity flags of every result, along with bit 1 of the flag
+/// register, which is always set.
+static SZP: [u8; 256] = {
+    let mut table = [0; 256];
+
+    let mut result = 0;
+    while result < 256 {
+        let mut flags = 0b0000_0010 | (result as u8 & Flag::Sign as u8);
+        if result == 0 {
+            flags |= Flag::Zero as u8;
+        }
+        if (result as u8).count_ones().is_multiple_of(2) {
+            flags |= Flag::Parity as u8;
+        }
+
+        table[result] = flags;
+        result += 1;
+    }
+
+    table
+};
+
+/// Operands and result of the last arithmetic or logic operation, from which
+/// its flags are only worked out when read.
+///
+/// The operands are kept in the form the flags are worked out from: CY is bit
+/// 8 of `carry` and AC is bit 4 of `aux ^ result`, whatever the operation.
+#[derive(Debug, Clone, Copy, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub(crate) struct Alu {
+    /// Whether the flags are yet to be worked out. The flag register is up to
+    /// date otherwise.
+    pub(crate) pending: bool,
+    pub(crate) result: u8,
+    /// The operation widened to 16 bits, whose bit 8 is CY.
+    carry: u16,
+    /// The operands XORed together. Bit 4 differs from that of the result
+    /// when the operation carried out of bit 3.
+    aux: u8,
+}
+
+impl Alu {
+    /// Returns `a + b + carry`.
+    #[inline]
+    pub(crate) fn add(a: u8, b: u8, carry: bool) -> Self {
+        Self {
+            pending: true,
+            result: a.wrapping_add(b).wrapping_add(carry as u8),
+            carry: a as u16 + b as u16 + carry as u16,
+            aux: a ^ b,
+        }
+    }
+
+    /// Returns `a - b - borrow`. The 8080 adds the complement of `b`, so AC is
+    /// the carry of that addition while CY is set on a borrow.
+    #[inline]
+    pub(crate) fn sub(a: u8, b: u8, borrow: bool) -> Self {
+        Self {
+            pending: true,
+            result: a.wrapping_sub(b).wrapping_sub(borrow as u8),
+            carry: (a as u16).wrapping_sub(b as u16 + borrow as u16),
+            aux: a ^ !b,
+        }
+    }
+
+    /// Returns `a & b`, which sets AC from bit 3 of the operands and clears
+    /// CY.
+    #[inline]
+    pub(crate) fn and(a: u8, b: u8) -> Self {
+        let result = a & b;
+
+        Self {
+            pending: true,
+            result,
+            carry: 0,
+            aux: result ^ ((a | b) << 1),
+        }
+    }
+
+    /// Returns `result`, as left by ORA or XRA, which clear AC and CY.
+    #[inline]
+    pub(crate) fn or(result: u8) -> Self {
+        Self {
+            pending: true,
+            result,
+            carry: 0,
+            aux: result,
+        }
+    }
+
+    /// Returns `value + 1`, leaving CY as `carry`.
+    #[inline]
+    pub(crate) fn inr(value: u8, carry: bool) -> Self {
+        Self {
+            pending: true,
+            result: value.wrapping_add(1),
+            carry: (carry as u16) << 8,
+            aux: value ^ 1,
+        }
+    }
+
+    /// Returns `value - 1`, leaving CY as `carry`. The 8080 adds 0xff.
+    #[inline]
+    pub(crate) fn dcr(value: u8, carry: bool) -> Self {
+        Self {
+            pending: true,
+            result: value.wrapping_sub(1),
+            carry: (carry as u16) << 8,
+            aux: value ^ 0xff,
+        }
+    }
+
+    /// Sets CY, leaving the other flags to the operation.
+    #[inline]
+    pub(crate) fn set_carry(&mut self, carry: bool) {
+        self.carry = (carry as u16) << 8;
+    }
+
+    /// Returns true if `flag` is set, where `settled` is the flag register,
+    /// which holds the flags unless they are pending.
+    #[inline]
+    pub(crate) fn flag(&self, flag: Flag, settled: u8) -> bool {
+        if !self.pending {
+            return settled & flag as u8 != 0;
+        }
+
+        let result = self.result;
+        match flag {
+            Flag::Sign => result & 0x80 != 0,
+            Flag::Zero => result == 0,
+            Flag::Parity => SZP[result as usize] & Flag::Parity as u8 != 0,
+            Flag::AuxCarry => (self.aux ^ result) & 0x10 != 0,
+            Flag::Carry => self.carry & 0x100 != 0,
+        }
+    }
+
+    /// Returns the flag register, where `settled` is the flag register,
+    /// which holds the flags unless they are pending.
+    #[inline]
+    pub(crate) fn bits(&self, settled: u8) -> u8 {
+        if !self.pending {
+            return settled;
+        }
+
+        SZP[self.result as usize] | ((self.aux ^ self.result) & 0x10) | (self.carry >> 8) as u8 & 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CPU, Condition, Flags, Instruction, Operand, Register, RegisterPair};
+
+    /// Computes the result and flags of an instruction from `a`, `b` and the
+    /// carry flag.
+    type Reference = fn(u8, u8, bool) -> (u8, Flags);
+
+    /// Returns flags worked out from their definitions.
+    fn flags(result: u8, aux_carry: bool, carry: bool) -> Flags {
+        Flags {
+            sign: result >= 0x80,
+            zero: result == 0,
+            aux_carry,
+            parity: result.count_ones().is_multiple_of(2),
+            carry,
+        }
+    }
+
+    fn add(a: u8, b: u8, carry: bool) -> (u8, Flags) {
+        let sum = a as u16 + b as u16 + carry as u16;
+        let aux_carry = (a & 0x0f) + (b & 0x0f) + carry as u8 > 0x0f;
+
+        (sum as u8, flags(sum as u8, aux_carry, sum > 0xff))
+    }
+
+    fn sub(a: u8, b: u8, borrow: bool) -> (u8, Flags) {
+        let result = (a as i16 - b as i16 - borrow as i16) as u8;
+        // AC is the carry out of the low nibble when adding the complement
+        let aux_carry = a & 0x0f >= (b & 0x0f) + borrow as u8;
+
+        (
+            result,
+            flags(result, aux_carry, (a as u16) < b as u16 + borrow as u16),
+        )
+    }
+
+    fn cmp(a: u8, b: u8, borrow: bool) -> (u8, Flags) {
+        (a, sub(a, b, borrow).1)
+    }
+
+    fn and(a: u8, b: u8, _carry: bool) -> (u8, Flags) {
+        (a & b, flags(a & b, (a | b) & 0x08 != 0, false))
+    }
+
+    fn xor(a: u8, b: u8, _carry: bool) -> (u8, Flags) {
+        (a ^ b, flags(a ^ b, false, false))
+    }
+
+    fn or(a: u8, b: u8, _carry: bool) -> (u8, Flags) {
+        (a | b, flags(a | b, false, false))
+    }
+
+    fn inr(a: u8, _b: u8, carry: bool) -> (u8, Flags) {
+        let result = a.wrapping_add(1);
+        (result, flags(result, a & 0x0f == 0x0f, carry))
+    }
+
+    fn dcr(a: u8, _b: u8, carry: bool) -> (u8, Flags) {
+        let result = a.wrapping_sub(1);
+        (result, flags(result, a & 0x0f != 0x00, carry))
+    }
+
+    /// Checks `instruction` against `reference` for every value of A, B and
+    /// the carry flag, with the other flags set opposite to the expected ones
+    /// so that none is left as it was by mistake.
+    fn check(cpu: &mut CPU, instruction: Instruction, reference: Reference) {
+        for a in 0..=0xff {
+            for b in 0..=0xff {
+                for carry in [false, true] {
+                    let (result, expected) = reference(a, b, carry);
+
+                    cpu.set_register(Register::A, a);
+                    cpu.set_register(Register::B, b);
+                    cpu.set_flags(Flags::from_bits(!expected.bits()));
+                    cpu.set_flag(Flag::Carry, carry);
+                    cpu.execute(&mut (), instruction).unwrap();
+
+                    assert_eq!(
+                        (cpu.register(Register::A), cpu.flags()),
+                        (result, expected),
+                        "{instruction} with A={a:#04x}, B={b:#04x}, CY={carry}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn arithmetic_and_logic_set_every_flag() {
+        let mut cpu = CPU::new(&[]);
+        let b = Operand::Register(Register::B);
+        let a = Operand::Register(Register::A);
+
+        let cases: [(Instruction, Reference); 10] = [
+            (Instruction::Add(b), |a, b, _| add(a, b, false)),
+            (Instruction::Adc(b), add),
+            (Instruction::Sub(b), |a, b, _| sub(a, b, false)),
+            (Instruction::Sbb(b), sub),
+            (Instruction::Ana(b), and),
+            (Instruction::Xra(b), xor),
+            (Instruction::Ora(b), or),
+            (Instruction::Cmp(b), |a, b, _| cmp(a, b, false)),
+            (Instruction::Inr(a), inr),
+            (Instruction::Dcr(a), dcr),
+        ];
+
+        for (instruction, reference) in cases {
+            check(&mut cpu, instruction, reference);
+        }
+    }
+
+    #[test]
+    fn immediates_set_the_same_flags() {
+        let mut cpu = CPU::new(&[]);
+        let b = Operand::Register(Register::B);
+
+        for data in [0x00, 0x01, 0x0f, 0x10, 0x7f, 0x80, 0xf0, 0xff] {
+            let cases = [
+                (Instruction::Adi(data), Instruction::Add(b)),
+                (Instruction::Aci(data), Instruction::Adc(b)),
+                (Instruction::Sui(data), Instruction::Sub(b)),
+                (Instruction::Sbi(data), Instruction::Sbb(b)),
+                (Instruction::Ani(data), Instruction::Ana(b)),
+                (Instruction::Xri(data), Instruction::Xra(b)),
+                (Instruction::Ori(data), Instruction::Ora(b)),
+                (Instruction::Cpi(data), Instruction::Cmp(b)),
+            ];
+
+            for (immediate, register) in cases {
+                for acc in 0..=0xff {
+                    for carry in [false, true] {
+                        let mut outcome = |instruction| {
+                            cpu.set_register(Register::A, acc);
+                            cpu.set_register(Register::B, data);
+                            cpu.set_flags(Flags::from_bits(0));
+                            cpu.set_flag(Flag::Carry, carry);
+                            cpu.execute(&mut (), instruction).unwrap();
+
+                            (cpu.register(Register::A), cpu.flags())
+                        };
+
+                        assert_eq!(outcome(immediate), outcome(register), "{immediate}");
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn dad_only_changes_the_carry_flag() {
+        let mut cpu = CPU::new(&[]);
+        let values = [
+            0x0000, 0x0001, 0x00ff, 0x7fff, 0x8000, 0x8001, 0xfffe, 0xffff,
+        ];
+
+        for bits in 0..=0xff {
+            for hl in values {
+                for bc in values {
+                    let before = Flags::from_bits(bits);
+
+                    cpu.set_register_pair(RegisterPair::HL, hl);
+                    cpu.set_register_pair(RegisterPair::BC, bc);
+                    cpu.set_flags(before);
+                    cpu.execute(&mut (), Instruction::Dad(RegisterPair::BC))
+                        .unwrap();
+
+                    let sum = hl as u32 + bc as u32;
+                    assert_eq!(cpu.register_pair(RegisterPair::HL), sum as u16);
+                    assert_eq!(
+                        cpu.flags(),
+                        Flags {
+                            carry: sum > 0xffff,
+                            ..before
+                        }
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn inr_and_dcr_keep_the_carry_flag() {
+        let mut cpu = CPU::new(&[]);
+
+        for op in [Operand::Register(Register::C), Operand::Memory] {
+            for value in [0x00, 0x0f, 0x7f, 0x80, 0xff] {
+                for carry in [false, true] {
+                    for (instruction, reference) in [
+                        (Instruction::Inr(op), inr as Reference),
+                        (Instruction::Dcr(op), dcr),
+                    ] {
+                        cpu.set_register(Register::C, value);
+                        cpu.set_register_pair(RegisterPair::HL, 0x0100);
+                        cpu.memory_mut().write(0x0100, value);
+                        cpu.set_flags(Flags::from_bits(0));
+                        cpu.set_flag(Flag::Carry, carry);
+                        cpu.execute(&mut (), instruction).unwrap();
+
+                        let result = match op {
+                            Operand::Memory => cpu.memory().read(0x0100),
+                            Operand::Register(reg) => cpu.register(reg),
+                        };
+                        assert_eq!((result, cpu.flags()), reference(value, 0, carry));
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn pending_flags_are_worked_out_when_read() {
+        let mut cpu = CPU::new(&[]);
+        cpu.set_sp(0x0200);
+
+        // SUI leaves CY set, which INR keeps while changing the others
+        for instruction in [
+            Instruction::Mvi(Operand::Register(Register::A), 0x01),
+            Instruction::Sui(0x02),
+            Instruction::Inr(Operand::Register(Register::B)),
+            Instruction::Push(RegisterPair::PSW),
+        ] {
+            cpu.execute(&mut (), instruction).unwrap();
+        }
+        assert_eq!(cpu.memory().read_range(0x01fe..0x0200), [0b0000_0011, 0xff]);
+
+        let pc = cpu.pc();
+        cpu.execute(&mut (), Instruction::Jcc(Condition::NoCarry, 0x1234))
+            .unwrap();
+        assert_eq!(cpu.pc(), pc.wrapping_add(3));
+        cpu.execute(&mut (), Instruction::Jcc(Condition::Carry, 0x1234))
+            .unwrap();
+        assert_eq!(cpu.pc(), 0x1234);
+    }
+}
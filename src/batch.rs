@@ -0,0 +1,167 @@
+use crate::{Bus, CPU};
+use std::thread;
+
+/// Many independent machines, each a [`CPU`] with its own [`Bus`], run side by
+/// side.
+///
+/// Machines cloned from a common [`CPU`] share its memory pages until they
+/// write to them, so ROM and untouched RAM are stored once however many
+/// machines there are.
+pub struct Batch<B> {
+    machines: Vec<(CPU, B)>,
+}
+
+impl<B> Default for Batch<B> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<B> Batch<B> {
+    /// Creates a new [`Batch`] with no machines.
+    pub fn new() -> Self {
+        Self { machines: vec![] }
+    }
+
+    /// Creates a new [`Batch`] with a clone of `base` for each of `buses`.
+    pub fn from_base(base: &CPU, buses: impl IntoIterator<Item = B>) -> Self {
+        Self {
+            machines: buses.into_iter().map(|bus| (base.clone(), bus)).collect(),
+        }
+    }
+
+    /// Adds a machine made of `cpu` and `bus`.
+    pub fn push(&mut self, cpu: CPU, bus: B) {
+        self.machines.push((cpu, bus));
+    }
+
+    /// Returns the number of machines.
+    pub fn len(&self) -> usize {
+        self.machines.len()
+    }
+
+    /// Returns true if there are no machines.
+    pub fn is_empty(&self) -> bool {
+        self.machines.is_empty()
+    }
+
+    /// Returns the [`CPU`] and [`Bus`] of machine `idx`, if there is one.
+    pub fn get(&self, idx: usize) -> Option<(&CPU, &B)> {
+        self.machines.get(idx).map(|(cpu, bus)| (cpu, bus))
+    }
+
+    /// Returns the [`CPU`] and [`Bus`] of machine `idx` mutably, if there is
+    /// one.
+    pub fn get_mut(&mut self, idx: usize) -> Option<(&mut CPU, &mut B)> {
+        self.machines.get_mut(idx).map(|(cpu, bus)| (cpu, bus))
+    }
+
+    /// Returns an iterator over the machines.
+    pub fn iter(&self) -> impl Iterator<Item = (&CPU, &B)> {
+        self.machines.iter().map(|(cpu, bus)| (cpu, bus))
+    }
+
+    /// Returns an iterator over the machines, allowing them to be modified.
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (&mut CPU, &mut B)> {
+        self.machines.iter_mut().map(|(cpu, bus)| (cpu, bus))
+    }
+
+    /// Calls `f` on every machine, spread over `threads` threads.
+    pub fn for_each_parallel<F>(&mut self, threads: usize, f: F)
+    where
+        B: Send,
+        F: Fn(&mut CPU, &mut B) + Sync,
+    {
+        let len = self.machines.len().div_ceil(threads.max(1)).max(1);
+
+        thread::scope(|scope| {
+            for chunk in self.machines.chunks_mut(len) {
+                let f = &f;
+
+                scope.spawn(move || {
+                    for (cpu, bus) in chunk {
+                        f(cpu, bus);
+                    }
+                });
+            }
+        });
+    }
+}
+
+impl<B: Bus> Batch<B> {
+    /// Executes the next instruction of every machine through [`CPU::cycle`],
+    /// keeping them in lockstep.
+    pub fn cycle(&mut self) {
+        for (cpu, bus) in &mut self.machines {
+            cpu.cycle(bus);
+        }
+    }
+
+    /// Runs every machine until it reaches cycle `until`, one after the other.
+    pub fn run(&mut self, until: u64) {
+        for (cpu, bus) in &mut self.machines {
+            while cpu.cycles() < until {
+                cpu.cycle(bus);
+            }
+        }
+    }
+
+    /// Runs every machine until it reaches cycle `until`, spread over
+    /// `threads` threads.
+    pub fn run_parallel(&mut self, until: u64, threads: usize)
+    where
+        B: Send,
+    {
+        self.for_each_parallel(threads, |cpu, bus| {
+            while cpu.cycles() < until {
+                cpu.cycle(bus);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A [`Bus`] whose input ports all read a fixed value.
+    struct Input(u8);
+
+    impl Bus for Input {
+        fn read(&mut self, _cpu: &CPU, _port: u8) -> u8 {
+            self.0
+        }
+
+        fn write(&mut self, _cpu: &CPU, _port: u8, _data: u8) {}
+    }
+
+    #[test]
+    fn clones_do_not_see_each_others_writes() {
+        let mut base = CPU::new(&[
+            0xdb, 0x00, // IN 0x00
+            0x32, 0x00, 0x01, // STA 0x0100
+            0x76, // HLT
+        ]);
+        base.memory_mut().write(0x0200, 0xee);
+
+        let mut batch = Batch::from_base(&base, (1..=3).map(Input));
+        batch.run_parallel(100, 2);
+        if let Some((cpu, _)) = batch.get_mut(0) {
+            cpu.memory_mut().write(0x0200, 0x00);
+        }
+
+        let written: Vec<_> = batch
+            .iter()
+            .map(|(cpu, _)| cpu.memory().read(0x0100))
+            .collect();
+        let shared: Vec<_> = batch
+            .iter()
+            .map(|(cpu, _)| cpu.memory().read(0x0200))
+            .collect();
+
+        assert_eq!(written, [1, 2, 3]);
+        assert_eq!(shared, [0x00, 0xee, 0xee]);
+        assert_eq!(base.memory().read(0x0100), 0x00);
+        assert_eq!(base.memory().read(0x0200), 0xee);
+    }
+}
@@ -0,0 +1,170 @@
+use crate::{CPU, Flags, MEM_SIZE, MapError, MemoryMap, RATE, Register};
+
+/// Builds a [`CPU`] with a configurable initial state.
+///
+/// Unless set otherwise, the [`CPU`] starts at address 0 with the stack
+/// pointer at 0xFFFF, zeroed registers and memory, every flag cleared, 64K of
+/// RAM and a clock speed of [`RATE`].
+#[derive(Clone)]
+pub struct CPUBuilder {
+    pc: u16,
+    sp: u16,
+    /// Registers in order B,C,D,E,H,L,A
+    registers: [u8; 7],
+    flags: Flags,
+    /// Power-on contents of the first 64K of memory.
+    memory: Box<[u8; MEM_SIZE]>,
+    map: MemoryMap,
+    /// Installed RAM in bytes.
+    ram: usize,
+    rate: u32,
+}
+
+impl Default for CPUBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CPUBuilder {
+    /// Creates a new [`CPUBuilder`] with the default initial state.
+    pub fn new() -> Self {
+        Self {
+            pc: 0,
+            sp: 0xFFFF,
+            registers: [0; 7],
+            flags: Flags::default(),
+            memory: Box::new([0; MEM_SIZE]),
+            map: MemoryMap::new(),
+            ram: MEM_SIZE,
+            rate: RATE,
+        }
+    }
+
+    /// Sets the initial program counter.
+    pub fn pc(mut self, pc: u16) -> Self {
+        self.pc = pc;
+        self
+    }
+
+    /// Sets the initial stack pointer.
+    pub fn sp(mut self, sp: u16) -> Self {
+        self.sp = sp;
+        self
+    }
+
+    /// Sets the initial content of `reg`.
+    pub fn register(mut self, reg: Register, value: u8) -> Self {
+        self.registers[reg as usize] = value;
+        self
+    }
+
+    /// Sets the initial flags.
+    pub fn flags(mut self, flags: Flags) -> Self {
+        self.flags = flags;
+        self
+    }
+
+    /// Fills memory with `value` at power on, as uninitialised RAM may not
+    /// read as zero.
+    pub fn fill(mut self, value: u8) -> Self {
+        self.memory.fill(value);
+        self
+    }
+
+    /// Places `data` in memory at `addr` at power on, overwriting anything
+    /// placed there before. Data past the end of the 64K address space is
+    /// dropped.
+    pub fn load(mut self, addr: u16, data: &[u8]) -> Self {
+        let start = addr as usize;
+        let len = data.len().min(MEM_SIZE - start);
+
+        self.memory[start..start + len].copy_from_slice(&data[..len]);
+        self
+    }
+
+    /// Lays out the address space according to `map`.
+    pub fn memory_map(mut self, map: MemoryMap) -> Self {
+        self.map = map;
+        self
+    }
+
+    /// Sets the installed RAM to `size` bytes from address 0. Memory above it
+    /// is left unmapped, reading 0xFF from the open bus, except where the
+    /// [`MemoryMap`] maps it. The map takes precedence, so that e.g. a monitor
+    /// ROM can sit at the top of the address space above 16K of RAM. `size`
+    /// must be a multiple of [`PAGE_SIZE`](crate::PAGE_SIZE).
+    pub fn ram_size(mut self, size: usize) -> Self {
+        self.ram = size;
+        self
+    }
+
+    /// Sets the clock speed in Hz, returned by [`CPU::rate`].
+    pub fn rate(mut self, rate: u32) -> Self {
+        self.rate = rate;
+        self
+    }
+
+    /// Builds the [`CPU`], failing if the memory layout is not page aligned.
+    pub fn build(self) -> Result<CPU, MapError> {
+        // Memory above the installed RAM is unmapped before the map applies
+        let mut map = MemoryMap::new();
+        if self.ram < MEM_SIZE {
+            map = map.unmapped(self.ram as u16..=0xffff);
+        }
+        let map = map.then(self.map);
+
+        let mut cpu = CPU::new_from_start(*self.memory, self.pc);
+        cpu.map_memory(&map)?;
+        cpu.set_sp(self.sp);
+        cpu.registers = self.registers;
+        cpu.set_flags(self.flags);
+        cpu.rate = self.rate;
+
+        Ok(cpu)
+    }
+}
+
+impl CPU {
+    /// Returns a [`CPUBuilder`] to create a [`CPU`] with a configurable initial
+    /// state.
+    pub fn builder() -> CPUBuilder {
+        CPUBuilder::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PAGE_SIZE;
+
+    #[test]
+    fn memory_map_takes_precedence_over_ram_size() {
+        // 16K of RAM mirrored once, with a monitor ROM at the top
+        let map = MemoryMap::new()
+            .mirror(0x4000..=0x7fff, 0x0000..=0x3fff)
+            .rom(0xf000..=0xffff);
+        let mut cpu = CPU::builder()
+            .load(0xf000, &[0xc3, 0x00, 0xf0])
+            .ram_size(0x4000)
+            .memory_map(map)
+            .build()
+            .unwrap();
+
+        let memory = cpu.memory_mut();
+        memory.write(0x3fff, 0x2a);
+        memory.write(0x8000, 0x2a);
+        memory.write(0xf000, 0x00);
+
+        assert_eq!(memory.read(0x7fff), 0x2a);
+        assert_eq!(memory.read(0x8000), 0xff);
+        assert_eq!(memory.read_range(0xf000..0xf003), [0xc3, 0x00, 0xf0]);
+    }
+
+    #[test]
+    fn ram_size_must_be_page_aligned() {
+        let built = CPU::builder().ram_size(PAGE_SIZE + 1).build();
+
+        assert!(matches!(built, Err(MapError::Unaligned(_))));
+    }
+}
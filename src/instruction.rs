@@ -0,0 +1,952 @@
+use crate::{Flag, Register, RegisterPair};
+use std::fmt;
+
+/// A register or the memory addressed by HL, as operated on by MOV, MVI, INR,
+/// DCR and the arithmetic and logic instructions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Operand {
+    Register(Register),
+    /// The memory addressed by HL, written as M.
+    Memory,
+}
+
+/// A condition tested by conditional jumps, calls and returns.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Condition {
+    NotZero,
+    Zero,
+    NoCarry,
+    Carry,
+    ParityOdd,
+    ParityEven,
+    Plus,
+    Minus,
+}
+
+/// A decoded instruction along with its operands.
+///
+/// Undocumented opcodes decode to the instruction they behave as.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Instruction {
+    Nop,
+    Lxi(RegisterPair, u16),
+    Stax(RegisterPair),
+    Ldax(RegisterPair),
+    Shld(u16),
+    Lhld(u16),
+    Sta(u16),
+    Lda(u16),
+    Inx(RegisterPair),
+    Dcx(RegisterPair),
+    Dad(RegisterPair),
+    Inr(Operand),
+    Dcr(Operand),
+    Mvi(Operand, u8),
+    Rlc,
+    Rrc,
+    Ral,
+    Rar,
+    Daa,
+    Cma,
+    Stc,
+    Cmc,
+    /// Moves the second operand into the first.
+    Mov(Operand, Operand),
+    Hlt,
+    Add(Operand),
+    Adc(Operand),
+    Sub(Operand),
+    Sbb(Operand),
+    Ana(Operand),
+    Xra(Operand),
+    Ora(Operand),
+    Cmp(Operand),
+    Adi(u8),
+    Aci(u8),
+    Sui(u8),
+    Sbi(u8),
+    Ani(u8),
+    Xri(u8),
+    Ori(u8),
+    Cpi(u8),
+    Ret,
+    /// Conditional return.
+    Rcc(Condition),
+    Jmp(u16),
+    /// Conditional jump.
+    Jcc(Condition, u16),
+    Call(u16),
+    /// Conditional call.
+    Ccc(Condition, u16),
+    /// Restart, calling address 8 times the operand.
+    Rst(u8),
+    Pop(RegisterPair),
+    Push(RegisterPair),
+    Out(u8),
+    In(u8),
+    Xthl,
+    Pchl,
+    Xchg,
+    Di,
+    Sphl,
+    Ei,
+}
+
+/// Static knowledge about an opcode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+    pub mnemonic: &'static str,
+    /// Length of the instruction in bytes, opcode included.
+    pub len: u8,
+    /// T-states taken, or taken when the condition holds for conditional
+    /// instructions, without wait states.
+    pub t_states: u8,
+    /// T-states taken when the condition does not hold. Same as
+    /// [`Metadata::t_states`] for other instructions.
+    pub t_states_not_taken: u8,
+    /// Flags the instruction may change.
+    pub flags: &'static [Flag],
+    /// Whether the opcode is documented by Intel.
+    pub documented: bool,
+}
+
+/// Metadata of every opcode, indexed by opcode.
+pub static OPCODES: [Metadata; 256] = {
+    let mut table = [metadata(0x00); 256];
+
+    let mut opcode = 0;
+    while opcode < 256 {
+        table[opcode] = metadata(opcode as u8);
+        opcode += 1;
+    }
+
+    table
+};
+
+const ALL: &[Flag] = &[
+    Flag::Sign,
+    Flag::Zero,
+    Flag::AuxCarry,
+    Flag::Parity,
+    Flag::Carry,
+];
+const NOT_CARRY: &[Flag] = &[Flag::Sign, Flag::Zero, Flag::AuxCarry, Flag::Parity];
+const CARRY: &[Flag] = &[Flag::Carry];
+
+/// Instructions of every opcode with zeroed operands, indexed by opcode.
+static DECODED: [Instruction; 256] = {
+    let mut table = [Instruction::Nop; 256];
+
+    let mut opcode = 0;
+    while opcode < 256 {
+        table[opcode] = decode_opcode(opcode as u8, 0, 0);
+        opcode += 1;
+    }
+
+    table
+};
+
+/// Decodes the instruction starting at the first of `bytes`. Bytes past the
+/// length of the instruction are ignored.
+#[inline(always)]
+pub const fn decode(bytes: [u8; 3]) -> Instruction {
+    let [opcode, data, high] = bytes;
+    let addr = u16::from_le_bytes([data, high]);
+
+    // Looking the opcode up and filling in the operands is quicker than
+    // matching the opcode's bit patterns. Most instructions have none to fill
+    // in, which spares them the match.
+    let instruction = DECODED[opcode as usize];
+    if OPCODES[opcode as usize].len == 1 {
+        return instruction;
+    }
+
+    match instruction {
+        Instruction::Lxi(pair, _) => Instruction::Lxi(pair, addr),
+        Instruction::Shld(_) => Instruction::Shld(addr),
+        Instruction::Lhld(_) => Instruction::Lhld(addr),
+        Instruction::Sta(_) => Instruction::Sta(addr),
+        Instruction::Lda(_) => Instruction::Lda(addr),
+        Instruction::Mvi(op, _) => Instruction::Mvi(op, data),
+        Instruction::Adi(_) => Instruction::Adi(data),
+        Instruction::Aci(_) => Instruction::Aci(data),
+        Instruction::Sui(_) => Instruction::Sui(data),
+        Instruction::Sbi(_) => Instruction::Sbi(data),
+        Instruction::Ani(_) => Instruction::Ani(data),
+        Instruction::Xri(_) => Instruction::Xri(data),
+        Instruction::Ori(_) => Instruction::Ori(data),
+        Instruction::Cpi(_) => Instruction::Cpi(data),
+        Instruction::Jmp(_) => Instruction::Jmp(addr),
+        Instruction::Jcc(condition, _) => Instruction::Jcc(condition, addr),
+        Instruction::Call(_) => Instruction::Call(addr),
+        Instruction::Ccc(condition, _) => Instruction::Ccc(condition, addr),
+        Instruction::Out(_) => Instruction::Out(data),
+        Instruction::In(_) => Instruction::In(data),
+        instruction => instruction,
+    }
+}
+
+/// Decodes `opcode` with operand bytes `data` and `addr`.
+const fn decode_opcode(opcode: u8, data: u8, addr: u16) -> Instruction {
+    match opcode {
+        0x00 | 0x08 | 0x10 | 0x18 | 0x20 | 0x28 | 0x30 | 0x38 => Instruction::Nop,
+        0x76 => Instruction::Hlt,
+        op if op & 0xcf == 0x01 => Instruction::Lxi(pair(op), addr),
+        0x02 | 0x12 => Instruction::Stax(pair(opcode)),
+        0x0a | 0x1a => Instruction::Ldax(pair(opcode)),
+        0x22 => Instruction::Shld(addr),
+        0x2a => Instruction::Lhld(addr),
+        0x32 => Instruction::Sta(addr),
+        0x3a => Instruction::Lda(addr),
+        op if op & 0xcf == 0x03 => Instruction::Inx(pair(op)),
+        op if op & 0xcf == 0x0b => Instruction::Dcx(pair(op)),
+        op if op & 0xcf == 0x09 => Instruction::Dad(pair(op)),
+        op if op & 0xc7 == 0x04 => Instruction::Inr(operand(op >> 3)),
+        op if op & 0xc7 == 0x05 => Instruction::Dcr(operand(op >> 3)),
+        op if op & 0xc7 == 0x06 => Instruction::Mvi(operand(op >> 3), data),
+        0x07 => Instruction::Rlc,
+        0x0f => Instruction::Rrc,
+        0x17 => Instruction::Ral,
+        0x1f => Instruction::Rar,
+        0x27 => Instruction::Daa,
+        0x2f => Instruction::Cma,
+        0x37 => Instruction::Stc,
+        0x3f => Instruction::Cmc,
+        0x40..=0x7f => Instruction::Mov(operand(opcode >> 3), operand(opcode)),
+        0x80..=0xbf => match (opcode >> 3) & 0x07 {
+            0 => Instruction::Add(operand(opcode)),
+            1 => Instruction::Adc(operand(opcode)),
+            2 => Instruction::Sub(operand(opcode)),
+            3 => Instruction::Sbb(operand(opcode)),
+            4 => Instruction::Ana(operand(opcode)),
+            5 => Instruction::Xra(operand(opcode)),
+            6 => Instruction::Ora(operand(opcode)),
+            _ => Instruction::Cmp(operand(opcode)),
+        },
+        op if op & 0xc7 == 0xc0 => Instruction::Rcc(condition(op)),
+        op if op & 0xc7 == 0xc2 => Instruction::Jcc(condition(op), addr),
+        op if op & 0xc7 == 0xc4 => Instruction::Ccc(condition(op), addr),
+        op if op & 0xc7 == 0xc6 => match (op >> 3) & 0x07 {
+            0 => Instruction::Adi(data),
+            1 => Instruction::Aci(data),
+            2 => Instruction::Sui(data),
+            3 => Instruction::Sbi(data),
+            4 => Instruction::Ani(data),
+            5 => Instruction::Xri(data),
+            6 => Instruction::Ori(data),
+            _ => Instruction::Cpi(data),
+        },
+        op if op & 0xc7 == 0xc7 => Instruction::Rst((op >> 3) & 0x07),
+        0xc1 | 0xd1 | 0xe1 | 0xf1 => Instruction::Pop(stack_pair(opcode)),
+        0xc5 | 0xd5 | 0xe5 | 0xf5 => Instruction::Push(stack_pair(opcode)),
+        0xc9 | 0xd9 => Instruction::Ret,
+        0xc3 | 0xcb => Instruction::Jmp(addr),
+        0xcd | 0xdd | 0xed | 0xfd => Instruction::Call(addr),
+        0xd3 => Instruction::Out(data),
+        0xdb => Instruction::In(data),
+        0xe3 => Instruction::Xthl,
+        0xe9 => Instruction::Pchl,
+        0xeb => Instruction::Xchg,
+        0xf3 => Instruction::Di,
+        0xf9 => Instruction::Sphl,
+        // EI is the last opcode left
+        _ => Instruction::Ei,
+    }
+}
+
+/// Register or memory operand encoded in the lowest 3 bits of `code`, in
+/// order B,C,D,E,H,L,M,A.
+const fn operand(code: u8) -> Operand {
+    match code & 0x07 {
+        0 => Operand::Register(Register::B),
+        1 => Operand::Register(Register::C),
+        2 => Operand::Register(Register::D),
+        3 => Operand::Register(Register::E),
+        4 => Operand::Register(Register::H),
+        5 => Operand::Register(Register::L),
+        6 => Operand::Memory,
+        _ => Operand::Register(Register::A),
+    }
+}
+
+/// Register pair encoded in bits 4 and 5 of `opcode`, in order BC,DE,HL,SP.
+const fn pair(opcode: u8) -> RegisterPair {
+    match (opcode >> 4) & 0x03 {
+        0 => RegisterPair::BC,
+        1 => RegisterPair::DE,
+        2 => RegisterPair::HL,
+        _ => RegisterPair::SP,
+    }
+}
+
+/// Same as [`pair`] for PUSH and POP, which take PSW in place of SP.
+const fn stack_pair(opcode: u8) -> RegisterPair {
+    match pair(opcode) {
+        RegisterPair::SP => RegisterPair::PSW,
+        pair => pair,
+    }
+}
+
+/// Condition encoded in bits 3 to 5 of `opcode`.
+const fn condition(opcode: u8) -> Condition {
+    match (opcode >> 3) & 0x07 {
+        0 => Condition::NotZero,
+        1 => Condition::Zero,
+        2 => Condition::NoCarry,
+        3 => Condition::Carry,
+        4 => Condition::ParityOdd,
+        5 => Condition::ParityEven,
+        6 => Condition::Plus,
+        _ => Condition::Minus,
+    }
+}
+
+/// Builds the metadata of `opcode`.
+const fn metadata(opcode: u8) -> Metadata {
+    let instruction = DECODED[opcode as usize];
+    let (t_states, t_states_not_taken) = instruction.t_states();
+
+    Metadata {
+        mnemonic: instruction.mnemonic(),
+        len: instruction.len(),
+        t_states,
+        t_states_not_taken,
+        flags: instruction.flags(),
+        documented: !matches!(
+            opcode,
+            0x08 | 0x10 | 0x18 | 0x20 | 0x28 | 0x30 | 0x38 | 0xcb | 0xd9 | 0xdd | 0xed | 0xfd
+        ),
+    }
+}
+
+impl Condition {
+    /// Returns the flag tested by the condition and the value for which it
+    /// holds.
+    pub(crate) const fn flag(self) -> (Flag, bool) {
+        match self {
+            Self::NotZero => (Flag::Zero, false),
+            Self::Zero => (Flag::Zero, true),
+            Self::NoCarry => (Flag::Carry, false),
+            Self::Carry => (Flag::Carry, true),
+            Self::ParityOdd => (Flag::Parity, false),
+            Self::ParityEven => (Flag::Parity, true),
+            Self::Plus => (Flag::Sign, false),
+            Self::Minus => (Flag::Sign, true),
+        }
+    }
+}
+
+impl Operand {
+    /// Returns the 3 bit code of the operand, in order B,C,D,E,H,L,M,A.
+    const fn code(self) -> u8 {
+        match self {
+            Self::Register(Register::A) => 7,
+            Self::Register(reg) => reg as u8,
+            Self::Memory => 6,
+        }
+    }
+}
+
+impl Instruction {
+    /// Returns true if an opcode encodes the instruction. Register pairs and
+    /// operands must be ones the instruction takes, and RST numbers must be
+    /// below 8.
+    pub const fn is_valid(&self) -> bool {
+        match *self {
+            Self::Lxi(pair, _) | Self::Inx(pair) | Self::Dcx(pair) | Self::Dad(pair) => {
+                !matches!(pair, RegisterPair::PSW)
+            }
+            Self::Stax(pair) | Self::Ldax(pair) => {
+                matches!(pair, RegisterPair::BC | RegisterPair::DE)
+            }
+            Self::Pop(pair) | Self::Push(pair) => !matches!(pair, RegisterPair::SP),
+            // The opcode MOV M,M would have is HLT's
+            Self::Mov(Operand::Memory, Operand::Memory) => false,
+            Self::Rst(n) => n < 8,
+            _ => true,
+        }
+    }
+
+    /// Returns the documented opcode of the instruction, failing if it is not
+    /// valid.
+    pub const fn opcode(&self) -> Result<u8, InvalidInstruction> {
+        if !self.is_valid() {
+            return Err(InvalidInstruction(*self));
+        }
+
+        Ok(self.valid_opcode())
+    }
+
+    /// Returns the documented opcode of the instruction, which must be valid.
+    const fn valid_opcode(&self) -> u8 {
+        const fn pair(pair: RegisterPair) -> u8 {
+            match pair {
+                RegisterPair::BC => 0x00,
+                RegisterPair::DE => 0x10,
+                RegisterPair::HL => 0x20,
+                RegisterPair::SP | RegisterPair::PSW => 0x30,
+            }
+        }
+        const fn condition(condition: Condition) -> u8 {
+            (condition as u8) << 3
+        }
+
+        match *self {
+            Self::Nop => 0x00,
+            Self::Lxi(p, _) => 0x01 | pair(p),
+            Self::Stax(p) => 0x02 | pair(p),
+            Self::Ldax(p) => 0x0a | pair(p),
+            Self::Shld(_) => 0x22,
+            Self::Lhld(_) => 0x2a,
+            Self::Sta(_) => 0x32,
+            Self::Lda(_) => 0x3a,
+            Self::Inx(p) => 0x03 | pair(p),
+            Self::Dcx(p) => 0x0b | pair(p),
+            Self::Dad(p) => 0x09 | pair(p),
+            Self::Inr(op) => 0x04 | op.code() << 3,
+            Self::Dcr(op) => 0x05 | op.code() << 3,
+            Self::Mvi(op, _) => 0x06 | op.code() << 3,
+            Self::Rlc => 0x07,
+            Self::Rrc => 0x0f,
+            Self::Ral => 0x17,
+            Self::Rar => 0x1f,
+            Self::Daa => 0x27,
+            Self::Cma => 0x2f,
+            Self::Stc => 0x37,
+            Self::Cmc => 0x3f,
+            Self::Mov(dst, src) => 0x40 | dst.code() << 3 | src.code(),
+            Self::Hlt => 0x76,
+            Self::Add(op) => 0x80 | op.code(),
+            Self::Adc(op) => 0x88 | op.code(),
+            Self::Sub(op) => 0x90 | op.code(),
+            Self::Sbb(op) => 0x98 | op.code(),
+            Self::Ana(op) => 0xa0 | op.code(),
+            Self::Xra(op) => 0xa8 | op.code(),
+            Self::Ora(op) => 0xb0 | op.code(),
+            Self::Cmp(op) => 0xb8 | op.code(),
+            Self::Adi(_) => 0xc6,
+            Self::Aci(_) => 0xce,
+            Self::Sui(_) => 0xd6,
+            Self::Sbi(_) => 0xde,
+            Self::Ani(_) => 0xe6,
+            Self::Xri(_) => 0xee,
+            Self::Ori(_) => 0xf6,
+            Self::Cpi(_) => 0xfe,
+            Self::Ret => 0xc9,
+            Self::Rcc(c) => 0xc0 | condition(c),
+            Self::Jmp(_) => 0xc3,
+            Self::Jcc(c, _) => 0xc2 | condition(c),
+            Self::Call(_) => 0xcd,
+            Self::Ccc(c, _) => 0xc4 | condition(c),
+            Self::Rst(n) => 0xc7 | n << 3,
+            Self::Pop(p) => 0xc1 | pair(p),
+            Self::Push(p) => 0xc5 | pair(p),
+            Self::Out(_) => 0xd3,
+            Self::In(_) => 0xdb,
+            Self::Xthl => 0xe3,
+            Self::Pchl => 0xe9,
+            Self::Xchg => 0xeb,
+            Self::Di => 0xf3,
+            Self::Sphl => 0xf9,
+            Self::Ei => 0xfb,
+        }
+    }
+
+    /// Encodes the instruction, returning its bytes and its length, failing if
+    /// it is not valid.
+    pub const fn encode(&self) -> Result<([u8; 3], usize), InvalidInstruction> {
+        let opcode = match self.opcode() {
+            Ok(opcode) => opcode,
+            Err(error) => return Err(error),
+        };
+
+        Ok(match *self {
+            Self::Lxi(_, addr)
+            | Self::Shld(addr)
+            | Self::Lhld(addr)
+            | Self::Sta(addr)
+            | Self::Lda(addr)
+            | Self::Jmp(addr)
+            | Self::Jcc(_, addr)
+            | Self::Call(addr)
+            | Self::Ccc(_, addr) => {
+                let [low, high] = addr.to_le_bytes();
+                ([opcode, low, high], 3)
+            }
+            Self::Mvi(_, data)
+            | Self::Adi(data)
+            | Self::Aci(data)
+            | Self::Sui(data)
+            | Self::Sbi(data)
+            | Self::Ani(data)
+            | Self::Xri(data)
+            | Self::Ori(data)
+            | Self::Cpi(data)
+            | Self::Out(data)
+            | Self::In(data) => ([opcode, data, 0], 2),
+            _ => ([opcode, 0, 0], 1),
+        })
+    }
+
+    /// Returns the metadata of the instruction's opcode, failing if it is not
+    /// valid.
+    pub fn metadata(&self) -> Result<&'static Metadata, InvalidInstruction> {
+        Ok(&OPCODES[self.opcode()? as usize])
+    }
+
+    /// Returns the length of the instruction in bytes.
+    pub(crate) const fn len(&self) -> u8 {
+        match *self {
+            Self::Lxi(..)
+            | Self::Shld(_)
+            | Self::Lhld(_)
+            | Self::Sta(_)
+            | Self::Lda(_)
+            | Self::Jmp(_)
+            | Self::Jcc(..)
+            | Self::Call(_)
+            | Self::Ccc(..) => 3,
+            Self::Mvi(..)
+            | Self::Adi(_)
+            | Self::Aci(_)
+            | Self::Sui(_)
+            | Self::Sbi(_)
+            | Self::Ani(_)
+            | Self::Xri(_)
+            | Self::Ori(_)
+            | Self::Cpi(_)
+            | Self::Out(_)
+            | Self::In(_) => 2,
+            _ => 1,
+        }
+    }
+
+    pub const fn mnemonic(&self) -> &'static str {
+        const fn condition(condition: Condition, names: [&'static str; 8]) -> &'static str {
+            names[condition as usize]
+        }
+
+        match *self {
+            Self::Nop => "NOP",
+            Self::Lxi(..) => "LXI",
+            Self::Stax(_) => "STAX",
+            Self::Ldax(_) => "LDAX",
+            Self::Shld(_) => "SHLD",
+            Self::Lhld(_) => "LHLD",
+            Self::Sta(_) => "STA",
+            Self::Lda(_) => "LDA",
+            Self::Inx(_) => "INX",
+            Self::Dcx(_) => "DCX",
+            Self::Dad(_) => "DAD",
+            Self::Inr(_) => "INR",
+            Self::Dcr(_) => "DCR",
+            Self::Mvi(..) => "MVI",
+            Self::Rlc => "RLC",
+            Self::Rrc => "RRC",
+            Self::Ral => "RAL",
+            Self::Rar => "RAR",
+            Self::Daa => "DAA",
+            Self::Cma => "CMA",
+            Self::Stc => "STC",
+            Self::Cmc => "CMC",
+            Self::Mov(..) => "MOV",
+            Self::Hlt => "HLT",
+            Self::Add(_) => "ADD",
+            Self::Adc(_) => "ADC",
+            Self::Sub(_) => "SUB",
+            Self::Sbb(_) => "SBB",
+            Self::Ana(_) => "ANA",
+            Self::Xra(_) => "XRA",
+            Self::Ora(_) => "ORA",
+            Self::Cmp(_) => "CMP",
+            Self::Adi(_) => "ADI",
+            Self::Aci(_) => "ACI",
+            Self::Sui(_) => "SUI",
+            Self::Sbi(_) => "SBI",
+            Self::Ani(_) => "ANI",
+            Self::Xri(_) => "XRI",
+            Self::Ori(_) => "ORI",
+            Self::Cpi(_) => "CPI",
+            Self::Ret => "RET",
+            Self::Rcc(c) => condition(c, ["RNZ", "RZ", "RNC", "RC", "RPO", "RPE", "RP", "RM"]),
+            Self::Jmp(_) => "JMP",
+            Self::Jcc(c, _) => condition(c, ["JNZ", "JZ", "JNC", "JC", "JPO", "JPE", "JP", "JM"]),
+            Self::Call(_) => "CALL",
+            Self::Ccc(c, _) => condition(c, ["CNZ", "CZ", "CNC", "CC", "CPO", "CPE", "CP", "CM"]),
+            Self::Rst(_) => "RST",
+            Self::Pop(_) => "POP",
+            Self::Push(_) => "PUSH",
+            Self::Out(_) => "OUT",
+            Self::In(_) => "IN",
+            Self::Xthl => "XTHL",
+            Self::Pchl => "PCHL",
+            Self::Xchg => "XCHG",
+            Self::Di => "DI",
+            Self::Sphl => "SPHL",
+            Self::Ei => "EI",
+        }
+    }
+
+    /// Returns the T-states taken when the condition holds and when it does
+    /// not, without wait states.
+    const fn t_states(&self) -> (u8, u8) {
+        const fn memory(op: Operand, register: u8, memory: u8) -> u8 {
+            match op {
+                Operand::Memory => memory,
+                Operand::Register(_) => register,
+            }
+        }
+
+        let t_states = match *self {
+            Self::Rcc(_) => return (11, 5),
+            Self::Ccc(..) => return (17, 11),
+            Self::Nop
+            | Self::Rlc
+            | Self::Rrc
+            | Self::Ral
+            | Self::Rar
+            | Self::Daa
+            | Self::Cma
+            | Self::Stc
+            | Self::Cmc
+            | Self::Xchg
+            | Self::Di
+            | Self::Ei => 4,
+            Self::Inx(_) | Self::Dcx(_) | Self::Pchl | Self::Sphl => 5,
+            Self::Stax(_)
+            | Self::Ldax(_)
+            | Self::Hlt
+            | Self::Adi(_)
+            | Self::Aci(_)
+            | Self::Sui(_)
+            | Self::Sbi(_)
+            | Self::Ani(_)
+            | Self::Xri(_)
+            | Self::Ori(_)
+            | Self::Cpi(_) => 7,
+            Self::Lxi(..)
+            | Self::Dad(_)
+            | Self::Ret
+            | Self::Jmp(_)
+            | Self::Jcc(..)
+            | Self::Pop(_)
+            | Self::Out(_)
+            | Self::In(_) => 10,
+            Self::Rst(_) | Self::Push(_) => 11,
+            Self::Sta(_) | Self::Lda(_) => 13,
+            Self::Shld(_) | Self::Lhld(_) => 16,
+            Self::Call(_) => 17,
+            Self::Xthl => 18,
+            Self::Inr(op) | Self::Dcr(op) => memory(op, 5, 10),
+            Self::Mvi(op, _) => memory(op, 7, 10),
+            Self::Mov(Operand::Memory, _) | Self::Mov(_, Operand::Memory) => 7,
+            Self::Mov(..) => 5,
+            Self::Add(op)
+            | Self::Adc(op)
+            | Self::Sub(op)
+            | Self::Sbb(op)
+            | Self::Ana(op)
+            | Self::Xra(op)
+            | Self::Ora(op)
+            | Self::Cmp(op) => memory(op, 4, 7),
+        };
+
+        (t_states, t_states)
+    }
+
+    /// Returns the flags the instruction may change.
+    const fn flags(&self) -> &'static [Flag] {
+        match *self {
+            Self::Inr(_) | Self::Dcr(_) => NOT_CARRY,
+            Self::Add(_)
+            | Self::Adc(_)
+            | Self::Sub(_)
+            | Self::Sbb(_)
+            | Self::Ana(_)
+            | Self::Xra(_)
+            | Self::Ora(_)
+            | Self::Cmp(_)
+            | Self::Adi(_)
+            | Self::Aci(_)
+            | Self::Sui(_)
+            | Self::Sbi(_)
+            | Self::Ani(_)
+            | Self::Xri(_)
+            | Self::Ori(_)
+            | Self::Cpi(_)
+            | Self::Daa
+            | Self::Pop(RegisterPair::PSW) => ALL,
+            Self::Dad(_)
+            | Self::Rlc
+            | Self::Rrc
+            | Self::Ral
+            | Self::Rar
+            | Self::Stc
+            | Self::Cmc => CARRY,
+            _ => &[],
+        }
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Register(reg) => write!(f, "{reg:?}"),
+            Self::Memory => write!(f, "M"),
+        }
+    }
+}
+
+/// Writes `pair` the way Intel mnemonics name it, by its first register.
+fn write_pair(f: &mut fmt::Formatter<'_>, pair: RegisterPair) -> fmt::Result {
+    match pair {
+        RegisterPair::BC => write!(f, "B"),
+        RegisterPair::DE => write!(f, "D"),
+        RegisterPair::HL => write!(f, "H"),
+        RegisterPair::SP => write!(f, "SP"),
+        RegisterPair::PSW => write!(f, "PSW"),
+    }
+}
+
+impl fmt::Display for Instruction {
+    /// Formats the instruction in Intel syntax, e.g. `MVI A,0x2a`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.mnemonic())?;
+
+        match *self {
+            Self::Lxi(pair, addr) => {
+                write!(f, " ")?;
+                write_pair(f, pair)?;
+                write!(f, ",0x{addr:04x}")
+            }
+            Self::Stax(pair)
+            | Self::Ldax(pair)
+            | Self::Inx(pair)
+            | Self::Dcx(pair)
+            | Self::Dad(pair)
+            | Self::Pop(pair)
+            | Self::Push(pair) => {
+                write!(f, " ")?;
+                write_pair(f, pair)
+            }
+            Self::Shld(addr)
+            | Self::Lhld(addr)
+            | Self::Sta(addr)
+            | Self::Lda(addr)
+            | Self::Jmp(addr)
+            | Self::Jcc(_, addr)
+            | Self::Call(addr)
+            | Self::Ccc(_, addr) => write!(f, " 0x{addr:04x}"),
+            Self::Inr(op)
+            | Self::Dcr(op)
+            | Self::Add(op)
+            | Self::Adc(op)
+            | Self::Sub(op)
+            | Self::Sbb(op)
+            | Self::Ana(op)
+            | Self::Xra(op)
+            | Self::Ora(op)
+            | Self::Cmp(op) => write!(f, " {op}"),
+            Self::Mvi(op, data) => write!(f, " {op},0x{data:02x}"),
+            Self::Mov(dst, src) => write!(f, " {dst},{src}"),
+            Self::Adi(data)
+            | Self::Aci(data)
+            | Self::Sui(data)
+            | Self::Sbi(data)
+            | Self::Ani(data)
+            | Self::Xri(data)
+            | Self::Ori(data)
+            | Self::Cpi(data)
+            | Self::Out(data)
+            | Self::In(data) => write!(f, " 0x{data:02x}"),
+            Self::Rst(n) => write!(f, " {n}"),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// An [`Instruction`] no opcode encodes, such as `Stax(RegisterPair::HL)`,
+/// `Mov(Operand::Memory, Operand::Memory)` or `Rst(8)`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct InvalidInstruction(pub Instruction);
+
+impl fmt::Display for InvalidInstruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} is not an instruction of the 8080", self.0)
+    }
+}
+
+impl std::error::Error for InvalidInstruction {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CPU;
+    use std::collections::HashSet;
+
+    const PAIRS: [RegisterPair; 5] = [
+        RegisterPair::BC,
+        RegisterPair::DE,
+        RegisterPair::HL,
+        RegisterPair::SP,
+        RegisterPair::PSW,
+    ];
+    const CONDITIONS: [Condition; 8] = [
+        Condition::NotZero,
+        Condition::Zero,
+        Condition::NoCarry,
+        Condition::Carry,
+        Condition::ParityOdd,
+        Condition::ParityEven,
+        Condition::Plus,
+        Condition::Minus,
+    ];
+
+    /// Returns every instruction with every register pair, operand and
+    /// condition, valid or not, with the given immediate operands.
+    fn every(data: u8, addr: u16) -> Vec<Instruction> {
+        use Instruction::*;
+
+        let operands: Vec<_> = (0..8).map(operand).collect();
+        let mut all = vec![
+            Nop,
+            Shld(addr),
+            Lhld(addr),
+            Sta(addr),
+            Lda(addr),
+            Rlc,
+            Rrc,
+            Ral,
+            Rar,
+            Daa,
+            Cma,
+            Stc,
+            Cmc,
+            Hlt,
+            Adi(data),
+            Aci(data),
+            Sui(data),
+            Sbi(data),
+            Ani(data),
+            Xri(data),
+            Ori(data),
+            Cpi(data),
+            Ret,
+            Jmp(addr),
+            Call(addr),
+            Out(data),
+            In(data),
+            Xthl,
+            Pchl,
+            Xchg,
+            Di,
+            Sphl,
+            Ei,
+        ];
+
+        for pair in PAIRS {
+            all.extend([
+                Lxi(pair, addr),
+                Stax(pair),
+                Ldax(pair),
+                Inx(pair),
+                Dcx(pair),
+                Dad(pair),
+                Pop(pair),
+                Push(pair),
+            ]);
+        }
+        for &op in &operands {
+            all.extend([
+                Inr(op),
+                Dcr(op),
+                Mvi(op, data),
+                Add(op),
+                Adc(op),
+                Sub(op),
+                Sbb(op),
+                Ana(op),
+                Xra(op),
+                Ora(op),
+                Cmp(op),
+            ]);
+            all.extend(operands.iter().map(|&src| Mov(op, src)));
+        }
+        for condition in CONDITIONS {
+            all.extend([Rcc(condition), Jcc(condition, addr), Ccc(condition, addr)]);
+        }
+        all.extend((0..10).map(Rst));
+
+        all
+    }
+
+    #[test]
+    fn every_opcode_round_trips() {
+        for opcode in 0..=0xff {
+            let bytes = [opcode, 0x34, 0x12];
+            let instruction = decode(bytes);
+            let (encoded, len) = instruction.encode().unwrap();
+
+            assert_eq!(len, OPCODES[opcode as usize].len as usize);
+            assert_eq!(decode(encoded), instruction, "{opcode:#04x}");
+            assert_eq!(encoded[1..len], bytes[1..len]);
+            assert_eq!(instruction.metadata().unwrap().len as usize, len);
+
+            // Undocumented opcodes encode as the documented one they behave as
+            if OPCODES[opcode as usize].documented {
+                assert_eq!(encoded[0], opcode);
+            }
+        }
+    }
+
+    #[test]
+    fn every_valid_instruction_round_trips() {
+        let mut opcodes = HashSet::new();
+
+        for instruction in every(0x2a, 0xbeef)
+            .into_iter()
+            .filter(Instruction::is_valid)
+        {
+            let (bytes, len) = instruction.encode().unwrap();
+
+            assert_eq!(decode(bytes), instruction);
+            assert_eq!(len, instruction.metadata().unwrap().len as usize);
+            assert!(opcodes.insert(bytes[0]), "{instruction:?}");
+        }
+
+        // Every documented opcode is covered
+        assert_eq!(
+            opcodes.len(),
+            OPCODES.iter().filter(|m| m.documented).count()
+        );
+    }
+
+    #[test]
+    fn invalid_instructions_are_rejected() {
+        let invalid: Vec<_> = every(0x2a, 0xbeef)
+            .into_iter()
+            .filter(|instruction| !instruction.is_valid())
+            .collect();
+
+        // LXI, INX, DCX and DAD of PSW, STAX and LDAX of HL, SP and PSW, POP
+        // and PUSH of SP, MOV M,M and RST 8 and 9
+        assert_eq!(invalid.len(), 4 + 6 + 2 + 1 + 2);
+
+        for instruction in invalid {
+            let error = InvalidInstruction(instruction);
+
+            assert_eq!(instruction.opcode(), Err(error));
+            assert_eq!(instruction.encode(), Err(error));
+            assert_eq!(instruction.metadata(), Err(error));
+
+            let mut cpu = CPU::new(&[]);
+            let state = cpu.save_state();
+            assert_eq!(cpu.execute(&mut (), instruction), Err(error));
+            assert_eq!(cpu.save_state(), state);
+        }
+    }
+}
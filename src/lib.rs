@@ -1,14 +1,125 @@
-/// Clock speed in Hz
+mod alu;
+mod batch;
+mod builder;
+mod instruction;
+mod machine;
+mod memory;
+mod native;
+mod pic;
+mod recompiler;
+mod registers;
+mod replay;
+mod rewind;
+mod scheduler;
+mod state;
+
+use alu::Alu;
+pub use batch::Batch;
+pub use builder::CPUBuilder;
+pub use instruction::{
+    Condition, Instruction, InvalidInstruction, Metadata, OPCODES, Operand, decode,
+};
+pub use machine::{CycleKind, MachineCycle, Pins, status};
+pub use memory::{MapError, Memory, MemoryMap, PAGE_SIZE};
+pub use native::{Compiled, Native};
+pub use pic::Pic;
+pub use recompiler::Recompiler;
+pub use registers::{Flag, Flags, Register, RegisterPair};
+pub use replay::{Input, Recorded, Recorder, Recording, Replay};
+pub use rewind::Rewind;
+pub use scheduler::{Device, Scheduler};
+pub use state::StateError;
+use std::collections::VecDeque;
+
+/// Default clock speed in Hz
 pub const RATE: u32 = 2_000_000;
 const KB: usize = 1024;
 pub const MEM_SIZE: usize = KB * 64;
 
+/// Packs `instruction` as supplied by an interrupting device, if it is a
+/// single whole instruction.
+fn supplied(instruction: &[u8]) -> Option<[u8; 3]> {
+    let opcode = *instruction.first()?;
+
+    if instruction.len() != OPCODES[opcode as usize].len as usize {
+        return None;
+    }
+
+    let mut packed = [0; 3];
+    packed[..instruction.len()].copy_from_slice(instruction);
+
+    Some(packed)
+}
+
 pub trait Bus {
     /// Reads a byte from the specified `port`.
     fn read(&mut self, _cpu: &CPU, port: u8) -> u8;
 
     /// Writes a byte to the specified `port`.
     fn write(&mut self, _cpu: &CPU, port: u8, data: u8);
+
+    /// Intercepts an opcode fetch from `addr`.
+    ///
+    /// Returning `None` fetches from the [`CPU`]'s own memory. Defaults to
+    /// [`Bus::load`].
+    fn fetch(&mut self, cpu: &CPU, addr: u16) -> Option<u8> {
+        self.load(cpu, addr)
+    }
+
+    /// Intercepts a memory read from `addr`. This covers operands, data and
+    /// stack reads.
+    ///
+    /// Returning `None` reads from the [`CPU`]'s own memory.
+    fn load(&mut self, _cpu: &CPU, _addr: u16) -> Option<u8> {
+        None
+    }
+
+    /// Intercepts a memory write of `data` to `addr`.
+    ///
+    /// Returning `true` marks the write as handled and leaves the [`CPU`]'s
+    /// own memory untouched. Returning `false` lets the write through.
+    fn store(&mut self, _cpu: &CPU, _addr: u16, _data: u8) -> bool {
+        false
+    }
+
+    /// Returns the number of wait states to insert into a machine cycle of
+    /// `kind` at `addr`, as a slow device would by holding READY low. For I/O
+    /// the port is placed on both halves of `addr`.
+    ///
+    /// These add to any wait states from the [`MemoryMap`].
+    fn wait_states(&mut self, _cpu: &CPU, _kind: CycleKind, _addr: u16) -> u8 {
+        0
+    }
+
+    /// Returns true if a device holds the INT input active.
+    ///
+    /// Sampled between instructions while interrupts are enabled, which
+    /// includes a halted [`CPU`]. Requests made through [`CPU::interrupt`] or
+    /// [`CPU::assert_interrupt`] take precedence.
+    fn interrupt_requested(&mut self, _cpu: &CPU) -> bool {
+        false
+    }
+
+    /// Returns the byte placed on the data bus by the interrupting device
+    /// during an interrupt acknowledge cycle.
+    ///
+    /// Called once for each byte of the instruction supplied in response to
+    /// [`Bus::interrupt_requested`]. Defaults to RST 7, as read from a data bus
+    /// pulled high.
+    fn acknowledge(&mut self, _cpu: &CPU) -> u8 {
+        0xff
+    }
+}
+
+/// Supplier of the instruction executed in response to an interrupt.
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+enum Supplier {
+    /// An instruction supplied through [`CPU::interrupt`] or
+    /// [`CPU::assert_interrupt`].
+    Cpu([u8; 3]),
+    /// A device behind the [`Bus`], read through [`Bus::acknowledge`].
+    Bus,
 }
 
 impl Bus for () {
@@ -19,6 +130,8 @@ impl Bus for () {
     }
 }
 
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct CPU {
     /// Stack pointer
     sp: u16,
@@ -34,8 +147,11 @@ pub struct CPU {
     /// 1: Unsed, always 1
     /// C: Carry flag
     flag: u8,
+    /// Last arithmetic or logic operation, whose flags are yet to be worked
+    /// out into `flag`.
+    alu: Alu,
     /// Memory
-    memory: [u8; MEM_SIZE],
+    memory: Memory,
     /// Registers in order B,C,D,E,H,L,A
     registers: [u8; 7],
 
@@ -45,8 +161,39 @@ pub struct CPU {
     /// 1: Enable after next code
     interrupt: u8,
 
-    /// Pending RST supplied by interrupting device.
-    pending_interrupt: Option<u8>,
+    /// Pending instruction supplied by interrupting device.
+    pending_interrupt: Option<Supplier>,
+    /// Instruction supplied by the device holding the INT input active.
+    int: Option<[u8; 3]>,
+
+    /// Level of the HOLD input.
+    hold: bool,
+    /// Whether the bus has been released in response to HOLD.
+    hlda: bool,
+    /// T-states left of a transfer started through [`CPU::dma`], during which
+    /// the bus stays released.
+    dma: u64,
+
+    /// T-states elapsed since power on.
+    cycles: u64,
+    /// Instructions executed since power on.
+    instructions: u64,
+    /// Clock speed in Hz.
+    rate: u32,
+
+    /// Whether machine cycles are being recorded for [`CPU::step`].
+    #[cfg_attr(feature = "serde", serde(skip))]
+    tracing: bool,
+    /// Machine cycles of the current instruction not yet returned by
+    /// [`CPU::step`].
+    #[cfg_attr(feature = "serde", serde(skip))]
+    machine_cycles: VecDeque<MachineCycle>,
+    /// Whether there is nothing to do between instructions but to poll the
+    /// bus for INT and fetch the next one, with no machine cycles to trace
+    /// and no wait states from memory, so [`CPU::cycle`] can skip checking.
+    /// Cleared by anything which may change that.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    quiet: bool,
 }
 
 impl CPU {
@@ -66,1238 +213,1047 @@ impl CPU {
             pc: start,
             sp: 0xFFFF,
             flag: 2,
-            memory: program,
+            alu: Alu::default(),
+            memory: Memory::new(program),
             registers: [0; 7],
             halt: false,
             interrupt: 0,
             pending_interrupt: None,
+            int: None,
+            hold: false,
+            hlda: false,
+            dma: 0,
+            cycles: 0,
+            instructions: 0,
+            rate: RATE,
+            tracing: false,
+            machine_cycles: VecDeque::new(),
+            quiet: false,
         }
     }
 
-    pub fn memory(&self) -> &[u8] {
+    /// Returns the memory of the CPU. It is no longer a flat `&[u8]`, as pages
+    /// may be mapped, mirrored or banked, but it can still be indexed by
+    /// address, and [`Memory::read_range`] copies the bytes visible in a range.
+    pub fn memory(&self) -> &Memory {
         &self.memory
     }
 
-    pub fn cycle(&mut self, bus: &mut impl Bus) -> u8 {
-        if self.halt {
-            return 1;
-        }
-
-        //if self.now.elapsed() < self.end_cycle {
-        //    return 0;
-        //}
-
-        // One instruction delay for enabling interrupt
-        if self.interrupt == 2 {
-            self.interrupt = 4;
-        } else if self.interrupt == 4 {
-            self.interrupt = 1;
-        }
-
-        let opcode = match self.pending_interrupt.take() {
-            Some(rst) => {
-                // Memory RSTs save the next PC. Interrupt RSTs save the current
-                // PC. Subtracting here unifies the two. Later when saving the
-                // PC, it'll be incremented by 1.
-                self.pc -= 1;
-                rst
-            }
-            None => self.memory[self.pc as usize],
-        };
-
-        let maddr = {
-            let h = self.registers[4] as u16;
-            let l = self.registers[5] as u16;
+    pub fn memory_mut(&mut self) -> &mut Memory {
+        self.quiet = false;
+        &mut self.memory
+    }
 
-            ((h << 8) | l) as usize
-        };
+    /// Lays out the address space according to `map`, replacing any previous
+    /// map. The contents of the first 64K of memory are kept.
+    pub fn map_memory(&mut self, map: &MemoryMap) -> Result<(), MapError> {
+        self.quiet = false;
+        self.memory.map(map)
+    }
 
-        let reg8f = |opcode: u8| {
-            match opcode {
-                // B
-                0x08 => self.registers[0],
-                // C
-                0x09 => self.registers[1],
-                // D
-                0x0a => self.registers[2],
-                // E
-                0x0b => self.registers[3],
-                // H
-                0x0c => self.registers[4],
-                // L
-                0x0d => self.registers[5],
-                // M
-                0x0e => self.memory[maddr],
-                // A
-                0x0f => self.registers[6],
-
-                _ => unreachable!(),
-            }
-        };
-        let reg07 = |opcode: u8| {
-            match opcode {
-                // B
-                0x00 => self.registers[0],
-                // C
-                0x01 => self.registers[1],
-                // D
-                0x02 => self.registers[2],
-                // E
-                0x03 => self.registers[3],
-                // H
-                0x04 => self.registers[4],
-                // L
-                0x05 => self.registers[5],
-                // M
-                0x06 => self.memory[maddr],
-                // A
-                0x07 => self.registers[6],
-
-                _ => unreachable!(),
-            }
-        };
+    /// Makes `bank` visible in the banked windows selected through `port`, as
+    /// if `bank` was written to `port`.
+    pub fn select_bank(&mut self, port: u8, bank: u8) {
+        self.memory.select_bank(port, bank);
+    }
 
-        let duration = match opcode {
-            // NOP
-            0x00 | 0x10 | 0x20 | 0x30 | 0x08 | 0x18 | 0x28 | 0x38 => {
-                self.pc += 1;
-                4.0
-            }
+    /// Executes the next instruction and returns the number of T-states it
+    /// took, including wait states. A halted [`CPU`] idles for 1 T-state per
+    /// call until an interrupt is serviced, as does one which has released the
+    /// bus in response to HOLD.
+    #[inline(always)]
+    pub fn cycle(&mut self, bus: &mut impl Bus) -> u64 {
+        if self.quiet && self.interrupt == 1 && bus.interrupt_requested(self) {
+            self.pending_interrupt = Some(Supplier::Bus);
+            self.quiet = false;
+        }
 
-            // CALL a
-            0xcd | 0xdd | 0xed | 0xfd => self.call(true),
-            // CZ
-            0xcc => self.call(self.flag & 64 != 0),
-            // CNZ
-            0xc4 => self.call(self.flag & 64 == 0),
-            // CC
-            0xdc => self.call(self.flag & 1 != 0),
-            // CNC
-            0xd4 => self.call(self.flag & 1 == 0),
-            // CPE
-            0xec => self.call(self.flag & 4 != 0),
-            // CP0
-            0xe4 => self.call(self.flag & 4 == 0),
-            // CM
-            0xfc => self.call(self.flag & 128 != 0),
-            // CP
-            0xf4 => self.call(self.flag & 128 == 0),
-
-            // INR B
-            0x04 => self.incr(0),
-            // INR C
-            0x0c => self.incr(1),
-            // INR D
-            0x14 => self.incr(2),
-            // INR E
-            0x1c => self.incr(3),
-            // INR H
-            0x24 => self.incr(4),
-            // INR L
-            0x2c => self.incr(5),
-            // INR A
-            0x3c => self.incr(6),
-            // INR M
-            0x34 => {
-                let m = self.memory[maddr];
-                let res = m.wrapping_add(1);
-                let ac = ((m & 0x0F) + (1 & 0x0F)) & 0x10 != 0;
-
-                self.memory[maddr] = res;
-
-                let z = res == 0;
-                let p = (res.count_ones() % 2) == 0;
-
-                // Affected flags get reset to 0
-                self.flag &= 0b00000011;
-
-                self.flag |= res & 0x80;
-                self.flag |= u8::from(z) << 6;
-                self.flag |= u8::from(ac) << 4;
-                self.flag |= u8::from(p) << 2;
-
-                self.pc += 1;
-                10.0
-            }
+        if !self.quiet {
+            return self.cycle_slow(bus);
+        }
 
-            // DCR B
-            0x05 => self.dcr(0),
-            // DCR C
-            0x0d => self.dcr(1),
-            // DCR D
-            0x15 => self.dcr(2),
-            // DCR E
-            0x1d => self.dcr(3),
-            // DCR H
-            0x25 => self.dcr(4),
-            // DCR L
-            0x2d => self.dcr(5),
-            // DCR A
-            0x3d => self.dcr(6),
-            // DCR M
-            0x35 => {
-                let m = self.memory[maddr];
-                let res = m.wrapping_sub(1);
-                let ac = calc_ac(m, 1);
-                let p = (res.count_ones() % 2) == 0;
-
-                self.memory[maddr] = res;
-
-                self.flag &= 0b00000011;
-
-                self.flag |= res & 0x80;
-                self.flag |= u8::from(res == 0) << 6;
-                self.flag |= u8::from(ac) << 4;
-                self.flag |= u8::from(p) << 2;
-
-                self.pc += 1;
-
-                10.0
-            }
+        let start = self.cycles;
+        let pc = self.pc;
+        let opcode = self.fetch(bus, pc);
+        let metadata = &OPCODES[opcode as usize];
 
-            // JMP
-            0xc3 | 0xcb => self.jump(true),
-            // JZ
-            0xca => self.jump(self.flag & 64 != 0),
-            // JNZ
-            0xc2 => self.jump(self.flag & 64 == 0),
-            // JC
-            0xda => self.jump(self.flag & 1 != 0),
-            // JNC
-            0xd2 => self.jump(self.flag & 1 == 0),
-            // JPE
-            0xea => self.jump(self.flag & 4 != 0),
-            // JPO
-            0xe2 => self.jump(self.flag & 4 == 0),
-            // JM
-            0xfa => self.jump(self.flag & 128 != 0),
-            // JP
-            0xf2 => self.jump(self.flag & 128 == 0),
-            // PCHL
-            0xe9 => {
-                let h = self.registers[4] as u16;
-                let l = self.registers[5] as u16;
-
-                self.pc = (h << 8) | l;
-
-                5.0
-            }
+        // Most instructions have no operands, and running them apart from the
+        // rest spares them the operand reads
+        if metadata.len == 1 {
+            self.run(bus, decode([opcode, 0, 0]), metadata);
+        } else {
+            let instruction = self.read_operands(bus, opcode, metadata);
+            self.run(bus, instruction, metadata);
+        }
 
-            // RET
-            0xc9 | 0xd9 => {
-                self.ret(true);
-                10.0
-            }
-            // RZ
-            0xc8 => self.ret(self.flag & 64 != 0),
-            // RNZ
-            0xc0 => self.ret(self.flag & 64 == 0),
-            // RC
-            0xd8 => self.ret(self.flag & 1 != 0),
-            // RNC
-            0xd0 => self.ret(self.flag & 1 == 0),
-            // RPE
-            0xe8 => self.ret(self.flag & 4 != 0),
-            // RPO
-            0xe0 => self.ret(self.flag & 4 == 0),
-            // RM
-            0xf8 => self.ret(self.flag & 128 != 0),
-            // RP
-            0xf0 => self.ret(self.flag & 128 == 0),
-
-            // LXI BC
-            0x01 => self.lxi(0, 1),
-            // LXI DE
-            0x11 => self.lxi(2, 3),
-            // LXI HL
-            0x21 => self.lxi(4, 5),
-            // LXI SP
-            0x31 => {
-                let hi = self.memory[(self.pc + 2) as usize] as u16;
-                let low = self.memory[(self.pc + 1) as usize] as u16;
-
-                self.sp = (hi << 8) | low;
-
-                self.pc += 3;
-                10.0
-            }
+        self.cycles - start
+    }
 
-            // MVI B
-            0x06 => self.mvi(0),
-            //MVI C
-            0x0e => self.mvi(1),
-            // MVI D
-            0x16 => self.mvi(2),
-            // MVI E
-            0x1e => self.mvi(3),
-            // MVI H
-            0x26 => self.mvi(4),
-            // MVI L
-            0x2e => self.mvi(5),
-            // MVI A
-            0x3e => self.mvi(6),
-            // MVI M
-            0x36 => {
-                self.memory[maddr] = self.memory[(self.pc + 1) as usize];
-
-                self.pc += 2;
-
-                10.0
-            }
+    /// Does the work of [`CPU::cycle`] between instructions, then executes
+    /// the next instruction, if any.
+    #[inline(never)]
+    fn cycle_slow(&mut self, bus: &mut impl Bus) -> u64 {
+        // HOLD is honoured between instructions, even when halted.
+        self.hlda = self.hold || self.dma > 0;
+        if self.hlda {
+            self.dma = self.dma.saturating_sub(1);
+            self.cycles += 1;
+            return 1;
+        }
 
-            // LDAX BC
-            0x0a => {
-                let b = self.registers[0] as u16;
-                let c = self.registers[1] as u16;
-                let addr = (b << 8) | c;
+        if !self.tracing && !self.machine_cycles.is_empty() {
+            self.machine_cycles.clear();
+        }
 
-                self.registers[6] = self.memory[addr as usize];
+        self.sample_interrupts(bus);
+        self.settle();
 
-                self.pc += 1;
-                7.0
+        let start = self.cycles;
+        let (instruction, metadata) = if self.halt || self.pending_interrupt.is_some() {
+            match self.acknowledge(bus) {
+                Some(next) => next,
+                None => return 1,
             }
-            // LDAX DE
-            0x1a => {
-                let d = self.registers[2] as u16;
-                let e = self.registers[3] as u16;
-                let addr = (d << 8) | e;
+        } else {
+            self.fetch_instruction(bus)
+        };
+        self.execute_next(bus, instruction, metadata);
 
-                self.registers[6] = self.memory[addr as usize];
+        self.cycles - start
+    }
 
-                self.pc += 1;
-                7.0
-            }
+    /// Reads the instruction at the PC through a fetch of its opcode and
+    /// memory reads of its operands, and decodes it.
+    #[inline(always)]
+    fn fetch_instruction(&mut self, bus: &mut impl Bus) -> (Instruction, &'static Metadata) {
+        let opcode = self.fetch(bus, self.pc);
+        let metadata = &OPCODES[opcode as usize];
 
-            // LDA
-            0x3a => {
-                let hi = self.memory[(self.pc + 2) as usize] as u16;
-                let low = self.memory[(self.pc + 1) as usize] as u16;
-                let addr = (hi << 8) | low;
+        (self.read_operands(bus, opcode, metadata), metadata)
+    }
 
-                self.registers[6] = self.memory[addr as usize];
+    /// Reads the operands of the instruction at the PC, whose opcode has
+    /// been fetched, and decodes it.
+    #[inline(always)]
+    fn read_operands(
+        &mut self,
+        bus: &mut impl Bus,
+        opcode: u8,
+        metadata: &Metadata,
+    ) -> Instruction {
+        let pc = self.pc;
+
+        // Reading past the instruction is harmless, while the machine cycles
+        // only run for its operands
+        let mut bytes = [
+            opcode,
+            self.memory.read(pc.wrapping_add(1)),
+            self.memory.read(pc.wrapping_add(2)),
+        ];
+        for (idx, byte) in bytes
+            .iter_mut()
+            .enumerate()
+            .take(metadata.len as usize)
+            .skip(1)
+        {
+            let addr = pc.wrapping_add(idx as u16);
+            *byte = self.read_cycle(bus, CycleKind::MemoryRead, addr, *byte);
+        }
 
-                self.pc += 3;
-                13.0
-            }
+        decode(bytes)
+    }
 
-            // STA
-            0x32 => {
-                let hi = self.memory[(self.pc + 2) as usize] as u16;
-                let low = self.memory[(self.pc + 1) as usize] as u16;
-                let addr = (hi << 8) | low;
+    /// Reads the instruction supplied by the pending interrupt, leaving HLT
+    /// if halted. Idles for 1 T-state and returns `None` if halted with no
+    /// interrupt pending.
+    #[cold]
+    fn acknowledge(&mut self, bus: &mut impl Bus) -> Option<(Instruction, &'static Metadata)> {
+        // A halted CPU only leaves HLT to service an interrupt
+        let Some(supplier) = self.pending_interrupt.take() else {
+            self.cycles += 1;
+            return None;
+        };
 
-                self.memory[addr as usize] = self.registers[6];
+        let kind = if self.halt {
+            self.halt = false;
+            CycleKind::InterruptAcknowledgeHalt
+        } else {
+            CycleKind::InterruptAcknowledge
+        };
 
-                self.pc += 3;
-                13.0
-            }
+        // Acknowledging an interrupt disables further interrupts
+        self.interrupt = 0;
 
-            // STAX BC
-            0x02 => {
-                let b = self.registers[0] as u16;
-                let c = self.registers[1] as u16;
-                let addr = (b << 8) | c;
+        let opcode = self.supplied_byte(bus, supplier, kind, 0);
+        let metadata = &OPCODES[opcode as usize];
+        let mut bytes = [opcode, 0, 0];
+        for idx in 1..metadata.len {
+            bytes[idx as usize] =
+                self.supplied_byte(bus, supplier, CycleKind::InterruptAcknowledge, idx);
+        }
 
-                self.memory[addr as usize] = self.registers[6];
+        // Instructions from memory move the PC past themselves. Supplied
+        // instructions leave it at the interrupted one. Moving it back here
+        // unifies the two, so that CALLs and RSTs save the interrupted PC.
+        self.pc = self.pc.wrapping_sub(metadata.len as u16);
 
-                self.pc += 1;
-                7.0
-            }
-            // STAX DE
-            0x12 => {
-                let d = self.registers[2] as u16;
-                let e = self.registers[3] as u16;
-                let addr = (d << 8) | e;
+        Some((decode(bytes), metadata))
+    }
 
-                self.memory[addr as usize] = self.registers[6];
+    /// Reads byte `idx` of the instruction supplied by `supplier` through an
+    /// interrupt acknowledge cycle of `kind`.
+    fn supplied_byte(
+        &mut self,
+        bus: &mut impl Bus,
+        supplier: Supplier,
+        kind: CycleKind,
+        idx: u8,
+    ) -> u8 {
+        let pc = self.pc;
+        let wait = self.wait(bus, kind, pc);
+        let data = match supplier {
+            Supplier::Cpu(instruction) => instruction[idx as usize],
+            Supplier::Bus => bus.acknowledge(self),
+        };
+        self.record(kind, pc, data, wait);
 
-                self.pc += 1;
-                7.0
-            }
+        data
+    }
 
-            // LHLD
-            0x2a => {
-                let hi = self.memory[(self.pc + 2) as usize] as u16;
-                let low = self.memory[(self.pc + 1) as usize] as u16;
-                let addr = (hi << 8) | low;
+    /// Advances the delay of EI and samples INT, as done between
+    /// instructions.
+    fn sample_interrupts(&mut self, bus: &mut impl Bus) {
+        // One instruction delay for enabling interrupt
+        if self.interrupt == 2 {
+            self.interrupt = 4;
+        } else if self.interrupt == 4 {
+            self.interrupt = 1;
+        }
 
-                self.registers[5] = self.memory[addr as usize];
-                self.registers[4] = self.memory[(addr + 1) as usize];
+        // INT is sampled between instructions while interrupts are enabled
+        if self.interrupt == 1 && self.pending_interrupt.is_none() {
+            self.pending_interrupt = match self.int.take() {
+                Some(instruction) => Some(Supplier::Cpu(instruction)),
+                None => bus.interrupt_requested(self).then_some(Supplier::Bus),
+            };
+        }
+    }
 
-                self.pc += 3;
-                16.0
-            }
+    /// Works out whether anything but INT needs checking between
+    /// instructions.
+    fn settle(&mut self) {
+        self.quiet = !(self.hold
+            || self.halt
+            || self.tracing
+            || self.memory.waits()
+            || self.interrupt > 1
+            || self.int.is_some()
+            || self.pending_interrupt.is_some());
+    }
 
-            // SHLD
-            0x22 => {
-                let hi = self.memory[(self.pc + 2) as usize] as u16;
-                let low = self.memory[(self.pc + 1) as usize] as u16;
-                let addr = (hi << 8) | low;
+    /// Does the work of [`CPU::cycle`] between instructions on behalf of code
+    /// compiled by a [`Recompiler`]. Returns true if the next instruction is
+    /// to be fetched from memory at the PC before `end` T-states have elapsed
+    /// since power on, in which case it must be executed by the compiled
+    /// code, starting with [`CPU::native_fetch`].
+    ///
+    /// Returning false leaves the rest to [`CPU::cycle`], as when the [`CPU`]
+    /// is halted, holding the bus, tracing machine cycles for [`CPU::step`] or
+    /// about to service an interrupt.
+    #[doc(hidden)]
+    #[inline]
+    pub fn native_boundary(&mut self, bus: &mut impl Bus, end: u64) -> bool {
+        if self.cycles >= end {
+            return false;
+        }
 
-                self.memory[addr as usize] = self.registers[5];
-                self.memory[(addr + 1) as usize] = self.registers[4];
+        if !self.quiet {
+            return self.native_boundary_slow(bus);
+        }
 
-                self.pc += 3;
-                16.0
-            }
+        if self.interrupt == 1 && bus.interrupt_requested(self) {
+            self.pending_interrupt = Some(Supplier::Bus);
+            self.quiet = false;
+            return false;
+        }
 
-            // XCHG
-            0xeb => {
-                //DH
-                self.registers.swap(2, 4);
+        true
+    }
 
-                // EL
-                self.registers.swap(3, 5);
+    /// Same as [`CPU::native_boundary`] when there may be more to do than to
+    /// poll the bus for INT.
+    #[inline(never)]
+    fn native_boundary_slow(&mut self, bus: &mut impl Bus) -> bool {
+        if self.hold
+            || self.dma > 0
+            || self.halt
+            || self.tracing
+            || self.pending_interrupt.is_some()
+        {
+            return false;
+        }
 
-                self.pc += 1;
-                5.0
-            }
+        self.hlda = false;
+        if !self.machine_cycles.is_empty() {
+            self.machine_cycles.clear();
+        }
 
-            // HLT
-            0x76 => {
-                self.pc += 1;
-                self.halt = true;
+        self.sample_interrupts(bus);
+        if self.pending_interrupt.is_some() {
+            return false;
+        }
 
-                7.0
-            }
+        self.settle();
+        true
+    }
 
-            // INX BC
-            0x03 => self.reg_cx(0, 1, |res| res.wrapping_add(1)),
-            // INX DE
-            0x13 => self.reg_cx(2, 3, |res| res.wrapping_add(1)),
-            // INX HL
-            0x23 => self.reg_cx(4, 5, |res| res.wrapping_add(1)),
-            // INX SP
-            0x33 => {
-                self.sp = self.sp.wrapping_add(1);
-                self.pc += 1;
-                5.0
-            }
+    /// Executes `instruction` as if it had been fetched at the PC and returns
+    /// the number of T-states it took, including wait states. The opcode and
+    /// operands are taken from `instruction` rather than read from memory.
+    ///
+    /// Interrupts are neither sampled nor serviced, unlike with
+    /// [`CPU::cycle`]. Fails without executing anything if `instruction` is
+    /// not valid, see [`Instruction::is_valid`].
+    pub fn execute(
+        &mut self,
+        bus: &mut impl Bus,
+        instruction: Instruction,
+    ) -> Result<u64, InvalidInstruction> {
+        let metadata = instruction.metadata()?;
+        let start = self.cycles;
+        self.execute_next(bus, instruction, metadata);
+
+        Ok(self.cycles - start)
+    }
 
-            // DCX BC
-            0x0b => self.reg_cx(0, 1, |res| res.wrapping_sub(1)),
-            // DCX DE
-            0x1b => self.reg_cx(2, 3, |res| res.wrapping_sub(1)),
-            // DCX HL
-            0x2b => self.reg_cx(4, 5, |res| res.wrapping_sub(1)),
-            // DCX SP
-            0x3b => {
-                self.sp = self.sp.wrapping_sub(1);
-                self.pc += 1;
-                5.0
-            }
+    /// Same as [`CPU::run`], kept out of line for all but the common path of
+    /// [`CPU::cycle`].
+    #[inline(never)]
+    fn execute_next(&mut self, bus: &mut impl Bus, instruction: Instruction, metadata: &Metadata) {
+        self.run(bus, instruction, metadata);
+    }
 
-            // RLC
-            0x07 => {
+    /// Executes `instruction`, read at the PC with the metadata of its
+    /// opcode, and moves the PC to the instruction after it.
+    #[inline(always)]
+    fn run(&mut self, bus: &mut impl Bus, instruction: Instruction, metadata: &Metadata) {
+        let pc = self.pc;
+        let next = pc.wrapping_add(metadata.len as u16);
+        let mut target = None;
+        let mut taken = true;
+        self.instructions += 1;
+
+        // Counted up front, so that wait states are added as they are
+        // inserted
+        self.cycles += metadata.t_states as u64;
+
+        match instruction {
+            Instruction::Nop => {}
+            Instruction::Lxi(pair, value) => self.set_register_pair(pair, value),
+            Instruction::Stax(pair) => {
+                let addr = self.register_pair(pair);
+                self.store(bus, addr, self.registers[6]);
+            }
+            Instruction::Ldax(pair) => {
+                let addr = self.register_pair(pair);
+                self.registers[6] = self.load(bus, addr);
+            }
+            Instruction::Shld(addr) => {
+                let hl = self.register_pair(RegisterPair::HL);
+                self.store_word(bus, CycleKind::MemoryWrite, addr, hl);
+            }
+            Instruction::Lhld(addr) => {
+                let hl = self.load_word(bus, CycleKind::MemoryRead, addr);
+                self.set_register_pair(RegisterPair::HL, hl);
+            }
+            Instruction::Sta(addr) => self.store(bus, addr, self.registers[6]),
+            Instruction::Lda(addr) => self.registers[6] = self.load(bus, addr),
+            Instruction::Inx(pair) => {
+                let value = self.register_pair(pair).wrapping_add(1);
+                self.set_register_pair(pair, value);
+            }
+            Instruction::Dcx(pair) => {
+                let value = self.register_pair(pair).wrapping_sub(1);
+                self.set_register_pair(pair, value);
+            }
+            Instruction::Dad(pair) => self.dad(self.register_pair(pair)),
+            Instruction::Inr(op) => {
+                let value = self.operand(bus, op);
+                let value = self.inr(value);
+                self.set_operand(bus, op, value);
+            }
+            Instruction::Dcr(op) => {
+                let value = self.operand(bus, op);
+                let value = self.dcr(value);
+                self.set_operand(bus, op, value);
+            }
+            Instruction::Mvi(op, data) => self.set_operand(bus, op, data),
+            Instruction::Rlc => {
                 let acc = self.registers[6];
 
-                self.flag = (self.flag & !1) | (acc >> 7);
+                self.set_carry(acc & 0x80 != 0);
                 self.registers[6] = acc.rotate_left(1);
-
-                self.pc += 1;
-                4.0
             }
-
-            // RRC
-            0x0f => {
+            Instruction::Rrc => {
                 let acc = self.registers[6];
 
-                self.flag = (self.flag & !1) | (acc & 1);
+                self.set_carry(acc & 1 != 0);
                 self.registers[6] = acc.rotate_right(1);
-
-                self.pc += 1;
-                4.0
             }
-
-            // RAL
-            0x17 => {
+            Instruction::Ral => {
                 let acc = self.registers[6];
-                let carry = self.flag & 1;
+                let carry = u8::from(self.flag(Flag::Carry));
 
-                self.flag = (self.flag & !1) | (acc >> 7);
+                self.set_carry(acc & 0x80 != 0);
                 self.registers[6] = (acc << 1) | carry;
-
-                self.pc += 1;
-                4.0
             }
-
-            // RAR
-            0x1f => {
+            Instruction::Rar => {
                 let acc = self.registers[6];
-                let carry = self.flag << 7;
+                let carry = u8::from(self.flag(Flag::Carry)) << 7;
 
-                self.flag = (self.flag & !1) | (acc & 1);
+                self.set_carry(acc & 1 != 0);
                 self.registers[6] = (acc >> 1) | carry;
-
-                self.pc += 1;
-                4.0
-            }
-
-            // CMA
-            0x2f => {
-                self.registers[6] = !self.registers[6];
-                self.pc += 1;
-                4.0
-            }
-
-            // CMC
-            0x3f => {
-                self.flag ^= 1;
-
-                self.pc += 1;
-                4.0
-            }
-
-            // STC
-            0x37 => {
-                self.flag |= 1;
-                self.pc += 1;
-                4.0
             }
-
-            // DAD BC
-            0x09 => self.dad(0, 1),
-            // DAD DE
-            0x19 => self.dad(2, 3),
-            // DAD HL
-            0x29 => self.dad(4, 5),
-            // DAD SP
-            0x39 => {
-                let sp = self.sp;
-
-                let h = self.registers[4] as u16;
-                let l = self.registers[5] as u16;
-                let hl = (h << 8) | l;
-
-                let carry = sp > 0xffff - hl;
-
-                let res = hl.wrapping_add(sp);
-
-                self.registers[4] = (res >> 8) as u8;
-                self.registers[5] = (res & 0x00ff) as u8;
-                self.flag &= !1;
-                self.flag |= u8::from(carry);
-
-                self.pc += 1;
-                10.0
+            Instruction::Daa => self.daa(),
+            Instruction::Cma => self.registers[6] = !self.registers[6],
+            Instruction::Stc => self.set_carry(true),
+            Instruction::Cmc => self.set_carry(!self.flag(Flag::Carry)),
+            Instruction::Mov(dst, src) => {
+                let value = self.operand(bus, src);
+                self.set_operand(bus, dst, value);
             }
-
-            // DAA
-            0x27 => {
-                let mut cy = (self.flag & 1) != 0;
-                let ac = (self.flag & 0b00010000) != 0;
-                let mut correction = 0;
-
-                let lsb = self.registers[6] & 0x0f;
-                let msb = self.registers[6] >> 4;
-
-                if ac || lsb > 9 {
-                    correction += 0x06;
+            Instruction::Hlt => {
+                self.halt = true;
+                self.quiet = false;
+                self.record(CycleKind::HaltAcknowledge, next, 0, 0);
+            }
+            Instruction::Add(op) => {
+                let value = self.operand(bus, op);
+                self.add(6, value, false);
+            }
+            Instruction::Adc(op) => {
+                let carry = self.flag(Flag::Carry);
+                let value = self.operand(bus, op);
+                self.add(6, value, carry);
+            }
+            Instruction::Sub(op) => {
+                let value = self.operand(bus, op);
+                self.sub(6, value, false);
+            }
+            Instruction::Sbb(op) => {
+                let carry = self.flag(Flag::Carry);
+                let value = self.operand(bus, op);
+                self.sub(6, value, carry);
+            }
+            Instruction::Ana(op) => {
+                let value = self.operand(bus, op);
+                self.ana(value);
+            }
+            Instruction::Xra(op) => {
+                let value = self.operand(bus, op);
+                self.xra(value);
+            }
+            Instruction::Ora(op) => {
+                let value = self.operand(bus, op);
+                self.ora(value);
+            }
+            Instruction::Cmp(op) => {
+                let value = self.operand(bus, op);
+                self.cmp(value);
+            }
+            Instruction::Adi(data) => self.add(6, data, false),
+            Instruction::Aci(data) => self.add(6, data, self.flag(Flag::Carry)),
+            Instruction::Sui(data) => self.sub(6, data, false),
+            Instruction::Sbi(data) => self.sub(6, data, self.flag(Flag::Carry)),
+            Instruction::Ani(data) => self.ana(data),
+            Instruction::Xri(data) => self.xra(data),
+            Instruction::Ori(data) => self.ora(data),
+            Instruction::Cpi(data) => self.cmp(data),
+            Instruction::Ret => target = Some(self.pop_word(bus)),
+            Instruction::Rcc(condition) => {
+                taken = self.condition(condition);
+                if taken {
+                    target = Some(self.pop_word(bus));
                 }
-
-                if cy || msb > 9 || (msb >= 9 && lsb > 9) {
-                    correction += 0x60;
-                    cy = true;
+            }
+            Instruction::Jmp(addr) => target = Some(addr),
+            Instruction::Jcc(condition, addr) => {
+                taken = self.condition(condition);
+                if taken {
+                    target = Some(addr);
                 }
-
-                self.add(6, correction, false);
-
-                self.flag &= !1;
-                self.flag |= u8::from(cy);
-
-                self.pc += 1;
-                4.0
             }
-
-            // POP BC
-            0xc1 => self.pop(0, 1),
-            // POP DE
-            0xd1 => self.pop(2, 3),
-            // POP HL
-            0xe1 => self.pop(4, 5),
-            // POP PSW
-            0xf1 => {
-                self.flag = (self.memory[(self.sp) as usize] & 0b11010101) | 0b0000_0010;
-                self.registers[6] = self.memory[(self.sp + 1) as usize];
-                self.sp += 2;
-
-                self.pc += 1;
-                10.0
+            Instruction::Call(addr) => {
+                self.push_word(bus, next);
+                target = Some(addr);
             }
-
-            // PUSH BC
-            0xc5 => self.push(0, 1),
-            // PUSH DE
-            0xd5 => self.push(2, 3),
-            // PUSH HL
-            0xe5 => self.push(4, 5),
-            // PUSH PSW
-            0xf5 => {
-                self.memory[(self.sp - 1) as usize] = self.registers[6];
-                self.memory[(self.sp - 2) as usize] = self.flag;
-                self.sp -= 2;
-
-                self.pc += 1;
-                11.0
+            Instruction::Ccc(condition, addr) => {
+                taken = self.condition(condition);
+                if taken {
+                    self.push_word(bus, next);
+                    target = Some(addr);
+                }
             }
-
-            // XTHL
-            0xe3 => {
-                std::mem::swap(&mut self.registers[5], &mut self.memory[self.sp as usize]);
-
-                std::mem::swap(
-                    &mut self.registers[4],
-                    &mut self.memory[(self.sp + 1) as usize],
+            Instruction::Rst(n) => {
+                self.push_word(bus, next);
+                target = Some((n as u16 & 0x07) * 8);
+            }
+            Instruction::Pop(pair) => {
+                let value = self.pop_word(bus);
+                self.set_register_pair(pair, value);
+            }
+            Instruction::Push(pair) => self.push_word(bus, self.register_pair(pair)),
+            Instruction::Out(port) => self.output(bus, port, self.registers[6]),
+            Instruction::In(port) => self.registers[6] = self.input(bus, port),
+            Instruction::Xthl => {
+                let sp = self.sp();
+                let top = self.load_word(bus, CycleKind::StackRead, sp);
+
+                self.store_word(
+                    bus,
+                    CycleKind::StackWrite,
+                    sp,
+                    self.register_pair(RegisterPair::HL),
                 );
-
-                self.pc += 1;
-                18.0
+                self.set_register_pair(RegisterPair::HL, top);
             }
+            Instruction::Pchl => target = Some(self.register_pair(RegisterPair::HL)),
+            Instruction::Xchg => {
+                //DH
+                self.registers.swap(2, 4);
 
-            // SPHL
-            0xf9 => {
-                let l = self.registers[5] as u16;
-                let h = self.registers[4] as u16;
-                let hl = (h << 8) | l;
-
-                self.sp = hl;
-                self.pc += 1;
-                5.0
-            }
-
-            // IN
-            0xdb => {
-                let port = self.memory[(self.pc + 1) as usize];
-                self.registers[6] = bus.read(self, port);
-
-                self.pc += 2;
-                10.0
-            }
-            // OUT
-            0xd3 => {
-                let port = self.memory[(self.pc + 1) as usize];
-
-                bus.write(self, port, self.registers[6]);
-
-                self.pc += 2;
-                10.0
+                // EL
+                self.registers.swap(3, 5);
             }
-
-            // EI
-            0xfb => {
+            Instruction::Di => self.interrupt = 0,
+            Instruction::Sphl => self.set_sp(self.register_pair(RegisterPair::HL)),
+            Instruction::Ei => {
                 self.interrupt = 2;
-                self.pc += 1;
-                4.0
-            }
-            // DI
-            0xf3 => {
-                self.interrupt = 0;
-                self.pc += 1;
-                4.0
-            }
-
-            // ADI
-            0xc6 => {
-                let imm = self.memory[(self.pc + 1) as usize];
-                self.add(6, imm, false);
-
-                self.pc += 2;
-                7.0
-            }
-
-            // SUI
-            0xd6 => {
-                let imm = self.memory[(self.pc + 1) as usize];
-
-                self.sub(6, imm, false);
-
-                self.pc += 2;
-                7.0
-            }
-
-            // ANI
-            0xe6 => {
-                let imm = self.memory[(self.pc + 1) as usize];
-                self.ana(imm);
-
-                self.pc += 2;
-                7.0
+                self.quiet = false;
             }
+        }
 
-            // ORI
-            0xf6 => {
-                let imm = self.memory[(self.pc + 1) as usize];
-
-                self.ora(imm);
-
-                self.pc += 2;
-                7.0
-            }
-
-            // ACI
-            0xce => {
-                let imm = self.memory[(self.pc + 1) as usize];
-                let carry = (self.flag & 0x01) != 0;
-
-                self.add(6, imm, carry);
-
-                self.pc += 2;
-                7.0
-            }
-
-            // SBI
-            0xde => {
-                let imm = self.memory[(self.pc + 1) as usize];
-                let carry = self.flag & 0x01 != 0;
-                self.sub(6, imm, carry);
-
-                self.pc += 2;
-                7.0
-            }
-
-            // XRI
-            0xee => {
-                let imm = self.memory[(self.pc + 1) as usize];
-                self.xra(imm);
-
-                self.pc += 2;
-                7.0
-            }
-
-            // CPI
-            0xfe => {
-                let imm = self.memory[(self.pc + 1) as usize];
-                self.cmp(imm);
-
-                self.pc += 2;
-                7.0
-            }
-
-            // RST 0
-            0xc7 => {
-                self.push_pc(1, 0);
-
-                11.0
-            }
-            // RST 1
-            0xcf => {
-                self.push_pc(1, 8);
-
-                11.0
-            }
-            // RST 2
-            0xd7 => {
-                self.push_pc(1, 2 * 8);
-
-                11.0
-            }
-            // RST 3
-            0xdf => {
-                self.push_pc(1, 3 * 8);
-
-                11.0
-            }
-            // RST 4
-            0xe7 => {
-                self.push_pc(1, 4 * 8);
-
-                11.0
-            }
-            // RST 5
-            0xef => {
-                self.push_pc(1, 5 * 8);
-
-                11.0
-            }
-            // RST 6
-            0xf7 => {
-                self.push_pc(1, 6 * 8);
-
-                11.0
-            }
-            // RST 7
-            0xff => {
-                self.push_pc(1, 7 * 8);
-
-                11.0
-            }
-
-            // MOV B,X
-            0x40..=0x47 => {
-                let cmp = opcode & 0x0f;
-
-                let src = reg07(cmp);
-
-                self.registers[0] = src;
-                self.pc += 1;
-
-                if cmp == 0x06 { 7.0 } else { 5.0 }
-            }
-            // MOV C,X
-            0x48..=0x4f => {
-                let cmp = opcode & 0x0f;
-
-                let src = reg8f(cmp);
-
-                self.registers[1] = src;
-                self.pc += 1;
-
-                if cmp == 0x0e { 7.0 } else { 5.0 }
-            }
-            // MOV D,X
-            0x50..=0x57 => {
-                let cmp = opcode & 0x0f;
+        // The length follows from the instruction rather than the metadata,
+        // so that moving on does not wait for the opcode to be read
+        self.pc = target.unwrap_or(pc.wrapping_add(instruction.len() as u16));
 
-                let src = reg07(cmp);
+        if !taken {
+            self.cycles -= (metadata.t_states - metadata.t_states_not_taken) as u64;
+        }
+    }
 
-                self.registers[2] = src;
-                self.pc += 1;
+    /// Attempts to supply an interrupt to the cpu. Returns true if successful.
+    ///
+    /// `instruction` is executed in place of the next instruction, with its
+    /// bytes read through interrupt acknowledge cycles rather than memory. It
+    /// is usually an RST or a CALL, which save the PC of the interrupted
+    /// instruction.
+    ///
+    /// Fails if interrupts are not enabled for the cpu.
+    /// Fails if `instruction` is not a single whole instruction.
+    pub fn interrupt(&mut self, instruction: &[u8]) -> bool {
+        if self.interrupt != 1 {
+            return false;
+        }
 
-                if cmp == 0x06 { 7.0 } else { 5.0 }
+        match supplied(instruction) {
+            Some(instruction) => {
+                self.pending_interrupt = Some(Supplier::Cpu(instruction));
+                self.quiet = false;
+                true
             }
-            // MOV E,X
-            0x58..=0x5f => {
-                let cmp = opcode & 0x0f;
-
-                let src = reg8f(cmp);
-
-                self.registers[3] = src;
-                self.pc += 1;
+            None => false,
+        }
+    }
 
-                if cmp == 0x0e { 7.0 } else { 5.0 }
+    /// Holds the INT input active, supplying `instruction` once the interrupt
+    /// is acknowledged. Returns true if successful.
+    ///
+    /// Unlike [`CPU::interrupt`], the request stays latched while interrupts
+    /// are disabled and is serviced at the first instruction boundary after
+    /// they are enabled. A halted [`CPU`] leaves HLT to service it. The input
+    /// goes inactive once the interrupt is acknowledged.
+    ///
+    /// Fails if `instruction` is not a single whole instruction.
+    pub fn assert_interrupt(&mut self, instruction: &[u8]) -> bool {
+        match supplied(instruction) {
+            Some(instruction) => {
+                self.int = Some(instruction);
+                self.quiet = false;
+                true
             }
-            // MOV H,X
-            0x60..=0x67 => {
-                let cmp = opcode & 0x0f;
-
-                let src = reg07(cmp);
+            None => false,
+        }
+    }
 
-                self.registers[4] = src;
-                self.pc += 1;
+    /// Releases the INT input, withdrawing an unacknowledged request.
+    pub fn deassert_interrupt(&mut self) {
+        self.int = None;
+    }
 
-                if cmp == 0x06 { 7.0 } else { 5.0 }
-            }
-            // MOV L,X
-            0x68..=0x6f => {
-                let cmp = opcode & 0x0f;
+    /// Returns true if the INT input is active.
+    pub fn interrupt_requested(&self) -> bool {
+        self.int.is_some()
+    }
 
-                let src = reg8f(cmp);
+    /// Returns the number of T-states elapsed since power on.
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
 
-                self.registers[5] = src;
-                self.pc += 1;
+    /// Returns the clock speed of the [`CPU`] in Hz, which is [`RATE`] unless
+    /// set through [`CPUBuilder::rate`].
+    pub fn rate(&self) -> u32 {
+        self.rate
+    }
 
-                if cmp == 0x0e { 7.0 } else { 5.0 }
-            }
-            // MOV M,X
-            0x70..=0x77 => {
-                let cmp = opcode & 0x0f;
+    /// Returns the number of instructions executed since power on. Serviced
+    /// interrupts count as instructions.
+    pub fn instructions(&self) -> u64 {
+        self.instructions
+    }
 
-                let src = reg07(cmp);
+    /// Returns true if the [`CPU`] has been halted.
+    pub fn halted(&self) -> bool {
+        self.halt
+    }
 
-                self.memory[maddr] = src;
-                self.pc += 1;
+    /// Sets the level of the HOLD input.
+    ///
+    /// While HOLD is active the [`CPU`] releases the bus after the current
+    /// instruction and stalls until HOLD goes inactive, leaving memory to a DMA
+    /// controller. See [`CPU::hlda`].
+    pub fn set_hold(&mut self, hold: bool) {
+        self.hold = hold;
+        self.quiet = false;
+    }
 
-                7.0
-            }
-            // MOV A,X
-            0x78..=0x7f => {
-                let cmp = opcode & 0x0f;
+    /// Returns true if the [`CPU`] has released the bus in response to HOLD,
+    /// after which memory can be accessed through [`CPU::memory_mut`].
+    pub fn hlda(&self) -> bool {
+        self.hlda
+    }
 
-                let src = reg8f(cmp);
+    /// Hands the bus to a DMA controller for `t_states` T-states, during which
+    /// `transfer` reads and writes memory directly.
+    ///
+    /// This is a shorthand for holding HOLD active over the next `t_states`
+    /// calls to [`CPU::cycle`] and releasing it afterwards. Each of them idles
+    /// for 1 T-state with HLDA active, after the machine cycles left of the
+    /// current instruction for [`CPU::step`]. Instructions take effect as a
+    /// whole, so `transfer` runs straight away on memory as it stands at the
+    /// end of the current instruction.
+    pub fn dma<R>(&mut self, t_states: u64, transfer: impl FnOnce(&mut Memory) -> R) -> R {
+        self.dma = self.dma.saturating_add(t_states);
+        self.quiet = false;
+        transfer(&mut self.memory)
+    }
 
-                self.registers[6] = src;
-                self.pc += 1;
+    /// Advances the [`CPU`] by a single machine cycle and returns it.
+    ///
+    /// An instruction takes effect as a whole on its first machine cycle, with
+    /// the rest of its machine cycles returned by the following calls. A
+    /// halted [`CPU`] returns a halt acknowledge lasting 1 T-state without
+    /// SYNC, while one holding the bus returns a hold of 1 T-state. Calling
+    /// [`CPU::cycle`] in between discards the machine cycles left of the
+    /// current instruction.
+    pub fn step(&mut self, bus: &mut impl Bus) -> MachineCycle {
+        if let Some(cycle) = self.machine_cycles.pop_front() {
+            return cycle;
+        }
 
-                if cmp == 0x0e { 7.0 } else { 5.0 }
+        self.tracing = true;
+        self.quiet = false;
+        let duration = self.cycle(bus);
+
+        let recorded = self
+            .machine_cycles
+            .iter()
+            .map(|cycle| cycle.t_states as u64)
+            .sum::<u64>();
+
+        // M1 takes 4 T-states and other machine cycles 3. Instructions which
+        // take longer do so in M1 if they take 1 more, in the final write if
+        // they take 2 more (XTHL) or in bus idle machine cycles (DAD).
+        match duration.saturating_sub(recorded) {
+            0 => {}
+            1 => {
+                if let Some(cycle) = self.machine_cycles.front_mut() {
+                    cycle.t_states = cycle.t_states.saturating_add(1);
+                }
             }
-
-            // ADD X
-            0x80..=0x87 => {
-                let cmp = opcode & 0x0f;
-
-                let reg = reg07(cmp);
-                self.add(6, reg, false);
-
-                self.pc += 1;
-                if cmp == 0x06 { 7.0 } else { 4.0 }
+            2 => {
+                if let Some(cycle) = self.machine_cycles.back_mut() {
+                    cycle.t_states = cycle.t_states.saturating_add(2);
+                }
             }
-
-            // ADC X
-            0x88..=0x8f => {
-                let cmp = opcode & 0x0f;
-
-                let carry = (self.flag & 0x01) != 0;
-                let reg = reg8f(cmp);
-                self.add(6, reg, carry);
-
-                self.pc += 1;
-                if cmp == 0x0e { 7.0 } else { 4.0 }
+            extra => {
+                for _ in 0..extra / 3 {
+                    self.record(CycleKind::BusIdle, self.pc, 0, 0);
+                }
             }
+        }
 
-            // SUB X
-            0x90..=0x97 => {
-                let cmp = opcode & 0x0f;
-
-                let reg = reg07(cmp);
-                self.sub(6, reg, false);
+        self.tracing = false;
+
+        // Idling while halted or holding the bus takes 1 T-state
+        let t_states = u8::try_from(duration).unwrap_or(u8::MAX);
+        match self.machine_cycles.pop_front() {
+            Some(cycle) => cycle,
+            None if self.hlda => MachineCycle {
+                kind: CycleKind::Hold,
+                address: self.pc,
+                data: 0,
+                t_states,
+                pins: Pins {
+                    inte: self.interrupt != 0,
+                    hlda: true,
+                    wait: self.halt,
+                    ..Pins::default()
+                },
+            },
+            None => MachineCycle {
+                kind: CycleKind::HaltAcknowledge,
+                address: self.pc,
+                data: 0,
+                t_states,
+                pins: Pins {
+                    inte: self.interrupt != 0,
+                    wait: true,
+                    ..Pins::default()
+                },
+            },
+        }
+    }
 
-                self.pc += 1;
-                if cmp == 0x06 { 7.0 } else { 4.0 }
-            }
+    /// Returns the wait states to insert into a machine cycle of `kind` at
+    /// `addr`, adding them to the T-states elapsed.
+    #[inline(always)]
+    fn wait(&mut self, bus: &mut impl Bus, kind: CycleKind, addr: u16) -> u8 {
+        let memory = match kind {
+            _ if self.quiet => 0,
+            CycleKind::Input
+            | CycleKind::Output
+            | CycleKind::InterruptAcknowledge
+            | CycleKind::InterruptAcknowledgeHalt => 0,
+            _ => self.memory.wait_states(addr),
+        };
 
-            // SBB X
-            0x98..=0x9f => {
-                let cmp = opcode & 0x0f;
-                let carry = (self.flag & 0x01) != 0;
+        let wait = memory.saturating_add(bus.wait_states(self, kind, addr));
+        if wait > 0 {
+            self.cycles += wait as u64;
+        }
 
-                let reg = reg8f(cmp);
-                self.sub(6, reg, carry);
+        wait
+    }
 
-                self.pc += 1;
-                if cmp == 0x0e { 7.0 } else { 4.0 }
-            }
+    /// Records a machine cycle with `wait` wait states for [`CPU::step`].
+    #[inline(always)]
+    fn record(&mut self, kind: CycleKind, address: u16, data: u8, wait: u8) {
+        if !self.quiet && self.tracing {
+            self.trace(kind, address, data, wait);
+        }
+    }
 
-            // ANA X
-            0xa0..=0xa7 => {
-                let cmp = opcode & 0x0f;
+    /// Records a machine cycle while [`CPU::step`] is tracing.
+    #[cold]
+    fn trace(&mut self, kind: CycleKind, address: u16, data: u8, wait: u8) {
+        // Only M1 lasts 4 T-states, which rules out the operands supplied by
+        // an interrupting device
+        let t_states: u8 = match kind {
+            CycleKind::Fetch | CycleKind::InterruptAcknowledgeHalt => 4,
+            CycleKind::InterruptAcknowledge if self.machine_cycles.is_empty() => 4,
+            _ => 3,
+        };
 
-                let reg = reg07(cmp);
-                self.ana(reg);
+        self.machine_cycles.push_back(MachineCycle {
+            kind,
+            address,
+            data,
+            t_states: t_states.saturating_add(wait),
+            pins: Pins {
+                sync: true,
+                dbin: kind.is_read(),
+                wr: kind.is_write(),
+                inte: self.interrupt != 0,
+                hlda: false,
+                wait: wait > 0,
+            },
+        });
+    }
 
-                self.pc += 1;
-                if cmp == 0x06 { 7.0 } else { 4.0 }
-            }
+    /// Fetches an opcode from `addr`, giving `bus` the chance to intercept it.
+    #[inline(always)]
+    fn fetch(&mut self, bus: &mut impl Bus, addr: u16) -> u8 {
+        let wait = self.wait(bus, CycleKind::Fetch, addr);
+        let data = match bus.fetch(self, addr) {
+            Some(data) => data,
+            None => self.memory.read(addr),
+        };
 
-            // XRA X
-            0xa8..=0xaf => {
-                let cmp = opcode & 0x0f;
+        self.record(CycleKind::Fetch, addr, data, wait);
+        data
+    }
 
-                let reg = reg8f(cmp);
-                self.xra(reg);
+    /// Reads a byte from `addr`, giving `bus` the chance to intercept it.
+    #[inline(always)]
+    fn load(&mut self, bus: &mut impl Bus, addr: u16) -> u8 {
+        let data = self.memory.read(addr);
+        self.read_cycle(bus, CycleKind::MemoryRead, addr, data)
+    }
 
-                self.pc += 1;
-                if cmp == 0x0e { 7.0 } else { 4.0 }
-            }
+    /// Reads the little-endian word at `addr` through two machine cycles of
+    /// `kind`, like two calls to [`CPU::load`].
+    #[inline(always)]
+    fn load_word(&mut self, bus: &mut impl Bus, kind: CycleKind, addr: u16) -> u16 {
+        let word = self.memory.read_word(addr);
+        self.read_word_cycles(bus, kind, addr, word)
+    }
 
-            // ORA X
-            0xb0..=0xb7 => {
-                let cmp = opcode & 0x0f;
+    /// Runs the two read machine cycles of `kind` reading the little-endian
+    /// word at `addr`, which holds `word` in memory.
+    #[inline(always)]
+    fn read_word_cycles(
+        &mut self,
+        bus: &mut impl Bus,
+        kind: CycleKind,
+        addr: u16,
+        word: u16,
+    ) -> u16 {
+        let [low, high] = word.to_le_bytes();
+        let low = self.read_cycle(bus, kind, addr, low);
+        let high = self.read_cycle(bus, kind, addr.wrapping_add(1), high);
+
+        u16::from_le_bytes([low, high])
+    }
 
-                let reg = reg07(cmp);
-                self.ora(reg);
+    /// Runs a read machine cycle of `kind` at `addr`, which holds `data` in
+    /// memory, giving `bus` the chance to intercept it.
+    #[inline(always)]
+    fn read_cycle(&mut self, bus: &mut impl Bus, kind: CycleKind, addr: u16, data: u8) -> u8 {
+        let wait = self.wait(bus, kind, addr);
+        let data = bus.load(self, addr).unwrap_or(data);
 
-                self.pc += 1;
-                if cmp == 0x06 { 7.0 } else { 4.0 }
-            }
+        self.record(kind, addr, data, wait);
+        data
+    }
 
-            // CMP X
-            0xb8..=0xbf => {
-                let cmp = opcode & 0x0f;
+    /// Writes `data` to `addr` unless `bus` handles the write itself.
+    #[inline(always)]
+    fn store(&mut self, bus: &mut impl Bus, addr: u16, data: u8) {
+        if !self.write_cycle(bus, CycleKind::MemoryWrite, addr, data) {
+            self.memory.write(addr, data);
+        }
+    }
 
-                let reg = reg8f(cmp);
+    /// Runs a write machine cycle of `kind` writing `data` to `addr`. Returns
+    /// true if `bus` handled the write itself, else leaves memory to the
+    /// caller.
+    #[inline(always)]
+    fn write_cycle(&mut self, bus: &mut impl Bus, kind: CycleKind, addr: u16, data: u8) -> bool {
+        let wait = self.wait(bus, kind, addr);
+        self.record(kind, addr, data, wait);
 
-                self.cmp(reg);
+        bus.store(self, addr, data)
+    }
 
-                self.pc += 1;
-                if cmp == 0x0e { 7.0 } else { 4.0 }
-            }
+    /// Writes the little-endian word `value` to `addr` through two machine
+    /// cycles of `kind`, like two calls to [`CPU::store`]. The high byte goes
+    /// first when pushed, as on the 8080, and the low byte first otherwise.
+    /// Memory is only written once both cycles have run.
+    #[inline(always)]
+    fn store_word(&mut self, bus: &mut impl Bus, kind: CycleKind, addr: u16, value: u16) {
+        let [low, high] = value.to_le_bytes();
+        let high_addr = addr.wrapping_add(1);
+        let (high_stored, low_stored) = if kind == CycleKind::StackWrite {
+            let high_stored = self.write_cycle(bus, kind, high_addr, high);
+            (high_stored, self.write_cycle(bus, kind, addr, low))
+        } else {
+            let low_stored = self.write_cycle(bus, kind, addr, low);
+            (self.write_cycle(bus, kind, high_addr, high), low_stored)
         };
 
-        //self.end_cycle = Duration::from_secs_f32(duration * PERIOD);
-        //self.now = Instant::now();
-
-        duration as u8
-    }
-
-    /// Attempts to supply an interrupt to the cpu. Returns true if successful.
-    ///
-    /// Fails if interrupts are not enabled for the cpu.
-    /// Fails if `rst` is not a valid RST opcdoe.
-    pub fn interrupt(&mut self, rst: u8) -> bool {
-        if self.interrupt != 1 {
-            return false;
+        match (low_stored, high_stored) {
+            (false, false) => self.memory.write_word(addr, value),
+            (false, true) => self.memory.write(addr, low),
+            (true, false) => self.memory.write(high_addr, high),
+            (true, true) => {}
         }
+    }
 
-        match rst {
-            0xc7 | 0xcf | 0xd7 | 0xdf | 0xe7 | 0xef | 0xf7 | 0xff => {
-                self.pending_interrupt = Some(rst);
-                true
-            }
-            _ => false,
+    /// Returns the value of register or memory operand `op`.
+    #[inline(always)]
+    fn operand(&mut self, bus: &mut impl Bus, op: Operand) -> u8 {
+        match op {
+            Operand::Register(reg) => self.register(reg),
+            Operand::Memory => self.load(bus, self.register_pair(RegisterPair::HL)),
         }
     }
 
-    /// Returns the content of the specified register.
-    ///
-    /// Registers are in zero-indexed order B,C,D,E,H,L,A.
-    pub fn register(&self, reg: u8) -> u8 {
-        self.registers[reg as usize]
+    /// Sets register or memory operand `op` to `value`.
+    #[inline(always)]
+    fn set_operand(&mut self, bus: &mut impl Bus, op: Operand, value: u8) {
+        match op {
+            Operand::Register(reg) => self.set_register(reg, value),
+            Operand::Memory => self.store(bus, self.register_pair(RegisterPair::HL), value),
+        }
     }
 
-    /// Returns true if the [`CPU`] has been halted.
-    pub fn halted(&self) -> bool {
-        self.halt
+    /// Returns true if `condition` holds.
+    #[inline(always)]
+    fn condition(&self, condition: Condition) -> bool {
+        let (flag, set) = condition.flag();
+        self.flag(flag) == set
     }
 
     /// Adds a value plus an optional carry flag to a register.
     fn add(&mut self, reg: usize, val: u8, cy: bool) {
-        let reg_value = self.registers[reg];
-        let result = reg_value.wrapping_add(val).wrapping_add(cy as u8);
-        let p = (result.count_ones() % 2) == 0;
-
-        self.flag = 0b00000010;
-        self.flag |= result & 0x80;
-        self.flag |= u8::from(result == 0) << 6;
-        self.flag |= u8::from(carry(4, reg_value as u16, val as u16, cy)) << 4;
-        self.flag |= u8::from(p) << 2;
-        self.flag |= u8::from(carry(8, reg_value as u16, val as u16, cy));
-
-        self.registers[reg] = result;
+        self.alu = Alu::add(self.registers[reg], val, cy);
+        self.registers[reg] = self.alu.result;
     }
 
     fn sub(&mut self, reg: usize, val: u8, cy: bool) {
-        self.add(reg, !val, !cy);
-        self.flag ^= 1;
+        self.alu = Alu::sub(self.registers[reg], val, cy);
+        self.registers[reg] = self.alu.result;
     }
 
     /// XOR with register A
     fn xra(&mut self, val: u8) {
         self.registers[6] ^= val;
-        let acc = self.registers[6];
-        let p = (acc.count_ones() % 2) == 0;
-
-        self.flag = 0b00000010;
-        self.flag |= acc & 0x80;
-        self.flag |= u8::from(acc == 0) << 6;
-        self.flag |= u8::from(p) << 2;
+        self.alu = Alu::or(self.registers[6]);
     }
 
     /// OR with register A
     fn ora(&mut self, val: u8) {
         self.registers[6] |= val;
-
-        let acc = self.registers[6];
-        let p = (acc.count_ones() % 2) == 0;
-
-        self.flag = 0b00000010;
-        self.flag |= acc & 0x80;
-        self.flag |= u8::from(acc == 0) << 6;
-        self.flag |= u8::from(p) << 2;
+        self.alu = Alu::or(self.registers[6]);
     }
 
     /// Logical and with register A.
     fn ana(&mut self, val: u8) {
-        let reg_value = self.registers[6];
-
-        let result = reg_value & val;
-        self.registers[6] = result;
-
-        let p = (result.count_ones() % 2) == 0;
-
-        self.flag = 0b00000010;
-        self.flag |= result & 0x80;
-        self.flag |= u8::from(result == 0) << 6;
-        self.flag |= u8::from((reg_value | val) & 0x08 != 0) << 4;
-        self.flag |= u8::from(p) << 2;
+        self.alu = Alu::and(self.registers[6], val);
+        self.registers[6] = self.alu.result;
     }
 
+    /// Compares with register A, which sets the flags as subtracting `val`
+    /// would.
     fn cmp(&mut self, val: u8) {
-        let val = val as u16;
-        let acc = self.registers[6] as u16;
-        self.flag = 0b00000010;
-
-        let result = acc.wrapping_sub(val);
-        let ac = !(acc ^ result ^ val) & 0x10;
-        self.flag |= u8::from((result >> 8) != 0);
-
-        let result = (result & 0xff) as u8;
-        let p = (result.count_ones() % 2) == 0;
-
-        self.flag |= result & 0x80;
-        self.flag |= u8::from(result == 0) << 6;
-        self.flag |= u8::from(ac != 0) << 4;
-        self.flag |= u8::from(p) << 2;
+        self.alu = Alu::sub(self.registers[6], val, false);
     }
 
-    fn ret(&mut self, condition: bool) -> f32 {
-        if condition {
-            self.pop_pc();
-
-            11.0
-        } else {
-            self.pc += 1;
-            5.0
-        }
+    /// Returns `val + 1`, setting the flags as INR does.
+    #[inline(always)]
+    fn inr(&mut self, val: u8) -> u8 {
+        self.alu = Alu::inr(val, self.flag(Flag::Carry));
+        self.alu.result
     }
 
-    fn pop_pc(&mut self) {
-        let low = self.memory[(self.sp) as usize] as u16;
-        let hi = self.memory[(self.sp + 1) as usize] as u16;
-
-        self.pc = (hi << 8) | low;
-        self.sp += 2;
+    /// Returns `val - 1`, setting the flags as DCR does.
+    #[inline(always)]
+    fn dcr(&mut self, val: u8) -> u8 {
+        self.alu = Alu::dcr(val, self.flag(Flag::Carry));
+        self.alu.result
     }
 
-    fn push_pc(&mut self, next_pc: u16, new_pc: u16) {
-        let ret = self.pc + next_pc;
-
-        self.memory[(self.sp - 1) as usize] = (ret >> 8) as u8;
-        self.memory[(self.sp - 2) as usize] = (ret & 0x00ff) as u8;
-
-        self.sp -= 2;
-        self.pc = new_pc;
+    /// Sets CY, leaving the other flags as they are, pending or not.
+    #[inline(always)]
+    fn set_carry(&mut self, carry: bool) {
+        self.flag = (self.flag & !1) | u8::from(carry);
+        self.alu.set_carry(carry);
     }
 
-    fn call(&mut self, condition: bool) -> f32 {
-        if condition {
-            let hi = self.memory[(self.pc + 2) as usize] as u16;
-            let low = self.memory[(self.pc + 1) as usize] as u16;
-            let addr = (hi << 8) | low;
-
-            self.push_pc(3, addr);
-
-            17.0
-        } else {
-            self.pc += 3;
-
-            11.0
-        }
+    /// Returns the flag register, working out the flags of the last
+    /// arithmetic or logic operation.
+    fn flag_bits(&self) -> u8 {
+        self.alu.bits(self.flag)
     }
 
-    fn jump(&mut self, condition: bool) -> f32 {
-        if condition {
-            let hi = self.memory[(self.pc + 2) as usize] as u16;
-            let low = self.memory[(self.pc + 1) as usize] as u16;
-            let addr = (hi << 8) | low;
-
-            self.pc = addr;
-        } else {
-            self.pc += 3;
-        }
-
-        10.0
+    /// Works the flags of the last arithmetic or logic operation out into the
+    /// flag register, ahead of changing some of them.
+    fn settle_flags(&mut self) {
+        self.flag = self.flag_bits();
+        self.alu = Alu::default();
     }
 
-    fn incr(&mut self, register: usize) -> f32 {
-        let reg = self.registers[register];
-        let res = reg.wrapping_add(1);
-        let ac = ((reg & 0x0F) + (1 & 0x0F)) & 0x10 != 0;
-
-        self.registers[register] = res;
-
-        let z = res == 0;
-        let p = (res.count_ones() % 2) == 0;
+    /// Adjusts register A to two binary-coded decimal digits after an
+    /// addition.
+    fn daa(&mut self) {
+        self.settle_flags();
 
-        // Affected flags get reset to 0
-        self.flag &= 0b00000011;
+        let mut cy = (self.flag & 1) != 0;
+        let ac = (self.flag & 0b00010000) != 0;
+        let mut correction = 0;
 
-        self.flag |= res & 0x80;
-        self.flag |= u8::from(z) << 6;
-        self.flag |= u8::from(ac) << 4;
-        self.flag |= u8::from(p) << 2;
-
-        self.pc += 1;
-        5.0
-    }
+        let lsb = self.registers[6] & 0x0f;
+        let msb = self.registers[6] >> 4;
 
-    fn dcr(&mut self, register: usize) -> f32 {
-        let reg = self.registers[register];
-        let res = reg.wrapping_sub(1);
-        let ac = calc_ac(reg, 1);
-        let p = (res.count_ones() % 2) == 0;
-
-        self.registers[register] = res;
-
-        self.flag &= 0b00000011;
-
-        self.flag |= res & 0x80;
-        self.flag |= u8::from(res == 0) << 6;
-        self.flag |= u8::from(ac) << 4;
-        self.flag |= u8::from(p) << 2;
-
-        self.pc += 1;
-        5.0
-    }
+        if ac || lsb > 9 {
+            correction += 0x06;
+        }
 
-    fn lxi(&mut self, h: usize, l: usize) -> f32 {
-        let low = self.memory[(self.pc + 1) as usize];
-        let hi = self.memory[(self.pc + 2) as usize];
+        if cy || msb > 9 || (msb >= 9 && lsb > 9) {
+            correction += 0x60;
+            cy = true;
+        }
 
-        self.registers[h] = hi;
-        self.registers[l] = low;
+        self.add(6, correction, false);
+        self.settle_flags();
 
-        self.pc += 3;
-        10.0
+        self.flag &= !1;
+        self.flag |= u8::from(cy);
     }
 
-    fn mvi(&mut self, register: usize) -> f32 {
-        self.registers[register] = self.memory[(self.pc + 1) as usize];
-        self.pc += 2;
+    /// Runs an output machine cycle writing `data` to `port`, switching banks
+    /// if `port` selects them.
+    fn output(&mut self, bus: &mut impl Bus, port: u8, data: u8) {
+        let addr = u16::from_be_bytes([port, port]);
+        let wait = self.wait(bus, CycleKind::Output, addr);
 
-        7.0
+        self.record(CycleKind::Output, addr, data, wait);
+        self.memory.select_bank(port, data);
+        bus.write(self, port, data);
     }
 
-    fn reg_cx(&mut self, h: usize, l: usize, op: fn(u16) -> u16) -> f32 {
-        let hi = self.registers[h] as u16;
-        let low = self.registers[l] as u16;
-        let r = (hi << 8) | low;
-
-        let res = op(r);
+    /// Runs an input machine cycle and returns the byte read from `port`.
+    fn input(&mut self, bus: &mut impl Bus, port: u8) -> u8 {
+        let addr = u16::from_be_bytes([port, port]);
+        let wait = self.wait(bus, CycleKind::Input, addr);
+        let data = bus.read(self, port);
 
-        self.registers[h] = (res >> 8) as u8;
-        self.registers[l] = (res & 0x00ff) as u8;
-
-        self.pc += 1;
-        5.0
+        self.record(CycleKind::Input, addr, data, wait);
+        data
     }
 
-    fn dad(&mut self, high: usize, low: usize) -> f32 {
-        let high = self.registers[high] as u16;
-        let low = self.registers[low] as u16;
-        let one = (high << 8) | low;
-
-        let h = self.registers[4] as u16;
-        let l = self.registers[5] as u16;
-        let hl = (h << 8) | l;
+    #[inline(always)]
+    fn pop_word(&mut self, bus: &mut impl Bus) -> u16 {
+        let word = self.load_word(bus, CycleKind::StackRead, self.sp());
+        self.set_sp(self.sp().wrapping_add(2));
 
-        let carry = one > 0xffff - hl;
-
-        let res = hl.wrapping_add(one);
-
-        self.registers[4] = (res >> 8) as u8;
-        self.registers[5] = (res & 0x00ff) as u8;
-        self.flag &= !1;
-        self.flag |= u8::from(carry);
-
-        self.pc += 1;
-        10.0
+        word
     }
 
-    fn push(&mut self, h: usize, l: usize) -> f32 {
-        let h = self.registers[h];
-        let l = self.registers[l];
-
-        self.memory[(self.sp - 1) as usize] = h;
-        self.memory[(self.sp - 2) as usize] = l;
-        self.sp -= 2;
-
-        self.pc += 1;
-        11.0
+    #[inline(always)]
+    fn push_word(&mut self, bus: &mut impl Bus, value: u16) {
+        self.set_sp(self.sp().wrapping_sub(2));
+        self.store_word(bus, CycleKind::StackWrite, self.sp(), value);
     }
 
-    fn pop(&mut self, h: usize, l: usize) -> f32 {
-        self.registers[l] = self.memory[(self.sp) as usize];
-        self.registers[h] = self.memory[(self.sp + 1) as usize];
-        self.sp += 2;
+    /// Adds `value` to HL, setting the carry flag on overflow.
+    fn dad(&mut self, value: u16) {
+        let hl = self.register_pair(RegisterPair::HL);
+        let carry = value > 0xffff - hl;
 
-        self.pc += 1;
-        10.0
+        self.set_register_pair(RegisterPair::HL, hl.wrapping_add(value));
+        self.set_carry(carry);
     }
 
     pub fn debug(&self) {
         println!(
             "\nPC: {}, SP: {}, Halt: {}, Interrupt: {:08b}",
-            self.pc, self.sp, self.halt, self.interrupt
+            self.pc,
+            self.sp(),
+            self.halt,
+            self.interrupt
         );
 
         println!(
@@ -1313,25 +1269,59 @@ impl CPU {
 
         println!(
             "Flags: S: {}, Z: {}, A: {}, P: {}, C: {}",
-            self.flag >> 7,
-            (self.flag >> 6) & 1,
-            (self.flag >> 4) & 1,
-            (self.flag >> 2) & 1,
-            self.flag & 1,
+            self.flag_bits() >> 7,
+            (self.flag_bits() >> 6) & 1,
+            (self.flag_bits() >> 4) & 1,
+            (self.flag_bits() >> 2) & 1,
+            self.flag_bits() & 1,
         );
     }
 }
 
-/// returns if there was a carry between bit "bit_no" and "bit_no - 1" when
-/// executing "a + b + cy"
-fn carry(bit_no: u8, a: u16, b: u16, carry: bool) -> bool {
-    let result = a.wrapping_add(b).wrapping_add(carry as u16);
-    let carry = result ^ a ^ b;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dma_holds_the_bus_after_the_current_instruction() {
+        // MVI A,0x2a; STA 0x0100
+        let mut cpu = CPU::new(&[0x3e, 0x2a, 0x32, 0x00, 0x01]);
+        cpu.cycle(&mut ());
+
+        // STA has 4 machine cycles, the first returned on its own
+        assert_eq!(cpu.step(&mut ()).kind, CycleKind::Fetch);
+        let read = cpu.dma(3, |memory| memory.read(0x0100));
+        assert_eq!(read, 0x2a);
+
+        for kind in [
+            CycleKind::MemoryRead,
+            CycleKind::MemoryRead,
+            CycleKind::MemoryWrite,
+        ] {
+            assert_eq!(cpu.step(&mut ()).kind, kind);
+        }
+        for _ in 0..3 {
+            let cycle = cpu.step(&mut ());
+            assert_eq!((cycle.kind, cycle.t_states), (CycleKind::Hold, 1));
+            assert!(cpu.hlda());
+        }
 
-    (carry & (1 << bit_no)) != 0
-}
+        cpu.cycle(&mut ());
+        assert!(!cpu.hlda());
+        assert_eq!(cpu.pc(), 6);
+    }
 
-/// Calculates the Auxillary Carry from `x-y`
-fn calc_ac(x: u8, y: u8) -> bool {
-    (((!y).wrapping_add(1) & 0x0f) + (x & 0x0f)) & 0x10 != 0
+    #[test]
+    fn cycle_returns_t_states_past_255() {
+        // LHLD 0x0100, 5 machine cycles of 3 or 4 T-states and 255 wait states
+        let mut cpu = CPU::builder()
+            .load(0x0000, &[0x2a, 0x00, 0x01])
+            .memory_map(MemoryMap::new().wait_states(0x0000..=0xffff, 255))
+            .build()
+            .unwrap();
+
+        let start = cpu.cycles();
+        assert_eq!(cpu.cycle(&mut ()), 16 + 5 * 255);
+        assert_eq!(cpu.cycles() - start, 16 + 5 * 255);
+    }
 }
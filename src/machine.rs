@@ -0,0 +1,138 @@
+/// Status word bits placed on the data bus during T1 of every machine cycle.
+pub mod status {
+    /// Interrupt acknowledge.
+    pub const INTA: u8 = 0x01;
+    /// Active low write or output. Set for reads and inputs.
+    pub const WO: u8 = 0x02;
+    /// The address bus holds the stack pointer.
+    pub const STACK: u8 = 0x04;
+    /// Halt acknowledge.
+    pub const HLTA: u8 = 0x08;
+    /// The address bus holds an output port.
+    pub const OUT: u8 = 0x10;
+    /// First machine cycle of an instruction.
+    pub const M1: u8 = 0x20;
+    /// The address bus holds an input port.
+    pub const INP: u8 = 0x40;
+    /// The data bus will be used for a memory read.
+    pub const MEMR: u8 = 0x80;
+}
+
+/// The type of a machine cycle, as told apart by its status word.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum CycleKind {
+    /// Opcode fetch.
+    Fetch,
+    /// Memory read of an operand or data.
+    MemoryRead,
+    /// Memory write of data.
+    MemoryWrite,
+    /// Memory read from the stack.
+    StackRead,
+    /// Memory write to the stack.
+    StackWrite,
+    /// Read from an input port.
+    Input,
+    /// Write to an output port.
+    Output,
+    /// Interrupt acknowledge, where the interrupting device supplies the
+    /// instruction.
+    InterruptAcknowledge,
+    /// Halt acknowledge.
+    HaltAcknowledge,
+    /// Interrupt acknowledge while halted.
+    InterruptAcknowledgeHalt,
+    /// Internal cycle where the bus is not used, such as the last two machine
+    /// cycles of DAD. The status is that of a memory read but DBIN stays
+    /// inactive.
+    BusIdle,
+    /// The bus has been released to a DMA controller in response to HOLD. No
+    /// status word is output and the address bus floats.
+    Hold,
+}
+
+impl CycleKind {
+    /// Returns the status word output during T1 of this kind of cycle.
+    pub fn status(&self) -> u8 {
+        use status::*;
+
+        match self {
+            Self::Fetch => MEMR | M1 | WO,
+            Self::MemoryRead => MEMR | WO,
+            Self::MemoryWrite => 0,
+            Self::StackRead => MEMR | STACK | WO,
+            Self::StackWrite => STACK,
+            Self::Input => INP | WO,
+            Self::Output => OUT,
+            Self::InterruptAcknowledge => INTA | M1 | WO,
+            Self::HaltAcknowledge => MEMR | HLTA | WO,
+            Self::InterruptAcknowledgeHalt => INTA | HLTA | M1 | WO,
+            Self::BusIdle => MEMR | WO,
+            Self::Hold => 0,
+        }
+    }
+
+    /// Returns true if data flows into the [`CPU`](crate::CPU) during this
+    /// kind of cycle.
+    pub fn is_read(&self) -> bool {
+        matches!(
+            self,
+            Self::Fetch
+                | Self::MemoryRead
+                | Self::StackRead
+                | Self::Input
+                | Self::InterruptAcknowledge
+                | Self::InterruptAcknowledgeHalt
+        )
+    }
+
+    /// Returns true if data flows out of the [`CPU`](crate::CPU) during this
+    /// kind of cycle.
+    pub fn is_write(&self) -> bool {
+        matches!(self, Self::MemoryWrite | Self::StackWrite | Self::Output)
+    }
+}
+
+/// Levels of the output pins of the [`CPU`](crate::CPU). `true` means the pin
+/// is active, regardless of whether the pin is active high or low.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Pins {
+    /// Marks the start of a machine cycle. Active during T1.
+    pub sync: bool,
+    /// The data bus is in input mode.
+    pub dbin: bool,
+    /// Data on the data bus is stable for a write.
+    pub wr: bool,
+    /// Interrupts are enabled.
+    pub inte: bool,
+    /// The bus has been released in response to HOLD.
+    pub hlda: bool,
+    /// The [`CPU`](crate::CPU) is in a wait or halt state.
+    pub wait: bool,
+}
+
+/// A single machine cycle, as returned by [`CPU::step`](crate::CPU::step).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MachineCycle {
+    pub kind: CycleKind,
+    /// Address on the address bus. Ports are placed on both halves. For a
+    /// hold, the address of the next instruction.
+    pub address: u16,
+    /// Data transferred on the data bus.
+    pub data: u8,
+    /// Number of T-states the cycle took.
+    pub t_states: u8,
+    /// Pins over the data transfer of the cycle. SYNC is active if the cycle
+    /// had a T1.
+    pub pins: Pins,
+}
+
+impl MachineCycle {
+    /// Returns the status word output during T1 of the cycle.
+    pub fn status(&self) -> u8 {
+        self.kind.status()
+    }
+}
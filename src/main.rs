@@ -1,20 +1,62 @@
 use intel8080::*;
 use std::fs::read;
 use std::io::{self, Write};
+use std::time::Instant;
 
 fn main() {
-    let mut args = std::env::args();
-    let _ = args.next();
+    let args: Vec<String> = std::env::args().skip(1).collect();
 
-    let mode = args.next();
-
-    match mode.as_ref() {
+    match args.first() {
         Some(val) if val == "--tests" => run_tests(),
         Some(val) if val == "--trivial" => trivial(),
+        Some(val) if val == "--recompile" => recompile(&args[1..]),
         _ => {}
     }
 }
 
+/// Prints Rust source for the code in the ROM files given as `addr=path`
+/// after the name of the struct to compile them to, with addresses in hex.
+fn recompile(args: &[String]) {
+    let Some((name, roms)) = args.split_first() else {
+        eprintln!("Usage: --recompile <name> <addr>=<rom>...");
+        return;
+    };
+
+    let mut builder = CPU::builder();
+    let mut map = MemoryMap::new();
+
+    for rom in roms {
+        let Some((addr, path)) = rom.split_once('=') else {
+            eprintln!("Expected <addr>=<rom>, got {rom}");
+            return;
+        };
+        let Ok(addr) = u16::from_str_radix(addr.trim_start_matches("0x"), 16) else {
+            eprintln!("Invalid address {addr}");
+            return;
+        };
+        let data = match read(path) {
+            Ok(data) => data,
+            Err(error) => {
+                eprintln!("{path}: {error}");
+                return;
+            }
+        };
+
+        if data.is_empty() {
+            continue;
+        }
+
+        let end = (addr as usize + data.len()).min(MEM_SIZE) - 1;
+        map = map.rom(addr..=end as u16);
+        builder = builder.load(addr, &data);
+    }
+
+    match builder.memory_map(map).build() {
+        Ok(cpu) => print!("{}", Recompiler::new(&cpu).compile(name)),
+        Err(error) => eprintln!("{error}"),
+    }
+}
+
 fn _temp_main() {
     let mut memory = [0; MEM_SIZE];
     #[rustfmt::skip]
@@ -70,7 +112,7 @@ fn _temp_main() {
 
         match command {
             Some("i") => {
-                cpu.interrupt(0xcf);
+                cpu.interrupt(&[0xcf]);
                 cpu.debug();
             }
             _ => {
@@ -152,20 +194,13 @@ pub fn run_tests() {
 
 fn test(test: &str) {
     fn test_prep(program: &[u8]) -> CPU {
-        let start = 0x100;
-        let mut memory = [0; MEM_SIZE];
-        let len = program.len().min(MEM_SIZE - start);
-
-        memory[start..start + len].copy_from_slice(&program[..len]);
-
-        memory[0x0000] = 0xd3;
-        memory[0x0001] = 0x00;
-
-        memory[0x0005] = 0xd3;
-        memory[0x0006] = 0x01;
-        memory[0x0007] = 0xc9;
-
-        CPU::new_from_start(memory, start as u16)
+        CPU::builder()
+            .load(0x0100, program)
+            .load(0x0000, &[0xd3, 0x00])
+            .load(0x0005, &[0xd3, 0x01, 0xc9])
+            .pc(0x0100)
+            .build()
+            .expect("Full RAM should be page aligned")
     }
 
     let rom = read(format!("./tests/{test}.COM")).unwrap();
@@ -173,15 +208,20 @@ fn test(test: &str) {
 
     println!("\n**** Testing {test}.COM");
 
-    let mut ins = 0usize;
     let mut bus = TestingBus::new();
+    let start = Instant::now();
 
     while !bus.exit {
-        ins += 1;
         emulator.cycle(&mut bus);
     }
 
-    println!("\n**** {ins} instructions");
+    let elapsed = start.elapsed();
+    println!(
+        "\n**** {} instructions in {:.2}s ({:.1} MIPS)",
+        emulator.instructions(),
+        elapsed.as_secs_f64(),
+        emulator.instructions() as f64 / elapsed.as_secs_f64() / 1e6
+    );
 }
 
 #[derive(Default)]
@@ -204,13 +244,13 @@ impl Bus for TestingBus {
         if port == 0 {
             self.exit = true;
         } else if port == 1 {
-            let operation = cpu.register(1);
+            let operation = cpu.register(Register::C);
 
             if operation == 2 {
-                let e = cpu.register(3);
+                let e = cpu.register(Register::E);
                 print!("{}", e as char);
             } else {
-                let mut addr = ((cpu.register(2) as u16) << 8) | (cpu.register(3) as u16);
+                let mut addr = cpu.register_pair(RegisterPair::DE);
                 print!("{}", cpu.memory()[addr as usize] as char);
 
                 while cpu.memory()[addr as usize] != 36 {
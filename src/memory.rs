@@ -0,0 +1,1073 @@
+use crate::MEM_SIZE;
+use crate::state::{Reader, StateError};
+use std::ops::{Bound, Index, Range, RangeBounds, RangeInclusive};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU8, AtomicU64, Ordering, fence};
+
+/// Size in bytes of a page, the granularity at which memory is mapped.
+pub const PAGE_SIZE: usize = 256;
+const PAGES: usize = MEM_SIZE / PAGE_SIZE;
+
+/// Value read from unmapped memory.
+const OPEN_BUS: u8 = 0xFF;
+
+/// A page of backing storage, shared between clones of a [`Memory`] until one
+/// of them writes to it.
+///
+/// The bytes are atomics so that a page held by one [`Memory`] alone can be
+/// written in place without the atomic read-modify-write [`Arc::make_mut`]
+/// makes on every call. Relaxed accesses compile to plain loads and stores.
+type Storage = Arc<[AtomicU8; PAGE_SIZE]>;
+
+/// Every byte value, from which [`Index`] hands out references to bytes it
+/// cannot borrow from atomic storage.
+static BYTES: [u8; 256] = {
+    let mut bytes = [0; 256];
+    let mut idx = 0;
+    while idx < 256 {
+        bytes[idx] = idx as u8;
+        idx += 1;
+    }
+
+    bytes
+};
+
+/// Generations handed out so far.
+static GENERATIONS: AtomicU64 = AtomicU64::new(0);
+
+/// Returns a generation no [`Memory`] has had yet.
+fn generation() -> u64 {
+    GENERATIONS.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Splits `data` into pages of storage, dropping any partial page at the end.
+fn storage(data: &[u8]) -> Vec<Storage> {
+    data.chunks_exact(PAGE_SIZE)
+        .map(|chunk| page(|idx| chunk[idx]))
+        .collect()
+}
+
+/// Returns a page of storage holding `byte(idx)` at each offset `idx`.
+fn page(byte: impl Fn(usize) -> u8) -> Storage {
+    Arc::new(std::array::from_fn(|idx| AtomicU8::new(byte(idx))))
+}
+
+/// Returns the bytes held by `page`.
+fn bytes(page: &Storage) -> impl Iterator<Item = u8> + '_ {
+    page.iter().map(|byte| byte.load(Ordering::Relaxed))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+enum Access {
+    ReadWrite,
+    ReadOnly,
+    Unmapped,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+struct Page {
+    /// Offset of the page in the backing storage.
+    offset: usize,
+    access: Access,
+    /// Wait states inserted into every access to the page.
+    wait_states: u8,
+}
+
+/// The identity mapping where every page is RAM backed by the first 64K of
+/// storage.
+const IDENTITY: [Page; PAGES] = {
+    let mut pages = [Page {
+        offset: 0,
+        access: Access::ReadWrite,
+        wait_states: 0,
+    }; PAGES];
+
+    let mut idx = 0;
+    while idx < PAGES {
+        pages[idx].offset = idx * PAGE_SIZE;
+        idx += 1;
+    }
+
+    pages
+};
+
+/// A window of the address space which can be switched between banks.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+struct Bank {
+    /// Port which selects the visible bank.
+    port: u8,
+    /// First page of the window.
+    start: usize,
+    /// Pages of bank 0, as mapped before the window was declared.
+    base: Vec<Page>,
+    /// Number of banks.
+    count: u8,
+    /// Offset in the backing storage of bank 1. Bank `n` follows `n - 1`
+    /// windows after it.
+    offset: usize,
+    /// Currently visible bank.
+    selected: u8,
+}
+
+/// The memory seen by a [`CPU`](crate::CPU).
+///
+/// The 16-bit address space is split into pages of [`PAGE_SIZE`] bytes, each
+/// of which points into the backing storage. A [`MemoryMap`] decides which
+/// storage a page points to and whether it can be written to.
+///
+/// The first 64K of storage back the address space as it appears with no
+/// [`MemoryMap`]. Banked windows are backed by storage after it.
+///
+/// Cloning a [`Memory`] is cheap, as the clones share storage until written
+/// to.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "Layout"))]
+pub struct Memory {
+    /// Backing storage.
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serialize_data"))]
+    data: Vec<Storage>,
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serialize_pages"))]
+    pages: [Page; PAGES],
+    banks: Vec<Bank>,
+    /// Storage visible at each page, resolved from `pages` so that accesses
+    /// skip the lookup. Unmapped pages see a page of open bus.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    view: [Storage; PAGES],
+    /// Number of references this memory holds to the storage visible at each
+    /// page. Only while there are no others is the page written in place.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    refs: [usize; PAGES],
+    /// True for each page known to be writable and held by no clone, which
+    /// spares writes to it the checks. Atomic so that cloning, which shares
+    /// every page, can clear them through a shared reference.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    owned: [AtomicBool; PAGES],
+    /// True if any page, banked or not, has wait states.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    waits: bool,
+    /// Identifies the read-only pages and their contents, which only clones
+    /// share.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    generation: u64,
+}
+
+impl Clone for Memory {
+    fn clone(&self) -> Self {
+        // Every page is shared from here on, so neither side owns any
+        for owned in &self.owned {
+            owned.store(false, Ordering::Relaxed);
+        }
+
+        Self {
+            data: self.data.clone(),
+            pages: self.pages,
+            banks: self.banks.clone(),
+            view: self.view.clone(),
+            refs: self.refs,
+            owned: std::array::from_fn(|_| AtomicBool::new(false)),
+            waits: self.waits,
+            generation: self.generation,
+        }
+    }
+}
+
+/// Serializes `data` as a flat sequence of bytes.
+#[cfg(feature = "serde")]
+fn serialize_data<S: serde::Serializer>(
+    data: &[Storage],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(data.iter().flat_map(bytes))
+}
+
+/// Serializes `pages` as a sequence, as serde only supports short arrays.
+#[cfg(feature = "serde")]
+fn serialize_pages<S: serde::Serializer>(
+    pages: &[Page; PAGES],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(pages)
+}
+
+/// Deserialized fields of a [`Memory`], checked before conversion.
+#[cfg(feature = "serde")]
+#[derive(serde::Deserialize)]
+struct Layout {
+    data: Vec<u8>,
+    pages: Vec<Page>,
+    banks: Vec<Bank>,
+}
+
+#[cfg(feature = "serde")]
+impl TryFrom<Layout> for Memory {
+    type Error = &'static str;
+
+    fn try_from(layout: Layout) -> Result<Self, Self::Error> {
+        if !layout.data.len().is_multiple_of(PAGE_SIZE) {
+            return Err("Memory storage should be a whole number of pages");
+        }
+
+        let pages = layout
+            .pages
+            .try_into()
+            .map_err(|_| "Memory should have 256 pages")?;
+
+        Self::with_layout(storage(&layout.data), pages, layout.banks)
+            .ok_or("Memory pages and banks should lie within its storage")
+    }
+}
+
+impl Memory {
+    pub(crate) fn new(data: [u8; MEM_SIZE]) -> Self {
+        let mut memory = Self::unresolved(storage(&data), IDENTITY, vec![]);
+        memory.relayout();
+        memory
+    }
+
+    /// Returns memory with the given storage and layout, unless a page or
+    /// bank lies outside of the storage.
+    fn with_layout(data: Vec<Storage>, pages: [Page; PAGES], banks: Vec<Bank>) -> Option<Self> {
+        let mut memory = Self::unresolved(data, pages, banks);
+        if !memory.is_valid() {
+            return None;
+        }
+
+        memory.relayout();
+        Some(memory)
+    }
+
+    /// Returns memory with the given storage and layout, whose pages are yet
+    /// to be resolved by [`Memory::relayout`].
+    fn unresolved(data: Vec<Storage>, pages: [Page; PAGES], banks: Vec<Bank>) -> Self {
+        let open_bus = page(|_| OPEN_BUS);
+
+        Self {
+            data,
+            pages,
+            banks,
+            view: std::array::from_fn(|_| open_bus.clone()),
+            refs: [0; PAGES],
+            owned: std::array::from_fn(|_| AtomicBool::new(false)),
+            waits: false,
+            generation: generation(),
+        }
+    }
+
+    /// Resolves the storage visible at each page, notes which pages have wait
+    /// states and moves on to a new generation, after the layout changed.
+    fn relayout(&mut self) {
+        let open_bus = page(|_| OPEN_BUS);
+        for (view, page) in self.view.iter_mut().zip(&self.pages) {
+            *view = match page.access {
+                Access::Unmapped => open_bus.clone(),
+                _ => self.data[page.offset / PAGE_SIZE].clone(),
+            };
+        }
+
+        self.count_refs();
+
+        for owned in &mut self.owned {
+            *owned.get_mut() = false;
+        }
+
+        self.waits = self
+            .pages
+            .iter()
+            .chain(self.banks.iter().flat_map(|bank| &bank.base))
+            .any(|page| page.wait_states > 0);
+        self.generation = generation();
+    }
+
+    /// Counts the references held to the storage visible at each page.
+    fn count_refs(&mut self) {
+        // Besides the views, the storage itself holds a reference
+        let mut refs = vec![1; self.data.len()];
+        for page in self
+            .pages
+            .iter()
+            .filter(|page| page.access != Access::Unmapped)
+        {
+            refs[page.offset / PAGE_SIZE] += 1;
+        }
+
+        for (count, page) in self.refs.iter_mut().zip(&self.pages) {
+            *count = refs[page.offset / PAGE_SIZE];
+        }
+    }
+
+    /// Returns true if a page in `pages` is read-only or shows the storage of
+    /// a read-only page, which compiled code may rely on.
+    fn shows_rom(&self, pages: Range<usize>) -> bool {
+        self.pages[pages].iter().any(|page| {
+            page.access != Access::Unmapped
+                && self
+                    .pages
+                    .iter()
+                    .any(|other| other.access == Access::ReadOnly && other.offset == page.offset)
+        })
+    }
+
+    /// Returns the byte visible at `addr`. Unmapped memory reads as 0xFF.
+    #[inline]
+    pub fn read(&self, addr: u16) -> u8 {
+        self.view[addr as usize / PAGE_SIZE][addr as usize % PAGE_SIZE].load(Ordering::Relaxed)
+    }
+
+    /// Returns the little-endian word at `addr`, as two reads would.
+    #[inline]
+    pub(crate) fn read_word(&self, addr: u16) -> u16 {
+        let offset = addr as usize % PAGE_SIZE;
+        if offset == PAGE_SIZE - 1 {
+            return u16::from_le_bytes([self.read(addr), self.read(addr.wrapping_add(1))]);
+        }
+
+        let page = &self.view[addr as usize / PAGE_SIZE];
+        u16::from_le_bytes([
+            page[offset].load(Ordering::Relaxed),
+            page[offset + 1].load(Ordering::Relaxed),
+        ])
+    }
+
+    /// Writes `data` to `addr`. Writes to ROM or unmapped memory are ignored.
+    #[inline]
+    pub fn write(&mut self, addr: u16, data: u8) {
+        let idx = addr as usize / PAGE_SIZE;
+        if !*self.owned[idx].get_mut() && !self.own(idx) {
+            return;
+        }
+
+        self.view[idx][addr as usize % PAGE_SIZE].store(data, Ordering::Relaxed);
+    }
+
+    /// Writes the little-endian word `value` to `addr`, as two writes would.
+    #[inline]
+    pub(crate) fn write_word(&mut self, addr: u16, value: u16) {
+        let idx = addr as usize / PAGE_SIZE;
+        let offset = addr as usize % PAGE_SIZE;
+        let [low, high] = value.to_le_bytes();
+        if offset == PAGE_SIZE - 1 || !*self.owned[idx].get_mut() && !self.own(idx) {
+            self.write(addr, low);
+            self.write(addr.wrapping_add(1), high);
+            return;
+        }
+
+        let page = &self.view[idx];
+        page[offset].store(low, Ordering::Relaxed);
+        page[offset + 1].store(high, Ordering::Relaxed);
+    }
+
+    /// Makes page `idx` writable in place, copying it if a clone still
+    /// shares it. Returns false if the page cannot be written.
+    #[cold]
+    fn own(&mut self, idx: usize) -> bool {
+        if self.pages[idx].access != Access::ReadWrite {
+            return false;
+        }
+
+        // Nobody else can take a reference while `self` is borrowed mutably,
+        // so a page held by no other clone stays that way until it is cloned
+        if Arc::strong_count(&self.view[idx]) != self.refs[idx] {
+            self.unshare(idx);
+        }
+        // Pairs with the release of the last other reference, so that its
+        // holder is done reading before the page is written
+        fence(Ordering::Acquire);
+
+        *self.owned[idx].get_mut() = true;
+        true
+    }
+
+    /// Gives the storage visible at page `idx` a copy of its own, no longer
+    /// shared with clones.
+    #[cold]
+    fn unshare(&mut self, idx: usize) {
+        let offset = self.pages[idx].offset;
+        let shared = &self.data[offset / PAGE_SIZE];
+        let storage = page(|idx| shared[idx].load(Ordering::Relaxed));
+
+        for (view, page) in self.view.iter_mut().zip(&self.pages) {
+            if page.access != Access::Unmapped && page.offset == offset {
+                *view = storage.clone();
+            }
+        }
+        self.data[offset / PAGE_SIZE] = storage;
+    }
+
+    /// Returns true if the byte at `addr` is read-only, with no writable
+    /// address sharing its storage.
+    pub(crate) fn is_fixed(&self, addr: u16) -> bool {
+        let page = self.pages[addr as usize / PAGE_SIZE];
+
+        page.access == Access::ReadOnly
+            && !self
+                .pages
+                .iter()
+                .any(|other| other.access == Access::ReadWrite && other.offset == page.offset)
+    }
+
+    /// Returns the generation of the memory, which changes whenever its
+    /// read-only pages or their contents may have.
+    pub(crate) fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Returns the bytes visible in `range`.
+    pub fn read_range(&self, range: impl RangeBounds<u16>) -> Vec<u8> {
+        let start = match range.start_bound() {
+            Bound::Included(&start) => start as usize,
+            Bound::Excluded(&start) => start as usize + 1,
+            Bound::Unbounded => 0,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(&end) => end as usize + 1,
+            Bound::Excluded(&end) => end as usize,
+            Bound::Unbounded => MEM_SIZE,
+        };
+
+        (start..end).map(|addr| self.read(addr as u16)).collect()
+    }
+
+    /// Returns the number of wait states inserted into accesses to `addr`.
+    #[inline]
+    pub fn wait_states(&self, addr: u16) -> u8 {
+        if !self.waits {
+            return 0;
+        }
+
+        self.pages[addr as usize / PAGE_SIZE].wait_states
+    }
+
+    /// Whether any page, mapped or banked, inserts wait states.
+    pub(crate) fn waits(&self) -> bool {
+        self.waits
+    }
+
+    /// Returns the bank visible in the window selected through `port`, if
+    /// there is one.
+    pub fn bank(&self, port: u8) -> Option<u8> {
+        self.banks
+            .iter()
+            .find(|bank| bank.port == port)
+            .map(|bank| bank.selected)
+    }
+
+    /// Makes bank `data % count` visible in every window selected through
+    /// `port`, where `count` is the number of banks of the window.
+    ///
+    /// Only the pages of the switched windows are resolved again, and the
+    /// generation only moves on if read-only pages are switched in or out.
+    pub(crate) fn select_bank(&mut self, port: u8, data: u8) {
+        for window in 0..self.banks.len() {
+            let bank = &self.banks[window];
+            let selected = data % bank.count;
+            if bank.port != port || bank.selected == selected {
+                continue;
+            }
+
+            let (start, len) = (bank.start, bank.base.len());
+            let pages = start..start + len;
+            let showed_rom = self.shows_rom(pages.clone());
+
+            for idx in 0..len {
+                let bank = &self.banks[window];
+                let base = bank.base[idx];
+                let mapped = match selected {
+                    0 => base,
+                    n => Page {
+                        offset: bank.offset + ((n as usize - 1) * len + idx) * PAGE_SIZE,
+                        access: Access::ReadWrite,
+                        wait_states: base.wait_states,
+                    },
+                };
+
+                self.pages[start + idx] = mapped;
+                self.view[start + idx] = match mapped.access {
+                    Access::Unmapped => page(|_| OPEN_BUS),
+                    _ => self.data[mapped.offset / PAGE_SIZE].clone(),
+                };
+                *self.owned[start + idx].get_mut() = false;
+            }
+            self.banks[window].selected = selected;
+            self.count_refs();
+
+            if showed_rom || self.shows_rom(pages) {
+                self.generation = generation();
+            }
+        }
+    }
+
+    /// Applies `map` to the address space, replacing any previous map.
+    /// Contents of the first 64K of storage are kept while banked storage is
+    /// cleared.
+    pub(crate) fn map(&mut self, map: &MemoryMap) -> Result<(), MapError> {
+        let mut pages = IDENTITY;
+        let mut banks = vec![];
+        let mut size = MEM_SIZE;
+
+        for (range, region) in &map.regions {
+            let (start, end) = page_range(range)?;
+
+            let access = match region {
+                Region::Ram => Access::ReadWrite,
+                Region::Rom => Access::ReadOnly,
+                Region::Unmapped => Access::Unmapped,
+                Region::Banked { count, port } => {
+                    if *count == 0 {
+                        return Err(MapError::NoBanks(range.clone()));
+                    }
+
+                    let len = (end - start) * PAGE_SIZE;
+
+                    banks.push(Bank {
+                        port: *port,
+                        start,
+                        base: pages[start..end].to_vec(),
+                        count: *count,
+                        offset: size,
+                        selected: 0,
+                    });
+
+                    size += (*count as usize - 1) * len;
+
+                    continue;
+                }
+                Region::Mirror(source) => {
+                    let (from, to) = page_range(source)?;
+
+                    for (count, idx) in (start..end).enumerate() {
+                        pages[idx] = pages[from + count % (to - from)];
+                    }
+
+                    continue;
+                }
+                Region::WaitStates(wait_states) => {
+                    for page in &mut pages[start..end] {
+                        page.wait_states = *wait_states;
+                    }
+
+                    continue;
+                }
+            };
+
+            for (idx, page) in pages.iter_mut().enumerate().take(end).skip(start) {
+                *page = Page {
+                    offset: idx * PAGE_SIZE,
+                    access,
+                    wait_states: 0,
+                };
+            }
+        }
+
+        self.data.truncate(PAGES);
+        self.data.resize(size / PAGE_SIZE, page(|_| 0));
+        self.pages = pages;
+        self.banks = banks;
+        self.relayout();
+
+        Ok(())
+    }
+}
+
+impl Memory {
+    /// Returns an estimate of the size of the save-state of the memory.
+    pub(crate) fn state_len(&self) -> usize {
+        (self.data.len() + PAGES) * PAGE_SIZE
+    }
+
+    /// Appends the storage and layout of the memory to `state`.
+    pub(crate) fn save(&self, state: &mut Vec<u8>) {
+        state.extend_from_slice(&((self.data.len() * PAGE_SIZE) as u32).to_le_bytes());
+        for page in &self.data {
+            state.extend(bytes(page));
+        }
+
+        for page in &self.pages {
+            save_page(state, page);
+        }
+
+        state.extend_from_slice(&(self.banks.len() as u16).to_le_bytes());
+        for bank in &self.banks {
+            state.push(bank.port);
+            state.push(bank.start as u8);
+            state.extend_from_slice(&(bank.base.len() as u16).to_le_bytes());
+            for page in &bank.base {
+                save_page(state, page);
+            }
+            state.push(bank.count);
+            state.extend_from_slice(&(bank.offset as u32).to_le_bytes());
+            state.push(bank.selected);
+        }
+    }
+
+    /// Reads memory saved by [`Memory::save`] out of `reader`.
+    pub(crate) fn load(reader: &mut Reader) -> Result<Self, StateError> {
+        let len = reader.u32()? as usize;
+        if !len.is_multiple_of(PAGE_SIZE) {
+            return Err(StateError::Corrupt);
+        }
+
+        let data = storage(reader.bytes(len)?);
+
+        let mut pages = IDENTITY;
+        for page in &mut pages {
+            *page = load_page(reader)?;
+        }
+
+        let mut banks = vec![];
+        for _ in 0..reader.u16()? {
+            let port = reader.u8()?;
+            let start = reader.u8()? as usize;
+
+            let mut base = vec![];
+            for _ in 0..reader.u16()? {
+                base.push(load_page(reader)?);
+            }
+
+            banks.push(Bank {
+                port,
+                start,
+                base,
+                count: reader.u8()?,
+                offset: reader.u32()? as usize,
+                selected: reader.u8()?,
+            });
+        }
+
+        Self::with_layout(data, pages, banks).ok_or(StateError::Corrupt)
+    }
+
+    /// Returns true if every page and bank lies within the backing storage.
+    fn is_valid(&self) -> bool {
+        let len = self.data.len() * PAGE_SIZE;
+        let fits =
+            |page: &Page| page.offset.is_multiple_of(PAGE_SIZE) && page.offset + PAGE_SIZE <= len;
+
+        len >= MEM_SIZE
+            && self.pages.iter().all(fits)
+            && self.banks.iter().all(|bank| {
+                let banked = (bank.count as usize).saturating_sub(1) * bank.base.len();
+
+                bank.start + bank.base.len() <= PAGES
+                    && bank.selected < bank.count
+                    && bank.offset + banked * PAGE_SIZE <= len
+                    && bank.base.iter().all(fits)
+            })
+    }
+}
+
+fn save_page(state: &mut Vec<u8>, page: &Page) {
+    state.extend_from_slice(&(page.offset as u32).to_le_bytes());
+    state.push(match page.access {
+        Access::ReadWrite => 0,
+        Access::ReadOnly => 1,
+        Access::Unmapped => 2,
+    });
+    state.push(page.wait_states);
+}
+
+/// Reads a page saved by [`save_page`].
+fn load_page(reader: &mut Reader) -> Result<Page, StateError> {
+    let offset = reader.u32()? as usize;
+    let access = match reader.u8()? {
+        0 => Access::ReadWrite,
+        1 => Access::ReadOnly,
+        2 => Access::Unmapped,
+        _ => return Err(StateError::Corrupt),
+    };
+    let wait_states = reader.u8()?;
+
+    Ok(Page {
+        offset,
+        access,
+        wait_states,
+    })
+}
+
+/// Converts `range` into a half open range of page indices.
+fn page_range(range: &RangeInclusive<u16>) -> Result<(usize, usize), MapError> {
+    let (start, end) = (*range.start() as usize, *range.end() as usize + 1);
+
+    if start % PAGE_SIZE != 0 || end % PAGE_SIZE != 0 || start >= end {
+        return Err(MapError::Unaligned(range.clone()));
+    }
+
+    Ok((start / PAGE_SIZE, end / PAGE_SIZE))
+}
+
+impl Index<usize> for Memory {
+    type Output = u8;
+
+    /// Returns the byte visible at `addr`. Unmapped memory reads as 0xFF.
+    fn index(&self, addr: usize) -> &Self::Output {
+        let byte = &self.view[addr / PAGE_SIZE][addr % PAGE_SIZE];
+
+        &BYTES[byte.load(Ordering::Relaxed) as usize]
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+enum Region {
+    Ram,
+    Rom,
+    Mirror(RangeInclusive<u16>),
+    Unmapped,
+    Banked { count: u8, port: u8 },
+    WaitStates(u8),
+}
+
+/// A description of how the address space of a machine is laid out.
+///
+/// Regions are applied in the order they are added, so later regions take
+/// precedence over earlier ones. Addresses not covered by any region are RAM.
+/// All ranges must start and end on a [`PAGE_SIZE`] boundary.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MemoryMap {
+    regions: Vec<(RangeInclusive<u16>, Region)>,
+}
+
+impl MemoryMap {
+    /// Creates a new [`MemoryMap`] where all memory is RAM.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Maps `range` as read/write memory.
+    pub fn ram(mut self, range: RangeInclusive<u16>) -> Self {
+        self.regions.push((range, Region::Ram));
+        self
+    }
+
+    /// Maps `range` as read-only memory. Writes to it are ignored.
+    pub fn rom(mut self, range: RangeInclusive<u16>) -> Self {
+        self.regions.push((range, Region::Rom));
+        self
+    }
+
+    /// Maps `range` as a mirror of `source`, repeating `source` if `range` is
+    /// larger. The mirror shares both the contents and the access of `source`
+    /// as mapped at this point.
+    pub fn mirror(mut self, range: RangeInclusive<u16>, source: RangeInclusive<u16>) -> Self {
+        self.regions.push((range, Region::Mirror(source)));
+        self
+    }
+
+    /// Leaves `range` unmapped. Reads return 0xFF and writes are ignored.
+    pub fn unmapped(mut self, range: RangeInclusive<u16>) -> Self {
+        self.regions.push((range, Region::Unmapped));
+        self
+    }
+
+    /// Makes `window` switchable between `count` banks. Writing `n` to `port`
+    /// makes bank `n % count` visible in the window.
+    ///
+    /// Bank 0 is the memory mapped at `window` at this point, while the others
+    /// are RAM of their own. Bank 0 is visible initially. Later regions should
+    /// not overlap `window`.
+    pub fn banked(mut self, window: RangeInclusive<u16>, count: u8, port: u8) -> Self {
+        self.regions.push((window, Region::Banked { count, port }));
+        self
+    }
+
+    /// Inserts `count` wait states into every access to `range`, as slow
+    /// memory would by holding READY low. This only changes the timing of
+    /// memory already mapped at `range`.
+    pub fn wait_states(mut self, range: RangeInclusive<u16>, count: u8) -> Self {
+        self.regions.push((range, Region::WaitStates(count)));
+        self
+    }
+
+    /// Appends the regions of `map`, which take precedence over those of
+    /// `self`.
+    pub(crate) fn then(mut self, map: MemoryMap) -> Self {
+        self.regions.extend(map.regions);
+        self
+    }
+}
+
+/// Errors from applying a [`MemoryMap`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum MapError {
+    /// The range does not start and end on a [`PAGE_SIZE`] boundary.
+    Unaligned(RangeInclusive<u16>),
+    /// The banked window has no banks.
+    NoBanks(RangeInclusive<u16>),
+}
+
+impl std::fmt::Display for MapError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Unaligned(range) => write!(
+                f,
+                "Range 0x{:04x}..=0x{:04x} is not aligned to {PAGE_SIZE} byte pages",
+                range.start(),
+                range.end()
+            ),
+            Self::NoBanks(range) => write!(
+                f,
+                "Banked window 0x{:04x}..=0x{:04x} has no banks",
+                range.start(),
+                range.end()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for MapError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Bus, CPU, Register};
+
+    /// A [`Bus`] which supplies HLT to fetches from 0x0100, reads 0x5a from
+    /// 0x8000 and takes the writes to 0x8000, leaving the rest to memory.
+    #[derive(Default)]
+    struct Hooks {
+        stored: Vec<(u16, u8)>,
+    }
+
+    impl Bus for Hooks {
+        fn read(&mut self, _cpu: &CPU, _port: u8) -> u8 {
+            0
+        }
+
+        fn write(&mut self, _cpu: &CPU, _port: u8, _data: u8) {}
+
+        fn fetch(&mut self, _cpu: &CPU, addr: u16) -> Option<u8> {
+            (addr == 0x0100).then_some(0x76)
+        }
+
+        fn load(&mut self, _cpu: &CPU, addr: u16) -> Option<u8> {
+            (addr == 0x8000).then_some(0x5a)
+        }
+
+        fn store(&mut self, _cpu: &CPU, addr: u16, data: u8) -> bool {
+            if addr == 0x8000 {
+                self.stored.push((addr, data));
+            }
+            addr == 0x8000
+        }
+    }
+
+    /// Returns memory with ROM, a mirror of RAM and two windows of four banks,
+    /// with something written to every bank.
+    fn banked() -> Memory {
+        let mut memory = Memory::new([0x76; MEM_SIZE]);
+        let map = MemoryMap::new()
+            .rom(0x0000..=0x0fff)
+            .mirror(0x1000..=0x1fff, 0x2000..=0x20ff)
+            .banked(0x4000..=0x40ff, 4, 0x10)
+            .banked(0x8000..=0x81ff, 4, 0x11)
+            .unmapped(0xf000..=0xffff);
+        memory.map(&map).unwrap();
+
+        for bank in 0..4 {
+            memory.select_bank(0x10, bank);
+            memory.select_bank(0x11, bank);
+            memory.write(0x4000, bank + 1);
+            memory.write(0x81ff, bank + 5);
+        }
+        memory.select_bank(0x10, 2);
+        memory.write(0x1001, 0x2a);
+
+        memory
+    }
+
+    /// Returns every byte of `memory` as seen with each bank selected.
+    fn contents(memory: &Memory) -> Vec<Vec<u8>> {
+        let mut memory = memory.clone();
+
+        (0..4)
+            .map(|bank| {
+                memory.select_bank(0x10, bank);
+                memory.select_bank(0x11, bank);
+                memory.read_range(..)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn bus_hooks_intercept_fetches_loads_and_stores() {
+        let mut program = vec![
+            0x3a, 0x00, 0x80, // LDA 0x8000
+            0x32, 0x00, 0x80, // STA 0x8000
+            0x32, 0x01, 0x80, // STA 0x8001
+            0x3a, 0x00, 0x01, // LDA 0x0100
+            0xc3, 0x00, 0x01, // JMP 0x0100
+        ];
+        program.resize(0x0101, 0x00);
+        let mut cpu = CPU::new(&program);
+        let mut bus = Hooks::default();
+
+        while !cpu.halted() && cpu.instructions() < 10 {
+            cpu.cycle(&mut bus);
+        }
+
+        // The NOP in memory at 0x0100 is only replaced for the fetch
+        assert!(cpu.halted());
+        assert_eq!(cpu.register(Register::A), 0x00);
+        assert_eq!(bus.stored, [(0x8000, 0x5a)]);
+        assert_eq!(cpu.memory().read(0x8000), 0x00);
+        assert_eq!(cpu.memory().read(0x8001), 0x5a);
+    }
+
+    #[test]
+    fn rom_ignores_writes() {
+        let mut memory = banked();
+
+        memory.write(0x0000, 0x00);
+        memory.write_word(0x0ffe, 0x0000);
+        assert_eq!(memory.read(0x0000), 0x76);
+        assert_eq!(memory.read(0x0ffe), 0x76);
+        assert_eq!(memory.read(0x0fff), 0x76);
+        assert!(memory.is_fixed(0x0fff));
+    }
+
+    #[test]
+    fn mirrors_share_storage_with_their_source() {
+        let mut memory = banked();
+
+        memory.write(0x2010, 0xaa);
+        assert_eq!(memory.read(0x1010), 0xaa);
+        assert_eq!(memory.read(0x1f10), 0xaa);
+
+        memory.write(0x1520, 0xbb);
+        assert_eq!(memory.read(0x2020), 0xbb);
+        assert_eq!(memory.read(0x1020), 0xbb);
+    }
+
+    #[test]
+    fn unmapped_memory_reads_open_bus() {
+        let mut memory = banked();
+
+        memory.write(0xf000, 0x00);
+        memory.write(0xffff, 0x00);
+        assert_eq!(memory.read(0xf000), OPEN_BUS);
+        assert_eq!(memory.read(0xffff), OPEN_BUS);
+        assert!(
+            memory
+                .read_range(0xf000..)
+                .iter()
+                .all(|&byte| byte == OPEN_BUS)
+        );
+    }
+
+    #[test]
+    fn out_selects_banks_modulo_their_count() {
+        let program = [
+            0x3e, 0x05, // MVI A,5
+            0xd3, 0x10, // OUT 0x10
+            0x3e, 0x11, // MVI A,0x11
+            0x32, 0x00, 0x80, // STA 0x8000
+            0x3e, 0x04, // MVI A,4
+            0xd3, 0x10, // OUT 0x10
+            0x3a, 0x00, 0x80, // LDA 0x8000
+            0x47, // MOV B,A
+            0x3e, 0x01, // MVI A,1
+            0xd3, 0x10, // OUT 0x10
+            0x3a, 0x00, 0x80, // LDA 0x8000
+            0x76, // HLT
+        ];
+        let mut cpu = CPU::builder()
+            .load(0x0000, &program)
+            .load(0x8000, &[0xee])
+            .memory_map(MemoryMap::new().banked(0x8000..=0x80ff, 4, 0x10))
+            .build()
+            .unwrap();
+
+        while !cpu.halted() && cpu.instructions() < 20 {
+            cpu.cycle(&mut ());
+        }
+
+        // 5 selects bank 1, then 4 selects bank 0, which shows base memory
+        assert!(cpu.halted());
+        assert_eq!(cpu.register(Register::B), 0xee);
+        assert_eq!(cpu.register(Register::A), 0x11);
+        assert_eq!(cpu.memory().bank(0x10), Some(1));
+    }
+
+    #[test]
+    fn switching_ram_banks_keeps_the_generation() {
+        let mut memory = banked();
+        let generation = memory.generation();
+
+        memory.select_bank(0x10, 1);
+        memory.select_bank(0x11, 3);
+        assert_eq!(memory.generation(), generation);
+        assert_eq!(memory.read(0x4000), 2);
+        assert_eq!(memory.read(0x81ff), 8);
+    }
+
+    #[test]
+    fn switching_rom_out_moves_the_generation_on() {
+        let mut memory = Memory::new([0x76; MEM_SIZE]);
+        let map = MemoryMap::new()
+            .rom(0x0000..=0x00ff)
+            .banked(0x0000..=0x00ff, 2, 0x10);
+        memory.map(&map).unwrap();
+        let generation = memory.generation();
+
+        memory.select_bank(0x10, 1);
+        assert_ne!(memory.generation(), generation);
+        assert!(!memory.is_fixed(0x0000));
+
+        let generation = memory.generation();
+        memory.select_bank(0x10, 0);
+        assert_ne!(memory.generation(), generation);
+        assert!(memory.is_fixed(0x0000));
+    }
+
+    #[test]
+    fn switched_banks_are_copied_before_writes_while_shared() {
+        let mut memory = banked();
+        let clone = memory.clone();
+
+        memory.select_bank(0x10, 1);
+        memory.write(0x4000, 0xaa);
+        memory.select_bank(0x11, 0);
+        memory.write(0x8000, 0xbb);
+
+        assert_eq!(memory.read(0x4000), 0xaa);
+        assert_eq!(memory.read(0x8000), 0xbb);
+        assert_eq!(contents(&clone), contents(&banked()));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_round_trip() {
+        let memory = banked();
+        let json = serde_json::to_string(&memory).unwrap();
+        let mut loaded: Memory = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(contents(&loaded), contents(&memory));
+        assert_eq!(loaded.bank(0x10), Some(2));
+        assert_eq!(loaded.bank(0x11), Some(3));
+        assert_eq!(loaded.read(0x2001), 0x2a);
+
+        // Mirrors still share storage with their source and ROM stays fixed
+        loaded.write(0x1002, 0x55);
+        assert_eq!(loaded.read(0x2002), 0x55);
+        loaded.write(0x0000, 0x00);
+        assert_eq!(loaded.read(0x0000), 0x76);
+        assert_eq!(loaded.read(0xf000), OPEN_BUS);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_rejects_storage_outside_the_layout() {
+        let mut json: serde_json::Value = serde_json::to_value(banked()).unwrap();
+        let data = json["data"].as_array_mut().unwrap();
+        data.truncate(data.len() - PAGE_SIZE);
+
+        assert!(serde_json::from_value::<Memory>(json.clone()).is_err());
+
+        json["data"].as_array_mut().unwrap().pop();
+        assert!(serde_json::from_value::<Memory>(json).is_err());
+    }
+}
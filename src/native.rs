@@ -0,0 +1,284 @@
+use crate::instruction::OPCODES;
+use crate::memory::Memory;
+use crate::{Bus, CPU, CycleKind, MEM_SIZE, PAGE_SIZE};
+use std::marker::PhantomData;
+
+/// Code compiled ahead of time by a [`Recompiler`](crate::Recompiler).
+pub trait Compiled {
+    /// Read-only memory the code was compiled from, as runs of bytes by
+    /// address.
+    const ROM: &'static [(u16, &'static [u8])];
+
+    /// Runs the compiled block starting at the PC of `cpu`, if there is one,
+    /// until it ends or `end` T-states have elapsed since power on. Returns
+    /// false if no instruction was executed.
+    fn run<B: Bus>(cpu: &mut CPU, bus: &mut B, end: u64) -> bool;
+}
+
+/// Runs a [`CPU`] through [`Compiled`] code where it can, and through
+/// [`CPU::cycle`] elsewhere.
+///
+/// The result is the same as with [`CPU::cycle`] alone. Compiled code only
+/// runs while the memory it was compiled from is visible, read-only and
+/// unchanged, so code in RAM, behind a different memory map or bank, or which
+/// was never reached while compiling, is interpreted. As compiled code is not
+/// read through [`Bus::fetch`] or [`Bus::load`], buses which intercept reads
+/// of code should not use it.
+pub struct Native<C> {
+    /// Generation of the memory last checked against the compiled code, and
+    /// whether it matched.
+    checked: Option<(u64, bool)>,
+    compiled: PhantomData<C>,
+}
+
+impl<C: Compiled> Default for Native<C> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<C: Compiled> Native<C> {
+    /// Creates a new [`Native`] runner for the code of `C`.
+    pub fn new() -> Self {
+        Self {
+            checked: None,
+            compiled: PhantomData,
+        }
+    }
+
+    /// Runs `cpu` until it reaches cycle `until`, with `bus` as its [`Bus`].
+    pub fn run(&mut self, cpu: &mut CPU, bus: &mut impl Bus, until: u64) {
+        while cpu.cycles() < until {
+            self.cycle(cpu, bus, until);
+        }
+    }
+
+    /// Runs the compiled block at the PC of `cpu` until it ends or reaches
+    /// cycle `end`, or a single instruction through [`CPU::cycle`] if there is
+    /// no such block. Returns the number of T-states elapsed.
+    pub fn cycle(&mut self, cpu: &mut CPU, bus: &mut impl Bus, end: u64) -> u64 {
+        let start = cpu.cycles();
+
+        if !(self.matches(cpu.memory()) && C::run(cpu, bus, end)) {
+            cpu.cycle(bus);
+        }
+
+        cpu.cycles() - start
+    }
+
+    /// Returns true if `memory` holds the code of `C` in read-only memory.
+    fn matches(&mut self, memory: &Memory) -> bool {
+        let generation = memory.generation();
+
+        match self.checked {
+            Some((checked, matches)) if checked == generation => matches,
+            _ => {
+                let matches = C::ROM.iter().all(|&(start, bytes)| {
+                    let addrs = start as usize..start as usize + bytes.len();
+
+                    // Every page of the run must be fixed, its last included
+                    addrs.end <= MEM_SIZE
+                        && addrs
+                            .clone()
+                            .step_by(PAGE_SIZE)
+                            .chain(addrs.clone().last())
+                            .all(|addr| memory.is_fixed(addr as u16))
+                        && addrs
+                            .zip(bytes)
+                            .all(|(addr, &byte)| memory.read(addr as u16) == byte)
+                });
+
+                self.checked = Some((generation, matches));
+                matches
+            }
+        }
+    }
+}
+
+// Parts of instructions for code compiled by a `Recompiler`, which runs each
+// instruction as a call to `native_fetch` followed by its effect, once
+// `native_boundary` returned true. They are hidden from the documentation, as
+// only compiled code is meant to call them.
+//
+// Memory accesses run their machine cycles as `CPU::cycle` does, inserting
+// the same wait states and going through `Bus::load` and `Bus::store`. The
+// rest of an instruction is left to the accessors of the registers and flags.
+impl CPU {
+    /// Counts the instruction made of `opcode` and its operands at the PC and
+    /// the T-states it takes, and runs the machine cycles reading it. The PC
+    /// is left at the instruction, for the compiled code to move.
+    #[doc(hidden)]
+    #[inline]
+    pub fn native_fetch(&mut self, bus: &mut impl Bus, opcode: u8) {
+        let pc = self.pc;
+
+        self.instructions += 1;
+        self.cycles += OPCODES[opcode as usize].t_states as u64;
+
+        self.wait(bus, CycleKind::Fetch, pc);
+        for idx in 1..OPCODES[opcode as usize].len {
+            self.wait(bus, CycleKind::MemoryRead, pc.wrapping_add(idx as u16));
+        }
+    }
+
+    /// Takes back the T-states that conditional instruction `opcode` saves
+    /// when its condition does not hold.
+    #[doc(hidden)]
+    #[inline]
+    pub fn native_not_taken(&mut self, opcode: u8) {
+        let metadata = &OPCODES[opcode as usize];
+        self.cycles -= (metadata.t_states - metadata.t_states_not_taken) as u64;
+    }
+
+    /// Reads a byte from `addr`.
+    #[doc(hidden)]
+    #[inline]
+    pub fn native_read(&mut self, bus: &mut impl Bus, addr: u16) -> u8 {
+        self.load(bus, addr)
+    }
+
+    /// Writes `data` to `addr`.
+    #[doc(hidden)]
+    #[inline]
+    pub fn native_write(&mut self, bus: &mut impl Bus, addr: u16, data: u8) {
+        self.store(bus, addr, data);
+    }
+
+    /// Reads the little-endian word at `addr` through two machine cycles of
+    /// `kind`.
+    #[doc(hidden)]
+    #[inline]
+    pub fn native_read_word(&mut self, bus: &mut impl Bus, kind: CycleKind, addr: u16) -> u16 {
+        self.load_word(bus, kind, addr)
+    }
+
+    /// Writes the little-endian word `value` to `addr` through two machine
+    /// cycles of `kind`.
+    #[doc(hidden)]
+    #[inline]
+    pub fn native_write_word(
+        &mut self,
+        bus: &mut impl Bus,
+        kind: CycleKind,
+        addr: u16,
+        value: u16,
+    ) {
+        self.store_word(bus, kind, addr, value);
+    }
+
+    /// Pushes `value` onto the stack.
+    #[doc(hidden)]
+    #[inline]
+    pub fn native_push(&mut self, bus: &mut impl Bus, value: u16) {
+        self.push_word(bus, value);
+    }
+
+    /// Pops the word on top of the stack.
+    #[doc(hidden)]
+    #[inline]
+    pub fn native_pop(&mut self, bus: &mut impl Bus) -> u16 {
+        self.pop_word(bus)
+    }
+
+    /// Reads a byte from `port`.
+    #[doc(hidden)]
+    #[inline]
+    pub fn native_input(&mut self, bus: &mut impl Bus, port: u8) -> u8 {
+        self.input(bus, port)
+    }
+
+    /// Writes `data` to `port`, switching banks if `port` selects them.
+    #[doc(hidden)]
+    #[inline]
+    pub fn native_output(&mut self, bus: &mut impl Bus, port: u8, data: u8) {
+        self.output(bus, port, data);
+    }
+
+    /// Adds `value` and the carry, if `carry` is true, to register A, setting
+    /// the flags as ADD and ADC do.
+    #[doc(hidden)]
+    #[inline]
+    pub fn native_add(&mut self, value: u8, carry: bool) {
+        self.add(6, value, carry);
+    }
+
+    /// Subtracts `value` and the borrow, if `borrow` is true, from register A,
+    /// setting the flags as SUB and SBB do.
+    #[doc(hidden)]
+    #[inline]
+    pub fn native_sub(&mut self, value: u8, borrow: bool) {
+        self.sub(6, value, borrow);
+    }
+
+    /// ANDs `value` into register A, setting the flags as ANA does.
+    #[doc(hidden)]
+    #[inline]
+    pub fn native_and(&mut self, value: u8) {
+        self.ana(value);
+    }
+
+    /// XORs `value` into register A, setting the flags as XRA does.
+    #[doc(hidden)]
+    #[inline]
+    pub fn native_xor(&mut self, value: u8) {
+        self.xra(value);
+    }
+
+    /// ORs `value` into register A, setting the flags as ORA does.
+    #[doc(hidden)]
+    #[inline]
+    pub fn native_or(&mut self, value: u8) {
+        self.ora(value);
+    }
+
+    /// Sets the flags as subtracting `value` from register A would.
+    #[doc(hidden)]
+    #[inline]
+    pub fn native_compare(&mut self, value: u8) {
+        self.cmp(value);
+    }
+
+    /// Returns `value + 1`, setting the flags as INR does.
+    #[doc(hidden)]
+    #[inline]
+    pub fn native_increment(&mut self, value: u8) -> u8 {
+        self.inr(value)
+    }
+
+    /// Returns `value - 1`, setting the flags as DCR does.
+    #[doc(hidden)]
+    #[inline]
+    pub fn native_decrement(&mut self, value: u8) -> u8 {
+        self.dcr(value)
+    }
+
+    /// Adjusts register A as DAA does.
+    #[doc(hidden)]
+    #[inline]
+    pub fn native_daa(&mut self) {
+        self.daa();
+    }
+
+    /// Enables interrupts from after the next instruction, as EI does.
+    #[doc(hidden)]
+    #[inline]
+    pub fn native_enable_interrupts(&mut self) {
+        self.interrupt = 2;
+        self.quiet = false;
+    }
+
+    /// Disables interrupts, as DI does.
+    #[doc(hidden)]
+    #[inline]
+    pub fn native_disable_interrupts(&mut self) {
+        self.interrupt = 0;
+    }
+
+    /// Halts until an interrupt is serviced, as HLT does.
+    #[doc(hidden)]
+    #[inline]
+    pub fn native_halt(&mut self) {
+        self.halt = true;
+        self.quiet = false;
+    }
+}
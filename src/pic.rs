@@ -0,0 +1,685 @@
+/// CALL opcode supplied on the first interrupt acknowledge.
+const CALL: u8 = 0xCD;
+
+// ICW1 bits
+const IC4: u8 = 0x01;
+const SNGL: u8 = 0x02;
+const ADI: u8 = 0x04;
+const LTIM: u8 = 0x08;
+const INIT: u8 = 0x10;
+
+// ICW4 bits
+const AEOI: u8 = 0x02;
+const SFNM: u8 = 0x10;
+
+// OCW3 bits
+const OCW3: u8 = 0x08;
+const RIS: u8 = 0x01;
+const RR: u8 = 0x02;
+const POLL: u8 = 0x04;
+const SMM: u8 = 0x20;
+const ESMM: u8 = 0x40;
+
+/// Initialization command word expected next.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+enum Icw {
+    Icw2,
+    Icw3,
+    Icw4,
+}
+
+/// Interrupt acknowledge cycle expected next, after the first one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+enum Inta {
+    /// Second cycle, supplying the low byte of the address.
+    Low,
+    /// Third cycle, supplying the high byte of the address.
+    High,
+}
+
+/// An Intel 8259A programmable interrupt controller in 8080 mode.
+///
+/// The controller is programmed through two ports told apart by A0, taken
+/// from bit 0 of the port passed to [`Pic::read`] and [`Pic::write`]. Devices
+/// drive its eight request inputs through [`Pic::set_irq`].
+///
+/// While [`Pic::int`] is true the highest priority request is waiting to be
+/// acknowledged. Each interrupt acknowledge cycle then reads the next byte
+/// of a CALL to the handler of the request through [`Pic::inta`]. A
+/// [`Bus`](crate::Bus) wires these up by forwarding
+/// [`Bus::interrupt_requested`](crate::Bus::interrupt_requested) and
+/// [`Bus::acknowledge`](crate::Bus::acknowledge) to them.
+///
+/// Slaves are attached with [`Pic::cascade`], after which their INT outputs
+/// drive the request inputs of the master.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Pic {
+    icw1: u8,
+    /// High byte of the handler addresses.
+    icw2: u8,
+    /// Inputs with a slave attached.
+    icw3: u8,
+    icw4: u8,
+    expected: Option<Icw>,
+
+    /// Levels of the request inputs.
+    lines: u8,
+    /// Interrupt request register.
+    irr: u8,
+    /// In-service register.
+    isr: u8,
+    /// Interrupt mask register.
+    imr: u8,
+
+    /// Level with the lowest priority.
+    lowest: u8,
+    /// Whether to rotate priorities on automatic EOI.
+    rotate_on_aeoi: bool,
+    special_mask: bool,
+    /// Whether reads return the ISR rather than the IRR.
+    read_isr: bool,
+    /// Whether the next read is a poll.
+    poll: bool,
+
+    /// Interrupt acknowledge cycle in progress and the level acknowledged by
+    /// it, if it is not spurious.
+    acknowledging: Option<(Inta, Option<u8>)>,
+
+    slaves: Vec<(u8, Pic)>,
+}
+
+impl Default for Pic {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Pic {
+    /// Creates a new [`Pic`] with all requests unmasked, IR0 at the highest
+    /// priority and handlers 4 bytes apart from 0x0000.
+    pub fn new() -> Self {
+        Self {
+            icw1: INIT | SNGL | ADI,
+            icw2: 0,
+            icw3: 0,
+            icw4: 0,
+            expected: None,
+            lines: 0,
+            irr: 0,
+            isr: 0,
+            imr: 0,
+            lowest: 7,
+            rotate_on_aeoi: false,
+            special_mask: false,
+            read_isr: false,
+            poll: false,
+            acknowledging: None,
+            slaves: vec![],
+        }
+    }
+
+    /// Attaches `slave` to the request input `ir`. The master only routes
+    /// acknowledges to it once `ir` is marked in ICW3.
+    pub fn cascade(mut self, ir: u8, slave: Pic) -> Self {
+        let ir = ir & 0x07;
+
+        self.slaves.retain(|(idx, _)| *idx != ir);
+        self.slaves.push((ir, slave));
+        self
+    }
+
+    /// Returns the slave attached to the request input `ir`, if there is one.
+    pub fn slave_mut(&mut self, ir: u8) -> Option<&mut Pic> {
+        self.slaves
+            .iter_mut()
+            .find(|(idx, _)| *idx == ir & 0x07)
+            .map(|(_, slave)| slave)
+    }
+
+    /// Writes a command word. ICW1, OCW2 and OCW3 go to A0 = 0 while ICW2,
+    /// ICW3, ICW4 and OCW1 go to A0 = 1.
+    pub fn write(&mut self, port: u8, data: u8) {
+        if port & 0x01 == 0 {
+            if data & INIT != 0 {
+                self.icw1(data);
+            } else if data & OCW3 != 0 {
+                self.ocw3(data);
+            } else {
+                self.ocw2(data);
+            }
+
+            return;
+        }
+
+        match self.expected {
+            Some(Icw::Icw2) => {
+                self.icw2 = data;
+                self.expected = if self.icw1 & SNGL == 0 {
+                    Some(Icw::Icw3)
+                } else {
+                    self.icw4_or_done()
+                };
+            }
+            Some(Icw::Icw3) => {
+                self.icw3 = data;
+                self.expected = self.icw4_or_done();
+            }
+            Some(Icw::Icw4) => {
+                self.icw4 = data;
+                self.expected = None;
+            }
+            // OCW1
+            None => self.imr = data,
+        }
+    }
+
+    /// Reads a status register. A0 = 0 returns the IRR or ISR as selected by
+    /// OCW3, or the poll word after a poll command. A0 = 1 returns the IMR.
+    pub fn read(&mut self, port: u8) -> u8 {
+        if port & 0x01 != 0 {
+            return self.imr;
+        }
+
+        if self.poll {
+            self.poll = false;
+
+            // A poll acknowledges the request as an INTA would
+            return match self.pending() {
+                Some(level) => {
+                    self.accept(level);
+                    0x80 | level
+                }
+                None => 0,
+            };
+        }
+
+        if self.read_isr {
+            self.isr
+        } else {
+            self.requests()
+        }
+    }
+
+    /// Sets the level of the request input `ir`.
+    ///
+    /// In edge triggered mode a request is latched on a rising edge, while in
+    /// level triggered mode it lasts as long as the input is high. Either way
+    /// the input should stay high until the request is acknowledged.
+    pub fn set_irq(&mut self, ir: u8, level: bool) {
+        let bit = 1 << (ir & 0x07);
+
+        if level {
+            if self.lines & bit == 0 || self.icw1 & LTIM != 0 {
+                self.irr |= bit;
+            }
+            self.lines |= bit;
+        } else {
+            self.lines &= !bit;
+            self.irr &= !bit;
+        }
+    }
+
+    /// Returns true if the INT output is active.
+    pub fn int(&self) -> bool {
+        self.pending().is_some()
+    }
+
+    /// Performs an interrupt acknowledge cycle and returns the byte placed on
+    /// the data bus.
+    ///
+    /// The first of three cycles resolves the request to service and returns
+    /// CALL, while the next two return the address of its handler. A request
+    /// withdrawn before the first cycle is serviced as a spurious IR7.
+    pub fn inta(&mut self) -> u8 {
+        match self.acknowledging {
+            None => {
+                let level = self.pending();
+
+                if let Some(level) = level {
+                    self.accept(level);
+                }
+
+                if let Some(slave) = level.and_then(|level| self.slave(level)) {
+                    slave.inta();
+                }
+
+                self.acknowledging = Some((Inta::Low, level));
+                CALL
+            }
+            Some((Inta::Low, level)) => {
+                self.acknowledging = Some((Inta::High, level));
+
+                match level.and_then(|level| self.slave(level)) {
+                    Some(slave) => slave.inta(),
+                    None => self.address(level.unwrap_or(7)).to_le_bytes()[0],
+                }
+            }
+            Some((Inta::High, level)) => {
+                self.acknowledging = None;
+
+                let data = match level.and_then(|level| self.slave(level)) {
+                    Some(slave) => slave.inta(),
+                    None => self.address(level.unwrap_or(7)).to_le_bytes()[1],
+                };
+
+                if let Some(level) = level
+                    && self.icw4 & AEOI != 0
+                {
+                    self.isr &= !(1 << level);
+
+                    if self.rotate_on_aeoi {
+                        self.lowest = level;
+                    }
+                }
+
+                data
+            }
+        }
+    }
+
+    /// Performs all three interrupt acknowledge cycles and returns the CALL
+    /// instruction supplied by them.
+    pub fn acknowledge(&mut self) -> [u8; 3] {
+        [self.inta(), self.inta(), self.inta()]
+    }
+
+    fn icw1(&mut self, data: u8) {
+        self.icw1 = data;
+        self.expected = Some(Icw::Icw2);
+
+        if data & IC4 == 0 {
+            self.icw4 = 0;
+        }
+
+        self.irr = if data & LTIM != 0 { self.lines } else { 0 };
+        self.imr = 0;
+        self.lowest = 7;
+        self.special_mask = false;
+        self.read_isr = false;
+        self.poll = false;
+        self.acknowledging = None;
+    }
+
+    fn icw4_or_done(&self) -> Option<Icw> {
+        if self.icw1 & IC4 != 0 {
+            Some(Icw::Icw4)
+        } else {
+            None
+        }
+    }
+
+    fn ocw2(&mut self, data: u8) {
+        let level = data & 0x07;
+
+        match data >> 5 {
+            // Non-specific EOI
+            0b001 => {
+                self.eoi();
+            }
+            // Specific EOI
+            0b011 => self.isr &= !(1 << level),
+            // Rotate on non-specific EOI
+            0b101 => {
+                if let Some(level) = self.eoi() {
+                    self.lowest = level;
+                }
+            }
+            // Rotate in automatic EOI mode
+            0b100 => self.rotate_on_aeoi = true,
+            0b000 => self.rotate_on_aeoi = false,
+            // Rotate on specific EOI
+            0b111 => {
+                self.isr &= !(1 << level);
+                self.lowest = level;
+            }
+            // Set priority
+            0b110 => self.lowest = level,
+            _ => {}
+        }
+    }
+
+    fn ocw3(&mut self, data: u8) {
+        if data & ESMM != 0 {
+            self.special_mask = data & SMM != 0;
+        }
+
+        if data & RR != 0 {
+            self.read_isr = data & RIS != 0;
+        }
+
+        self.poll = data & POLL != 0;
+    }
+
+    /// Clears the highest priority level in service and returns it.
+    fn eoi(&mut self) -> Option<u8> {
+        let level = self.highest(self.isr)?;
+        self.isr &= !(1 << level);
+
+        Some(level)
+    }
+
+    /// Returns the levels requesting service, including those of slaves.
+    fn requests(&self) -> u8 {
+        self.slaves
+            .iter()
+            .filter(|(_, slave)| slave.int())
+            .fold(self.irr, |irr, (ir, _)| irr | 1 << ir)
+    }
+
+    /// Returns the level which would be serviced by the next acknowledge.
+    fn pending(&self) -> Option<u8> {
+        if self.expected.is_some() {
+            return None;
+        }
+
+        // Special mask mode lets any level not in service interrupt, lower
+        // ones included
+        if self.special_mask {
+            return self.highest(self.requests() & !self.imr & !self.isr);
+        }
+
+        let level = self.highest(self.requests() & !self.imr)?;
+        let bit = 1 << level;
+
+        // In special fully nested mode a slave may interrupt its own level
+        let nested = self.icw4 & SFNM != 0 && self.icw3 & bit != 0;
+
+        match self.highest(self.isr) {
+            Some(serving) if serving == level && nested => Some(level),
+            Some(serving) if self.priority(serving) <= self.priority(level) => None,
+            _ => Some(level),
+        }
+    }
+
+    /// Moves `level` from the IRR to the ISR.
+    fn accept(&mut self, level: u8) {
+        self.isr |= 1 << level;
+
+        if self.icw1 & LTIM == 0 {
+            self.irr &= !(1 << level);
+        }
+    }
+
+    /// Returns the slave which supplies the handler of `level`, if there is
+    /// one.
+    fn slave(&mut self, level: u8) -> Option<&mut Pic> {
+        if self.icw1 & SNGL != 0 || self.icw3 & (1 << level) == 0 {
+            return None;
+        }
+
+        self.slave_mut(level)
+    }
+
+    /// Returns the priority of `level`, where 0 is the highest.
+    fn priority(&self, level: u8) -> u8 {
+        level.wrapping_sub(self.lowest).wrapping_sub(1) & 0x07
+    }
+
+    /// Returns the highest priority level set in `levels`.
+    fn highest(&self, levels: u8) -> Option<u8> {
+        (1..=8)
+            .map(|offset| (self.lowest + offset) & 0x07)
+            .find(|level| levels & (1 << level) != 0)
+    }
+
+    /// Returns the address of the handler of `level`.
+    fn address(&self, level: u8) -> u16 {
+        let low = if self.icw1 & ADI != 0 {
+            (self.icw1 & 0xE0) | level << 2
+        } else {
+            (self.icw1 & 0xC0) | level << 3
+        };
+
+        u16::from_le_bytes([low, self.icw2])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns a single [`Pic`] with handlers 4 bytes apart from 0x2000,
+    /// initialised with `icw4` if given.
+    fn single(icw4: Option<u8>) -> Pic {
+        let mut pic = Pic::new();
+
+        pic.write(0, INIT | SNGL | ADI | u8::from(icw4.is_some()));
+        pic.write(1, 0x20);
+        if let Some(icw4) = icw4 {
+            pic.write(1, icw4);
+        }
+
+        pic
+    }
+
+    /// Returns the CALL to the handler of `level` of a [`single`] [`Pic`].
+    fn call(level: u8) -> [u8; 3] {
+        [CALL, level << 2, 0x20]
+    }
+
+    #[test]
+    fn initialisation_sequence() {
+        // Without ICW4 the next write to A0 = 1 is OCW1
+        let mut pic = single(None);
+        pic.write(1, 0xfe);
+        assert_eq!(pic.read(1), 0xfe);
+
+        // Cascade mode expects ICW3, then ICW4
+        let mut pic = Pic::new();
+        pic.write(0, INIT | IC4);
+        pic.write(1, 0x40);
+        pic.write(1, 0b0000_0100);
+        pic.write(1, 0);
+        pic.write(1, 0x0f);
+        assert_eq!((pic.icw2, pic.icw3, pic.imr), (0x40, 0b0000_0100, 0x0f));
+
+        // Handlers 8 bytes apart take bits 6 and 7 of ICW1
+        pic.set_irq(1, true);
+        assert_eq!(pic.address(1), 0x4008);
+    }
+
+    #[test]
+    fn masked_requests_wait() {
+        let mut pic = single(None);
+
+        pic.write(1, 0b0000_1000);
+        pic.set_irq(3, true);
+        assert!(!pic.int());
+
+        pic.write(1, 0);
+        assert!(pic.int());
+        assert_eq!(pic.acknowledge(), call(3));
+    }
+
+    #[test]
+    fn fully_nested_priorities() {
+        let mut pic = single(None);
+
+        pic.set_irq(3, true);
+        assert_eq!(pic.acknowledge(), call(3));
+
+        // A lower priority request waits for the EOI, a higher one does not
+        pic.set_irq(5, true);
+        assert!(!pic.int());
+        pic.set_irq(1, true);
+        assert_eq!(pic.acknowledge(), call(1));
+        assert_eq!(pic.isr, 0b0000_1010);
+
+        // Non-specific EOIs clear the highest level in service first
+        pic.write(0, 0x20);
+        assert_eq!(pic.isr, 0b0000_1000);
+        assert!(!pic.int());
+        pic.write(0, 0x20);
+        assert_eq!(pic.acknowledge(), call(5));
+
+        // Specific EOI
+        pic.write(0, 0x60 | 5);
+        assert_eq!(pic.isr, 0);
+    }
+
+    #[test]
+    fn edge_and_level_triggering() {
+        // Edge triggered requests need a new rising edge
+        let mut pic = single(None);
+        pic.set_irq(2, true);
+        pic.acknowledge();
+        pic.write(0, 0x20);
+        assert!(!pic.int());
+
+        // Level triggered requests last while the input is high
+        let mut pic = Pic::new();
+        pic.write(0, INIT | SNGL | ADI | LTIM);
+        pic.write(1, 0x20);
+        pic.set_irq(2, true);
+        pic.acknowledge();
+        pic.write(0, 0x20);
+        assert!(pic.int());
+        pic.set_irq(2, false);
+        assert!(!pic.int());
+    }
+
+    #[test]
+    fn automatic_eoi() {
+        let mut pic = single(Some(AEOI));
+
+        pic.set_irq(4, true);
+        assert_eq!(pic.acknowledge(), call(4));
+        assert_eq!(pic.isr, 0);
+
+        // Rotation in AEOI mode makes the serviced level the lowest
+        pic.write(0, 0x80);
+        pic.set_irq(4, false);
+        pic.set_irq(4, true);
+        pic.acknowledge();
+        assert_eq!(pic.lowest, 4);
+        pic.set_irq(4, false);
+        pic.set_irq(4, true);
+        pic.set_irq(6, true);
+        assert_eq!(pic.acknowledge(), call(6));
+    }
+
+    #[test]
+    fn rotation() {
+        let mut pic = single(None);
+
+        // Rotate on non-specific EOI
+        pic.set_irq(2, true);
+        pic.acknowledge();
+        pic.write(0, 0xa0);
+        assert_eq!(pic.lowest, 2);
+        pic.set_irq(1, true);
+        pic.set_irq(3, true);
+        assert_eq!(pic.acknowledge(), call(3));
+        pic.write(0, 0x20);
+
+        // Rotate on specific EOI
+        pic.write(0, 0xe0 | 3);
+        assert_eq!(pic.lowest, 3);
+
+        // Set priority
+        pic.write(0, 0xc0);
+        assert_eq!(pic.acknowledge(), call(1));
+    }
+
+    #[test]
+    fn special_mask_mode_services_lower_levels() {
+        let mut pic = single(None);
+
+        pic.set_irq(5, true);
+        pic.acknowledge();
+
+        // A new request from the level in service does not block lower ones
+        pic.write(0, OCW3 | ESMM | SMM);
+        pic.set_irq(5, false);
+        pic.set_irq(5, true);
+        assert!(!pic.int());
+        pic.set_irq(6, true);
+        assert!(pic.int());
+        assert_eq!(pic.acknowledge(), call(6));
+        assert_eq!(pic.isr, 0b0110_0000);
+
+        pic.write(0, OCW3 | ESMM);
+        pic.set_irq(6, true);
+        assert!(!pic.int());
+    }
+
+    #[test]
+    fn poll_and_status_reads() {
+        let mut pic = single(None);
+
+        pic.set_irq(6, true);
+        assert_eq!(pic.read(0), 0b0100_0000);
+
+        pic.write(0, OCW3 | POLL);
+        assert_eq!(pic.read(0), 0x86);
+        assert_eq!(pic.read(0), 0);
+
+        pic.write(0, OCW3 | RR | RIS);
+        assert_eq!(pic.read(0), 0b0100_0000);
+
+        pic.write(0, OCW3 | POLL);
+        assert_eq!(pic.read(0), 0);
+    }
+
+    #[test]
+    fn spurious_requests_call_ir7() {
+        let mut pic = single(None);
+
+        pic.set_irq(3, true);
+        assert!(pic.int());
+        pic.set_irq(3, false);
+
+        assert_eq!(pic.acknowledge(), call(7));
+        assert_eq!(pic.isr, 0);
+    }
+
+    /// Returns a master with a slave on IR2, both initialised with `icw4`.
+    fn cascaded(icw4: u8) -> Pic {
+        let mut slave = Pic::new();
+        slave.write(0, INIT | ADI | IC4);
+        slave.write(1, 0x30);
+        slave.write(1, 2);
+        slave.write(1, icw4);
+
+        let mut master = Pic::new().cascade(2, slave);
+        master.write(0, INIT | ADI | IC4);
+        master.write(1, 0x20);
+        master.write(1, 0b0000_0100);
+        master.write(1, icw4);
+
+        master
+    }
+
+    #[test]
+    fn cascaded_slaves_supply_their_handlers() {
+        let mut pic = cascaded(0);
+
+        pic.slave_mut(2).unwrap().set_irq(1, true);
+        assert!(pic.int());
+        assert_eq!(pic.acknowledge(), [CALL, 1 << 2, 0x30]);
+        assert_eq!(pic.isr, 0b0000_0100);
+
+        // Higher levels of the same slave wait without special fully nested
+        // mode
+        pic.slave_mut(2).unwrap().set_irq(0, true);
+        assert!(!pic.int());
+    }
+
+    #[test]
+    fn special_fully_nested_mode() {
+        let mut pic = cascaded(SFNM);
+
+        pic.slave_mut(2).unwrap().set_irq(1, true);
+        pic.acknowledge();
+
+        pic.slave_mut(2).unwrap().set_irq(0, true);
+        assert_eq!(pic.acknowledge(), [CALL, 0, 0x30]);
+
+        // Lower levels of the slave still wait
+        pic.slave_mut(2).unwrap().set_irq(5, true);
+        assert!(!pic.int());
+    }
+}
@@ -0,0 +1,478 @@
+use crate::instruction::{Condition, Instruction, OPCODES, Operand, decode};
+use crate::memory::Memory;
+use crate::{CPU, MEM_SIZE};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Number of ROM bytes per line of the emitted source.
+const BYTES_PER_LINE: usize = 16;
+
+/// An instruction traced in read-only memory, with the bytes it was decoded
+/// from.
+type Traced = ([u8; 3], Instruction);
+
+/// Compiles the code in the read-only memory of a [`CPU`] ahead of time to
+/// Rust source, which runs it through a [`Native`](crate::Native) runner.
+///
+/// Each instruction is emitted as Rust doing what it does, with its operands
+/// and the address of the next one written out, so that nothing is fetched or
+/// decoded at run time.
+///
+/// Code is traced from a set of entry points, following every branch, call
+/// and return address, and split into straight-line blocks. Code only reached
+/// through PCHL, or which lies outside read-only memory, is left to the
+/// interpreter.
+pub struct Recompiler {
+    memory: Memory,
+    entries: Vec<u16>,
+}
+
+impl Recompiler {
+    /// Creates a new [`Recompiler`] for the read-only memory of `cpu`, tracing
+    /// code from the reset vector at 0 and the vectors of the RST
+    /// instructions.
+    pub fn new(cpu: &CPU) -> Self {
+        Self {
+            memory: cpu.memory().clone(),
+            entries: (0..8).map(|n| n * 8).collect(),
+        }
+    }
+
+    /// Traces code from `addr` as well, e.g. the target of a PCHL.
+    pub fn entry(mut self, addr: u16) -> Self {
+        self.entries.push(addr);
+        self
+    }
+
+    /// Returns Rust source implementing [`Compiled`](crate::Compiled) for a
+    /// unit struct named `name`, which runs the code reachable from the entry
+    /// points.
+    pub fn compile(&self, name: &str) -> String {
+        let (code, leaders) = self.trace();
+        let mut source = String::from(
+            "// Compiled by intel8080::Recompiler. Do not edit.\n\n\
+             #[allow(unused_imports)]\n\
+             use intel8080::{Bus, CPU, Compiled, CycleKind, Flag, Register::*, RegisterPair::*};\n\n",
+        );
+
+        source.push_str(&format!("pub struct {name};\n\n"));
+        source.push_str(&format!("impl Compiled for {name} {{\n"));
+        source.push_str("    const ROM: &'static [(u16, &'static [u8])] = &[\n");
+        for (start, bytes) in runs(&code) {
+            source.push_str(&format!("        (0x{start:04x}, &[\n"));
+            for line in bytes.chunks(BYTES_PER_LINE) {
+                let line: Vec<_> = line.iter().map(|byte| format!("0x{byte:02x},")).collect();
+                source.push_str(&format!("            {}\n", line.join(" ")));
+            }
+            source.push_str("        ]),\n");
+        }
+        source.push_str("    ];\n\n");
+
+        source.push_str("    fn run<B: Bus>(cpu: &mut CPU, bus: &mut B, end: u64) -> bool {\n");
+        source.push_str("        match cpu.pc() {\n");
+        for leader in &leaders {
+            source.push_str(&format!(
+                "            0x{leader:04x} => block_{leader:04x}(cpu, bus, end),\n"
+            ));
+        }
+        source.push_str("            _ => false,\n        }\n    }\n}\n");
+
+        for &leader in &leaders {
+            source.push_str(&block(&code, &leaders, leader));
+        }
+
+        source
+    }
+
+    /// Returns the instruction at `addr`, if it lies wholly in read-only
+    /// memory.
+    fn decode(&self, addr: u16) -> Option<Traced> {
+        let opcode = self.memory.read(addr);
+        let len = OPCODES[opcode as usize].len as usize;
+
+        if addr as usize + len > MEM_SIZE
+            || !(0..len).all(|idx| self.memory.is_fixed(addr + idx as u16))
+        {
+            return None;
+        }
+
+        let mut bytes = [opcode, 0, 0];
+        for (idx, byte) in bytes.iter_mut().enumerate().take(len).skip(1) {
+            *byte = self.memory.read(addr + idx as u16);
+        }
+
+        Some((bytes, decode(bytes)))
+    }
+
+    /// Traces the code reachable from the entry points. Returns it by address
+    /// along with the addresses blocks start at, which are those control may
+    /// be transferred to.
+    fn trace(&self) -> (BTreeMap<u16, Traced>, BTreeSet<u16>) {
+        let mut code = BTreeMap::new();
+        let mut leaders = BTreeSet::new();
+        let mut pending = self.entries.clone();
+
+        while let Some(leader) = pending.pop() {
+            if !leaders.insert(leader) {
+                continue;
+            }
+
+            let mut addr = leader;
+            while !code.contains_key(&addr) {
+                let Some((bytes, instruction)) = self.decode(addr) else {
+                    break;
+                };
+                code.insert(addr, (bytes, instruction));
+
+                let next = addr.wrapping_add(OPCODES[bytes[0] as usize].len as u16);
+                match instruction {
+                    Instruction::Jmp(target) => pending.push(target),
+                    Instruction::Jcc(_, target)
+                    | Instruction::Call(target)
+                    | Instruction::Ccc(_, target) => pending.extend([target, next]),
+                    Instruction::Rst(n) => pending.extend([(n as u16 & 0x07) * 8, next]),
+                    // Execution resumes after HLT once an interrupt returns
+                    Instruction::Rcc(_) | Instruction::Out(_) | Instruction::Hlt => {
+                        pending.push(next)
+                    }
+                    Instruction::Ret | Instruction::Pchl => {}
+                    _ => {
+                        addr = next;
+                        continue;
+                    }
+                }
+
+                break;
+            }
+        }
+
+        leaders.retain(|leader| code.contains_key(leader));
+        (code, leaders)
+    }
+}
+
+/// Returns true if `instruction` ends a block of compiled code. Besides
+/// transfers of control, OUT may switch banks and so change the code which
+/// follows.
+fn ends(instruction: Instruction) -> bool {
+    matches!(
+        instruction,
+        Instruction::Jmp(_)
+            | Instruction::Jcc(..)
+            | Instruction::Call(_)
+            | Instruction::Ccc(..)
+            | Instruction::Ret
+            | Instruction::Rcc(_)
+            | Instruction::Rst(_)
+            | Instruction::Pchl
+            | Instruction::Hlt
+            | Instruction::Out(_)
+    )
+}
+
+/// Returns the source of the block starting at `leader`, which runs up to the
+/// first instruction ending it, the next block or untraced code.
+fn block(code: &BTreeMap<u16, Traced>, leaders: &BTreeSet<u16>, leader: u16) -> String {
+    let mut source = format!(
+        "\nfn block_{leader:04x}<B: Bus>(cpu: &mut CPU, bus: &mut B, end: u64) -> bool {{\n"
+    );
+
+    let mut addr = leader;
+    while let Some(&(bytes, instruction)) = code.get(&addr) {
+        // Nothing has run yet if the first instruction is left to the
+        // interpreter
+        let ran = addr != leader;
+        source.push_str(&format!(
+            "    if !cpu.native_boundary(bus, end) {{\n        return {ran};\n    }}\n"
+        ));
+        source.push_str(&format!("    // 0x{addr:04x} {instruction}\n"));
+        source.push_str(&format!("    cpu.native_fetch(bus, 0x{:02x});\n", bytes[0]));
+
+        let next = addr.wrapping_add(OPCODES[bytes[0] as usize].len as u16);
+        for line in statements(instruction, bytes[0], next) {
+            source.push_str(&format!("    {line}\n"));
+        }
+
+        addr = next;
+        if ends(instruction) || leaders.contains(&addr) {
+            break;
+        }
+    }
+
+    source.push_str("    true\n}\n");
+    source
+}
+
+/// Returns the statements running `instruction`, made of `opcode` and its
+/// operands, once it has been fetched and up to moving the PC past it, or to
+/// where it jumps. `next` is the address of the instruction after it.
+fn statements(instruction: Instruction, opcode: u8, next: u16) -> Vec<String> {
+    let mut lines = vec![];
+    let mut to_next = true;
+
+    match instruction {
+        Instruction::Nop => {}
+        Instruction::Lxi(pair, value) => {
+            lines.push(format!("cpu.set_register_pair({pair:?}, 0x{value:04x});"))
+        }
+        Instruction::Stax(pair) => lines.push(format!(
+            "cpu.native_write(bus, cpu.register_pair({pair:?}), cpu.register(A));"
+        )),
+        Instruction::Ldax(pair) => {
+            lines.push(format!(
+                "let data = cpu.native_read(bus, cpu.register_pair({pair:?}));"
+            ));
+            lines.push("cpu.set_register(A, data);".into());
+        }
+        Instruction::Shld(addr) => lines.push(format!(
+            "cpu.native_write_word(bus, CycleKind::MemoryWrite, 0x{addr:04x}, cpu.register_pair(HL));"
+        )),
+        Instruction::Lhld(addr) => {
+            lines.push(format!(
+                "let data = cpu.native_read_word(bus, CycleKind::MemoryRead, 0x{addr:04x});"
+            ));
+            lines.push("cpu.set_register_pair(HL, data);".into());
+        }
+        Instruction::Sta(addr) => {
+            lines.push(format!("cpu.native_write(bus, 0x{addr:04x}, cpu.register(A));"))
+        }
+        Instruction::Lda(addr) => {
+            lines.push(format!("let data = cpu.native_read(bus, 0x{addr:04x});"));
+            lines.push("cpu.set_register(A, data);".into());
+        }
+        Instruction::Inx(pair) => lines.push(format!(
+            "cpu.set_register_pair({pair:?}, cpu.register_pair({pair:?}).wrapping_add(1));"
+        )),
+        Instruction::Dcx(pair) => lines.push(format!(
+            "cpu.set_register_pair({pair:?}, cpu.register_pair({pair:?}).wrapping_sub(1));"
+        )),
+        Instruction::Dad(pair) => {
+            lines.push(format!(
+                "let (hl, carry) = cpu.register_pair(HL).overflowing_add(cpu.register_pair({pair:?}));"
+            ));
+            lines.push("cpu.set_register_pair(HL, hl);".into());
+            lines.push("cpu.set_flag(Flag::Carry, carry);".into());
+        }
+        Instruction::Inr(operand) => update(&mut lines, operand, "native_increment"),
+        Instruction::Dcr(operand) => update(&mut lines, operand, "native_decrement"),
+        Instruction::Mvi(operand, data) => write(&mut lines, operand, &format!("0x{data:02x}")),
+        Instruction::Rlc => {
+            lines.push("let a = cpu.register(A);".into());
+            lines.push("cpu.set_register(A, a.rotate_left(1));".into());
+            lines.push("cpu.set_flag(Flag::Carry, a & 0x80 != 0);".into());
+        }
+        Instruction::Rrc => {
+            lines.push("let a = cpu.register(A);".into());
+            lines.push("cpu.set_register(A, a.rotate_right(1));".into());
+            lines.push("cpu.set_flag(Flag::Carry, a & 0x01 != 0);".into());
+        }
+        Instruction::Ral => {
+            lines.push("let a = cpu.register(A);".into());
+            lines.push("cpu.set_register(A, (a << 1) | u8::from(cpu.flag(Flag::Carry)));".into());
+            lines.push("cpu.set_flag(Flag::Carry, a & 0x80 != 0);".into());
+        }
+        Instruction::Rar => {
+            lines.push("let a = cpu.register(A);".into());
+            lines.push("cpu.set_register(A, (a >> 1) | (u8::from(cpu.flag(Flag::Carry)) << 7));".into());
+            lines.push("cpu.set_flag(Flag::Carry, a & 0x01 != 0);".into());
+        }
+        Instruction::Daa => lines.push("cpu.native_daa();".into()),
+        Instruction::Cma => lines.push("cpu.set_register(A, !cpu.register(A));".into()),
+        Instruction::Stc => lines.push("cpu.set_flag(Flag::Carry, true);".into()),
+        Instruction::Cmc => lines.push("cpu.set_flag(Flag::Carry, !cpu.flag(Flag::Carry));".into()),
+        Instruction::Mov(to, from) => {
+            let value = read(&mut lines, from);
+            write(&mut lines, to, &value);
+        }
+        Instruction::Hlt => lines.push("cpu.native_halt();".into()),
+        Instruction::Add(operand) => accumulate(&mut lines, operand, "native_add", Some("false")),
+        Instruction::Adc(operand) => accumulate(&mut lines, operand, "native_add", Some(CARRY)),
+        Instruction::Sub(operand) => accumulate(&mut lines, operand, "native_sub", Some("false")),
+        Instruction::Sbb(operand) => accumulate(&mut lines, operand, "native_sub", Some(CARRY)),
+        Instruction::Ana(operand) => accumulate(&mut lines, operand, "native_and", None),
+        Instruction::Xra(operand) => accumulate(&mut lines, operand, "native_xor", None),
+        Instruction::Ora(operand) => accumulate(&mut lines, operand, "native_or", None),
+        Instruction::Cmp(operand) => accumulate(&mut lines, operand, "native_compare", None),
+        Instruction::Adi(data) => lines.push(format!("cpu.native_add(0x{data:02x}, false);")),
+        Instruction::Aci(data) => lines.push(format!("cpu.native_add(0x{data:02x}, {CARRY});")),
+        Instruction::Sui(data) => lines.push(format!("cpu.native_sub(0x{data:02x}, false);")),
+        Instruction::Sbi(data) => lines.push(format!("cpu.native_sub(0x{data:02x}, {CARRY});")),
+        Instruction::Ani(data) => lines.push(format!("cpu.native_and(0x{data:02x});")),
+        Instruction::Xri(data) => lines.push(format!("cpu.native_xor(0x{data:02x});")),
+        Instruction::Ori(data) => lines.push(format!("cpu.native_or(0x{data:02x});")),
+        Instruction::Cpi(data) => lines.push(format!("cpu.native_compare(0x{data:02x});")),
+        Instruction::Ret => {
+            lines.push("let pc = cpu.native_pop(bus);".into());
+            lines.push("cpu.set_pc(pc);".into());
+            to_next = false;
+        }
+        Instruction::Rcc(condition) => {
+            lines.push(format!("if {} {{", holds(condition)));
+            lines.push("    let pc = cpu.native_pop(bus);".into());
+            lines.push("    cpu.set_pc(pc);".into());
+            lines.push("} else {".into());
+            lines.push(format!("    cpu.native_not_taken(0x{opcode:02x});"));
+            lines.push(format!("    cpu.set_pc(0x{next:04x});"));
+            lines.push("}".into());
+            to_next = false;
+        }
+        Instruction::Jmp(addr) => {
+            lines.push(format!("cpu.set_pc(0x{addr:04x});"));
+            to_next = false;
+        }
+        Instruction::Jcc(condition, addr) => {
+            lines.push(format!(
+                "cpu.set_pc(if {} {{ 0x{addr:04x} }} else {{ 0x{next:04x} }});",
+                holds(condition)
+            ));
+            to_next = false;
+        }
+        Instruction::Call(addr) => {
+            lines.push(format!("cpu.native_push(bus, 0x{next:04x});"));
+            lines.push(format!("cpu.set_pc(0x{addr:04x});"));
+            to_next = false;
+        }
+        Instruction::Ccc(condition, addr) => {
+            lines.push(format!("if {} {{", holds(condition)));
+            lines.push(format!("    cpu.native_push(bus, 0x{next:04x});"));
+            lines.push(format!("    cpu.set_pc(0x{addr:04x});"));
+            lines.push("} else {".into());
+            lines.push(format!("    cpu.native_not_taken(0x{opcode:02x});"));
+            lines.push(format!("    cpu.set_pc(0x{next:04x});"));
+            lines.push("}".into());
+            to_next = false;
+        }
+        Instruction::Rst(n) => {
+            lines.push(format!("cpu.native_push(bus, 0x{next:04x});"));
+            lines.push(format!("cpu.set_pc(0x{:04x});", (n as u16 & 0x07) * 8));
+            to_next = false;
+        }
+        Instruction::Pop(pair) => {
+            lines.push("let value = cpu.native_pop(bus);".into());
+            lines.push(format!("cpu.set_register_pair({pair:?}, value);"));
+        }
+        Instruction::Push(pair) => {
+            lines.push(format!("cpu.native_push(bus, cpu.register_pair({pair:?}));"))
+        }
+        Instruction::Out(port) => {
+            lines.push(format!("cpu.native_output(bus, 0x{port:02x}, cpu.register(A));"))
+        }
+        Instruction::In(port) => {
+            lines.push(format!("let data = cpu.native_input(bus, 0x{port:02x});"));
+            lines.push("cpu.set_register(A, data);".into());
+        }
+        Instruction::Xthl => {
+            lines.push("let sp = cpu.sp();".into());
+            lines.push("let data = cpu.native_read_word(bus, CycleKind::StackRead, sp);".into());
+            lines.push(
+                "cpu.native_write_word(bus, CycleKind::StackWrite, sp, cpu.register_pair(HL));"
+                    .into(),
+            );
+            lines.push("cpu.set_register_pair(HL, data);".into());
+        }
+        Instruction::Pchl => {
+            lines.push("cpu.set_pc(cpu.register_pair(HL));".into());
+            to_next = false;
+        }
+        Instruction::Xchg => {
+            lines.push("let de = cpu.register_pair(DE);".into());
+            lines.push("cpu.set_register_pair(DE, cpu.register_pair(HL));".into());
+            lines.push("cpu.set_register_pair(HL, de);".into());
+        }
+        Instruction::Di => lines.push("cpu.native_disable_interrupts();".into()),
+        Instruction::Sphl => lines.push("cpu.set_sp(cpu.register_pair(HL));".into()),
+        Instruction::Ei => lines.push("cpu.native_enable_interrupts();".into()),
+    }
+
+    if to_next {
+        lines.push(format!("cpu.set_pc(0x{next:04x});"));
+    }
+
+    lines
+}
+
+/// The carry flag, as an expression of the emitted source.
+const CARRY: &str = "cpu.flag(Flag::Carry)";
+
+/// Returns the expression of the emitted source which is true if `condition`
+/// holds.
+fn holds(condition: Condition) -> &'static str {
+    match condition {
+        Condition::NotZero => "!cpu.flag(Flag::Zero)",
+        Condition::Zero => "cpu.flag(Flag::Zero)",
+        Condition::NoCarry => "!cpu.flag(Flag::Carry)",
+        Condition::Carry => "cpu.flag(Flag::Carry)",
+        Condition::ParityOdd => "!cpu.flag(Flag::Parity)",
+        Condition::ParityEven => "cpu.flag(Flag::Parity)",
+        Condition::Plus => "!cpu.flag(Flag::Sign)",
+        Condition::Minus => "cpu.flag(Flag::Sign)",
+    }
+}
+
+/// Returns the expression of the emitted source reading `operand`, pushing
+/// the statements it takes onto `lines`.
+fn read(lines: &mut Vec<String>, operand: Operand) -> String {
+    match operand {
+        Operand::Register(reg) => format!("cpu.register({reg:?})"),
+        Operand::Memory => {
+            lines.push("let data = cpu.native_read(bus, cpu.register_pair(HL));".into());
+            "data".into()
+        }
+    }
+}
+
+/// Pushes the statement writing `value`, an expression of the emitted source,
+/// to `operand` onto `lines`.
+fn write(lines: &mut Vec<String>, operand: Operand, value: &str) {
+    lines.push(match operand {
+        Operand::Register(reg) => format!("cpu.set_register({reg:?}, {value});"),
+        Operand::Memory => format!("cpu.native_write(bus, cpu.register_pair(HL), {value});"),
+    });
+}
+
+/// Pushes the statements replacing `operand` by the result of `method` on it
+/// onto `lines`, as done by INR and DCR. Memory is read and written at the
+/// same address.
+fn update(lines: &mut Vec<String>, operand: Operand, method: &str) {
+    match operand {
+        Operand::Register(reg) => {
+            lines.push(format!("let value = cpu.{method}(cpu.register({reg:?}));"));
+            lines.push(format!("cpu.set_register({reg:?}, value);"));
+        }
+        Operand::Memory => {
+            lines.push("let addr = cpu.register_pair(HL);".into());
+            lines.push("let value = cpu.native_read(bus, addr);".into());
+            lines.push(format!("let value = cpu.{method}(value);"));
+            lines.push("cpu.native_write(bus, addr, value);".into());
+        }
+    }
+}
+
+/// Pushes the statements running `method` on register A and `operand` onto
+/// `lines`, with `carry` as the carry or borrow it takes, if any.
+fn accumulate(lines: &mut Vec<String>, operand: Operand, method: &str, carry: Option<&str>) {
+    let value = read(lines, operand);
+    lines.push(match carry {
+        Some(carry) => format!("cpu.{method}({value}, {carry});"),
+        None => format!("cpu.{method}({value});"),
+    });
+}
+
+/// Returns the bytes of `code` as runs of consecutive addresses.
+fn runs(code: &BTreeMap<u16, Traced>) -> Vec<(u16, Vec<u8>)> {
+    let mut runs: Vec<(u16, Vec<u8>)> = vec![];
+
+    for (&addr, (bytes, _)) in code {
+        let len = OPCODES[bytes[0] as usize].len as usize;
+
+        match runs.last_mut() {
+            Some((start, run)) if *start as usize + run.len() >= addr as usize => {
+                // Instructions traced at overlapping addresses share bytes
+                let skip = *start as usize + run.len() - addr as usize;
+                run.extend(bytes.iter().take(len).skip(skip));
+            }
+            _ => runs.push((addr, bytes[..len].to_vec())),
+        }
+    }
+
+    runs
+}
@@ -0,0 +1,183 @@
+use crate::CPU;
+use crate::alu::Alu;
+
+/// An 8-bit register of the [`CPU`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Register {
+    B,
+    C,
+    D,
+    E,
+    H,
+    L,
+    /// Accumulator
+    A,
+}
+
+/// A pair of registers addressed as a 16-bit value, with the first register
+/// of the pair in the high byte.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum RegisterPair {
+    BC,
+    DE,
+    HL,
+    /// Stack pointer
+    SP,
+    /// Program status word, the accumulator followed by the flags
+    PSW,
+}
+
+/// A condition flag of the [`CPU`], valued by its bit in the flag register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Flag {
+    Sign = 0b1000_0000,
+    Zero = 0b0100_0000,
+    AuxCarry = 0b0001_0000,
+    Parity = 0b0000_0100,
+    Carry = 0b0000_0001,
+}
+
+/// The flag register of the [`CPU`] split into its flags.
+///
+/// Bits 1, 3 and 5 are not flags. They always read as 1, 0 and 0.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Flags {
+    pub sign: bool,
+    pub zero: bool,
+    pub aux_carry: bool,
+    pub parity: bool,
+    pub carry: bool,
+}
+
+impl Flags {
+    /// Splits a flag register value into its flags, ignoring bits 1, 3 and 5.
+    pub fn from_bits(bits: u8) -> Self {
+        let set = |flag: Flag| bits & flag as u8 != 0;
+
+        Self {
+            sign: set(Flag::Sign),
+            zero: set(Flag::Zero),
+            aux_carry: set(Flag::AuxCarry),
+            parity: set(Flag::Parity),
+            carry: set(Flag::Carry),
+        }
+    }
+
+    /// Returns the value of the flag register, in order S,Z,0,A,0,P,1,C.
+    pub fn bits(&self) -> u8 {
+        let bit = |set: bool, flag: Flag| if set { flag as u8 } else { 0 };
+
+        bit(self.sign, Flag::Sign)
+            | bit(self.zero, Flag::Zero)
+            | bit(self.aux_carry, Flag::AuxCarry)
+            | bit(self.parity, Flag::Parity)
+            | bit(self.carry, Flag::Carry)
+            | 0b0000_0010
+    }
+}
+
+impl CPU {
+    /// Returns the content of the specified register.
+    #[inline]
+    pub fn register(&self, reg: Register) -> u8 {
+        self.registers[reg as usize]
+    }
+
+    /// Sets the content of the specified register.
+    #[inline]
+    pub fn set_register(&mut self, reg: Register, value: u8) {
+        self.registers[reg as usize] = value;
+    }
+
+    /// Returns the content of the specified register pair.
+    #[inline]
+    pub fn register_pair(&self, pair: RegisterPair) -> u16 {
+        match pair {
+            RegisterPair::SP => self.sp(),
+            RegisterPair::PSW => u16::from_be_bytes([self.register(Register::A), self.flag_bits()]),
+            _ => self.pair(pair),
+        }
+    }
+
+    /// Sets the content of the specified register pair. Bits 1, 3 and 5 of
+    /// the flags keep their fixed values when setting [`RegisterPair::PSW`].
+    #[inline]
+    pub fn set_register_pair(&mut self, pair: RegisterPair, value: u16) {
+        match pair {
+            RegisterPair::SP => self.set_sp(value),
+            RegisterPair::PSW => {
+                let [a, flags] = value.to_be_bytes();
+                self.set_register(Register::A, a);
+                self.set_flags(Flags::from_bits(flags));
+            }
+            _ => self.set_pair(pair, value),
+        }
+    }
+
+    /// Returns true if the specified flag is set.
+    #[inline]
+    pub fn flag(&self, flag: Flag) -> bool {
+        self.alu.flag(flag, self.flag)
+    }
+
+    /// Sets or clears the specified flag.
+    pub fn set_flag(&mut self, flag: Flag, set: bool) {
+        self.settle_flags();
+
+        if set {
+            self.flag |= flag as u8;
+        } else {
+            self.flag &= !(flag as u8);
+        }
+    }
+
+    /// Returns the flag register split into its flags.
+    pub fn flags(&self) -> Flags {
+        Flags::from_bits(self.flag_bits())
+    }
+
+    /// Sets every flag at once.
+    pub fn set_flags(&mut self, flags: Flags) {
+        self.flag = flags.bits();
+        self.alu = Alu::default();
+    }
+
+    /// Returns the program counter.
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+
+    /// Sets the program counter.
+    pub fn set_pc(&mut self, pc: u16) {
+        self.pc = pc;
+    }
+
+    /// Returns the stack pointer.
+    pub fn sp(&self) -> u16 {
+        self.sp
+    }
+
+    /// Sets the stack pointer.
+    pub fn set_sp(&mut self, sp: u16) {
+        self.sp = sp;
+    }
+
+    /// Returns BC, DE or HL. Their registers lie in order, so they are
+    /// indexed rather than matched on, which spares the interpreter a jump.
+    #[inline]
+    fn pair(&self, pair: RegisterPair) -> u16 {
+        let high = pair as usize * 2;
+        u16::from_be_bytes([self.registers[high], self.registers[high + 1]])
+    }
+
+    /// Sets BC, DE or HL, like [`CPU::pair`].
+    #[inline]
+    fn set_pair(&mut self, pair: RegisterPair, value: u16) {
+        let high = pair as usize * 2;
+        [self.registers[high], self.registers[high + 1]] = value.to_be_bytes();
+    }
+}
@@ -0,0 +1,778 @@
+use crate::instruction::OPCODES;
+use crate::state::Reader;
+use crate::{Bus, CPU, CycleKind, Device, Scheduler, StateError};
+
+/// Identifies a recording.
+const MAGIC: [u8; 4] = *b"8rec";
+/// Version of the recording format written by [`Recording::save`].
+const VERSION: u16 = 1;
+
+/// A value returned by a [`Bus`] to the [`CPU`], or an interrupt injected
+/// into it through a [`Recorder`].
+///
+/// Hooks are only logged when they change the outcome, so memory accesses
+/// left to the [`CPU`]'s own memory or without wait states are not.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Input {
+    /// [`Bus::read`] returned `data` from `port`.
+    Read { port: u8, data: u8 },
+    /// [`Bus::fetch`] supplied the opcode at `addr`.
+    Fetch { addr: u16, data: u8 },
+    /// [`Bus::load`] supplied the byte at `addr`.
+    Load { addr: u16, data: u8 },
+    /// [`Bus::store`] handled the write of `data` to `addr`.
+    Store { addr: u16, data: u8 },
+    /// [`Bus::wait_states`] inserted `count` wait states at `addr`.
+    WaitStates { addr: u16, count: u8 },
+    /// [`Bus::interrupt_requested`] held the INT input active.
+    Interrupt,
+    /// [`Bus::acknowledge`] placed `data` on the data bus.
+    Acknowledge(u8),
+    /// [`CPU::interrupt`] supplied the instruction made of the first bytes of
+    /// the array.
+    Interrupted([u8; 3]),
+    /// [`CPU::assert_interrupt`] held INT active, supplying the instruction
+    /// made of the first bytes of the array.
+    Asserted([u8; 3]),
+    /// [`CPU::deassert_interrupt`] released INT.
+    Deasserted,
+}
+
+impl Input {
+    /// Returns true if the input was injected into the [`CPU`] rather than
+    /// returned by a [`Bus`].
+    fn injected(self) -> bool {
+        matches!(
+            self,
+            Self::Interrupted(_) | Self::Asserted(_) | Self::Deasserted
+        )
+    }
+}
+
+/// Returns the instruction packed into `bytes`.
+fn instruction(bytes: &[u8; 3]) -> &[u8] {
+    &bytes[..OPCODES[bytes[0] as usize].len as usize]
+}
+
+/// An [`Input`] along with when it was made.
+///
+/// Inputs are matched to the calls of a replay by cycle rather than by
+/// counting calls, as [`Native`](crate::Native) code skips the [`Bus`] hooks
+/// which would leave the outcome unchanged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Recorded {
+    /// Cycle of the instruction during which the [`Bus`] was called, or
+    /// before which the interrupt was injected.
+    pub cycle: u64,
+    pub input: Input,
+}
+
+/// Every input a [`Bus`] gave the [`CPU`] over a stretch of time, along with
+/// the state of the [`CPU`] it started from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Recording {
+    /// Save-state of the [`CPU`] when recording started.
+    state: Vec<u8>,
+    inputs: Vec<Recorded>,
+    /// Cycle at which recording stopped.
+    end: u64,
+}
+
+impl Recording {
+    /// Returns the [`CPU`] as it was when recording started.
+    pub fn start(&self) -> Result<CPU, StateError> {
+        let mut cpu = CPU::new(&[]);
+        cpu.load_state(&self.state)?;
+
+        Ok(cpu)
+    }
+
+    /// Returns the cycle at which recording stopped.
+    pub fn end(&self) -> u64 {
+        self.end
+    }
+
+    /// Returns the recorded inputs in the order they were made.
+    pub fn inputs(&self) -> &[Recorded] {
+        &self.inputs
+    }
+
+    /// Serializes the recording into a versioned binary blob which
+    /// [`Recording::load`] restores.
+    pub fn save(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(self.state.len() + self.inputs.len() * 13 + 32);
+
+        data.extend_from_slice(&MAGIC);
+        data.extend_from_slice(&VERSION.to_le_bytes());
+        data.extend_from_slice(&(self.state.len() as u32).to_le_bytes());
+        data.extend_from_slice(&self.state);
+        data.extend_from_slice(&self.end.to_le_bytes());
+        data.extend_from_slice(&(self.inputs.len() as u32).to_le_bytes());
+
+        for recorded in &self.inputs {
+            data.extend_from_slice(&recorded.cycle.to_le_bytes());
+
+            match recorded.input {
+                Input::Read { port, data: value } => data.extend_from_slice(&[0, port, value]),
+                Input::Fetch { addr, data: value } => save_access(&mut data, 1, addr, value),
+                Input::Load { addr, data: value } => save_access(&mut data, 2, addr, value),
+                Input::Store { addr, data: value } => save_access(&mut data, 3, addr, value),
+                Input::WaitStates { addr, count } => save_access(&mut data, 4, addr, count),
+                Input::Interrupt => data.push(5),
+                Input::Acknowledge(value) => data.extend_from_slice(&[6, value]),
+                Input::Interrupted(bytes) => {
+                    data.push(7);
+                    data.extend_from_slice(&bytes);
+                }
+                Input::Asserted(bytes) => {
+                    data.push(8);
+                    data.extend_from_slice(&bytes);
+                }
+                Input::Deasserted => data.push(9),
+            }
+        }
+
+        data
+    }
+
+    /// Restores a recording saved by [`Recording::save`].
+    pub fn load(data: &[u8]) -> Result<Self, StateError> {
+        let mut reader = Reader::new(data);
+
+        if reader.bytes(MAGIC.len())? != MAGIC {
+            return Err(StateError::BadMagic);
+        }
+
+        let version = reader.u16()?;
+        if version != VERSION {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+
+        let len = reader.u32()? as usize;
+        let state = reader.bytes(len)?.to_vec();
+        let end = reader.u64()?;
+
+        let count = reader.u32()?;
+        let mut inputs = Vec::new();
+        for _ in 0..count {
+            let cycle = reader.u64()?;
+            let input = match reader.u8()? {
+                0 => Input::Read {
+                    port: reader.u8()?,
+                    data: reader.u8()?,
+                },
+                1 => Input::Fetch {
+                    addr: reader.u16()?,
+                    data: reader.u8()?,
+                },
+                2 => Input::Load {
+                    addr: reader.u16()?,
+                    data: reader.u8()?,
+                },
+                3 => Input::Store {
+                    addr: reader.u16()?,
+                    data: reader.u8()?,
+                },
+                4 => Input::WaitStates {
+                    addr: reader.u16()?,
+                    count: reader.u8()?,
+                },
+                5 => Input::Interrupt,
+                6 => Input::Acknowledge(reader.u8()?),
+                7 => Input::Interrupted(load_instruction(&mut reader)?),
+                8 => Input::Asserted(load_instruction(&mut reader)?),
+                9 => Input::Deasserted,
+                _ => return Err(StateError::Corrupt),
+            };
+
+            inputs.push(Recorded { cycle, input });
+        }
+
+        if !reader.is_empty() {
+            return Err(StateError::Corrupt);
+        }
+
+        Ok(Self { state, inputs, end })
+    }
+}
+
+fn save_access(data: &mut Vec<u8>, tag: u8, addr: u16, value: u8) {
+    data.push(tag);
+    data.extend_from_slice(&addr.to_le_bytes());
+    data.push(value);
+}
+
+/// Reads the bytes of an injected instruction, which must be whole.
+fn load_instruction(reader: &mut Reader) -> Result<[u8; 3], StateError> {
+    let bytes = [reader.u8()?, reader.u8()?, reader.u8()?];
+
+    match crate::supplied(instruction(&bytes)) {
+        Some(packed) if packed == bytes => Ok(bytes),
+        _ => Err(StateError::Corrupt),
+    }
+}
+
+/// Wraps a [`Bus`] to record every input it gives the [`CPU`], so that a run
+/// can be reproduced with [`Replay`].
+///
+/// Interrupts injected into the [`CPU`] are only recorded when made through
+/// [`Recorder::interrupt`], [`Recorder::assert_interrupt`] and
+/// [`Recorder::deassert_interrupt`]. Other changes made directly to the
+/// [`CPU`], such as through [`CPU::memory_mut`] or [`CPU::set_hold`], are
+/// not recorded.
+pub struct Recorder<B> {
+    bus: B,
+    recording: Option<Recording>,
+    /// Cycle at which recording started.
+    start: u64,
+}
+
+impl<B: Bus> Recorder<B> {
+    /// Wraps `bus` without recording yet.
+    pub fn new(bus: B) -> Self {
+        Self {
+            bus,
+            recording: None,
+            start: 0,
+        }
+    }
+
+    /// Starts recording from the current state of `cpu`, discarding any
+    /// recording in progress. Should be called between instructions.
+    pub fn record(&mut self, cpu: &CPU) {
+        self.recording = Some(Recording {
+            state: cpu.save_state(),
+            inputs: Vec::new(),
+            end: cpu.cycles(),
+        });
+        self.start = cpu.cycles();
+    }
+
+    /// Stops recording at the current cycle of `cpu`, returning the recording
+    /// if one was in progress.
+    pub fn stop(&mut self, cpu: &CPU) -> Option<Recording> {
+        let mut recording = self.recording.take()?;
+        recording.end = cpu.cycles();
+
+        Some(recording)
+    }
+
+    /// Drops the inputs recorded from the current cycle of `cpu` on, after it
+    /// was stepped back to an earlier state of the recorded run, e.g. by
+    /// [`Rewind::rewind`](crate::Rewind::rewind). Recording starts over from
+    /// `cpu` if it was stepped back past the start of the recording.
+    pub fn rewind(&mut self, cpu: &CPU) {
+        let Some(recording) = &mut self.recording else {
+            return;
+        };
+
+        if cpu.cycles() < self.start {
+            self.record(cpu);
+        } else {
+            recording
+                .inputs
+                .retain(|recorded| recorded.cycle < cpu.cycles());
+            recording.end = cpu.cycles();
+        }
+    }
+
+    /// Returns true if a recording is in progress.
+    pub fn is_recording(&self) -> bool {
+        self.recording.is_some()
+    }
+
+    /// Supplies an interrupt to `cpu` through [`CPU::interrupt`], recording it
+    /// if it was accepted. Returns true if successful.
+    pub fn interrupt(&mut self, cpu: &mut CPU, instruction: &[u8]) -> bool {
+        let accepted = cpu.interrupt(instruction);
+        if accepted {
+            self.inject(cpu, Input::Interrupted(pack(instruction)));
+        }
+
+        accepted
+    }
+
+    /// Holds the INT input of `cpu` active through
+    /// [`CPU::assert_interrupt`], recording it if it was accepted. Returns true
+    /// if successful.
+    pub fn assert_interrupt(&mut self, cpu: &mut CPU, instruction: &[u8]) -> bool {
+        let accepted = cpu.assert_interrupt(instruction);
+        if accepted {
+            self.inject(cpu, Input::Asserted(pack(instruction)));
+        }
+
+        accepted
+    }
+
+    /// Releases the INT input of `cpu` through [`CPU::deassert_interrupt`],
+    /// recording it.
+    pub fn deassert_interrupt(&mut self, cpu: &mut CPU) {
+        cpu.deassert_interrupt();
+        self.inject(cpu, Input::Deasserted);
+    }
+
+    /// Returns a reference to the wrapped [`Bus`].
+    pub fn bus(&self) -> &B {
+        &self.bus
+    }
+
+    /// Returns a mutable reference to the wrapped [`Bus`].
+    pub fn bus_mut(&mut self) -> &mut B {
+        &mut self.bus
+    }
+
+    /// Returns the wrapped [`Bus`], dropping any recording in progress.
+    pub fn into_inner(self) -> B {
+        self.bus
+    }
+
+    /// Logs `input` to the [`Bus`] if it changed the outcome.
+    fn log(&mut self, cpu: &CPU, input: Option<Input>) {
+        if let (Some(recording), Some(input)) = (&mut self.recording, input) {
+            recording.inputs.push(Recorded {
+                cycle: cpu.cycles(),
+                input,
+            });
+        }
+    }
+
+    /// Logs `input` injected into `cpu`.
+    fn inject(&mut self, cpu: &CPU, input: Input) {
+        self.log(cpu, Some(input));
+    }
+}
+
+/// Packs `instruction`, which [`CPU`] accepted as whole, into an array.
+fn pack(instruction: &[u8]) -> [u8; 3] {
+    let mut bytes = [0; 3];
+    bytes[..instruction.len()].copy_from_slice(instruction);
+    bytes
+}
+
+impl<B: Bus> Bus for Recorder<B> {
+    fn read(&mut self, cpu: &CPU, port: u8) -> u8 {
+        let data = self.bus.read(cpu, port);
+        self.log(cpu, Some(Input::Read { port, data }));
+        data
+    }
+
+    fn write(&mut self, cpu: &CPU, port: u8, data: u8) {
+        self.bus.write(cpu, port, data);
+    }
+
+    fn fetch(&mut self, cpu: &CPU, addr: u16) -> Option<u8> {
+        let data = self.bus.fetch(cpu, addr);
+        self.log(cpu, data.map(|data| Input::Fetch { addr, data }));
+        data
+    }
+
+    fn load(&mut self, cpu: &CPU, addr: u16) -> Option<u8> {
+        let data = self.bus.load(cpu, addr);
+        self.log(cpu, data.map(|data| Input::Load { addr, data }));
+        data
+    }
+
+    fn store(&mut self, cpu: &CPU, addr: u16, data: u8) -> bool {
+        let handled = self.bus.store(cpu, addr, data);
+        self.log(cpu, handled.then_some(Input::Store { addr, data }));
+        handled
+    }
+
+    fn wait_states(&mut self, cpu: &CPU, kind: CycleKind, addr: u16) -> u8 {
+        let count = self.bus.wait_states(cpu, kind, addr);
+        self.log(
+            cpu,
+            (count > 0).then_some(Input::WaitStates { addr, count }),
+        );
+        count
+    }
+
+    fn interrupt_requested(&mut self, cpu: &CPU) -> bool {
+        let requested = self.bus.interrupt_requested(cpu);
+        self.log(cpu, requested.then_some(Input::Interrupt));
+        requested
+    }
+
+    fn acknowledge(&mut self, cpu: &CPU) -> u8 {
+        let data = self.bus.acknowledge(cpu);
+        self.log(cpu, Some(Input::Acknowledge(data)));
+        data
+    }
+}
+
+impl<B: Device> Device for Recorder<B> {
+    type Event = B::Event;
+
+    fn tick(&mut self, cpu: &mut CPU, cycles: u64) {
+        self.bus.tick(cpu, cycles);
+    }
+
+    fn fire(
+        &mut self,
+        cpu: &mut CPU,
+        scheduler: &mut Scheduler<Self::Event>,
+        at: u64,
+        event: Self::Event,
+    ) {
+        self.bus.fire(cpu, scheduler, at, event);
+    }
+}
+
+/// A [`Bus`] feeding back the inputs of a [`Recording`].
+///
+/// Running the [`CPU`] from [`Recording::start`] until [`Replay::end`]
+/// through [`Replay::run`] reproduces the recorded run exactly. Port writes are
+/// ignored.
+pub struct Replay {
+    inputs: Vec<Recorded>,
+    end: u64,
+    /// Index of the next input to replay.
+    next: usize,
+    /// Cycle at which the run first departed from the recording.
+    diverged: Option<u64>,
+}
+
+impl Replay {
+    /// Creates a new [`Replay`] of `recording`.
+    pub fn new(recording: Recording) -> Self {
+        Self {
+            inputs: recording.inputs,
+            end: recording.end,
+            next: 0,
+            diverged: None,
+        }
+    }
+
+    /// Returns the cycle at which recording stopped.
+    pub fn end(&self) -> u64 {
+        self.end
+    }
+
+    /// Returns true once every recorded input has been replayed.
+    pub fn finished(&self) -> bool {
+        self.next == self.inputs.len()
+    }
+
+    /// Returns the cycle at which the run first asked for an input other than
+    /// the one recorded, e.g. because it did not start from
+    /// [`Recording::start`]. Inputs are no longer exact from then on.
+    pub fn diverged(&self) -> Option<u64> {
+        self.diverged
+    }
+
+    /// Runs `cpu` until it reaches cycle `until` or the end of the recording,
+    /// injecting the recorded interrupts along the way.
+    pub fn run(&mut self, cpu: &mut CPU, until: u64) {
+        while cpu.cycles() < until.min(self.end) {
+            self.inject(cpu);
+            cpu.cycle(self);
+        }
+    }
+
+    /// Injects the interrupts recorded at the current cycle of `cpu`. Should
+    /// be called before every instruction, as [`Replay::run`] does.
+    pub fn inject(&mut self, cpu: &mut CPU) {
+        self.skip(cpu);
+
+        while let Some(&Recorded { cycle, input }) = self.inputs.get(self.next) {
+            if cycle != cpu.cycles() || !input.injected() {
+                break;
+            }
+            self.next += 1;
+
+            let accepted = match input {
+                Input::Interrupted(bytes) => cpu.interrupt(instruction(&bytes)),
+                Input::Asserted(bytes) => cpu.assert_interrupt(instruction(&bytes)),
+                // Only releases of INT are left
+                _ => {
+                    cpu.deassert_interrupt();
+                    true
+                }
+            };
+            if !accepted {
+                self.diverge(cycle);
+            }
+        }
+    }
+
+    /// Returns the input recorded for the current call to the [`Bus`] if
+    /// `requested` accepts it, which it does when it is of the kind and for
+    /// the address asked for.
+    fn replay(&mut self, cpu: &CPU, requested: impl Fn(Input) -> bool) -> Option<Input> {
+        self.skip(cpu);
+
+        let recorded = *self
+            .inputs
+            .get(self.next)
+            .filter(|recorded| recorded.cycle == cpu.cycles() && requested(recorded.input))?;
+        self.next += 1;
+
+        Some(recorded.input)
+    }
+
+    /// Skips the inputs recorded before the current cycle of `cpu`, which the
+    /// run never asked for.
+    fn skip(&mut self, cpu: &CPU) {
+        while let Some(recorded) = self.inputs.get(self.next) {
+            if recorded.cycle >= cpu.cycles() {
+                break;
+            }
+
+            self.diverge(recorded.cycle);
+            self.next += 1;
+        }
+    }
+
+    /// Notes that the run departed from the recording at `cycle`.
+    fn diverge(&mut self, cycle: u64) {
+        self.diverged.get_or_insert(cycle);
+    }
+}
+
+impl Bus for Replay {
+    fn read(&mut self, cpu: &CPU, port: u8) -> u8 {
+        match self.replay(
+            cpu,
+            |input| matches!(input, Input::Read { port: p, .. } if p == port),
+        ) {
+            Some(Input::Read { data, .. }) => data,
+            _ => {
+                self.diverge(cpu.cycles());
+                0xff
+            }
+        }
+    }
+
+    fn write(&mut self, _cpu: &CPU, _port: u8, _data: u8) {}
+
+    fn fetch(&mut self, cpu: &CPU, addr: u16) -> Option<u8> {
+        match self.replay(
+            cpu,
+            |input| matches!(input, Input::Fetch { addr: a, .. } if a == addr),
+        ) {
+            Some(Input::Fetch { data, .. }) => Some(data),
+            _ => None,
+        }
+    }
+
+    fn load(&mut self, cpu: &CPU, addr: u16) -> Option<u8> {
+        match self.replay(
+            cpu,
+            |input| matches!(input, Input::Load { addr: a, .. } if a == addr),
+        ) {
+            Some(Input::Load { data, .. }) => Some(data),
+            _ => None,
+        }
+    }
+
+    fn store(&mut self, cpu: &CPU, addr: u16, data: u8) -> bool {
+        self.replay(cpu, |input| input == Input::Store { addr, data })
+            .is_some()
+    }
+
+    fn wait_states(&mut self, cpu: &CPU, _kind: CycleKind, addr: u16) -> u8 {
+        match self.replay(
+            cpu,
+            |input| matches!(input, Input::WaitStates { addr: a, .. } if a == addr),
+        ) {
+            Some(Input::WaitStates { count, .. }) => count,
+            _ => 0,
+        }
+    }
+
+    fn interrupt_requested(&mut self, cpu: &CPU) -> bool {
+        self.replay(cpu, |input| input == Input::Interrupt)
+            .is_some()
+    }
+
+    fn acknowledge(&mut self, cpu: &CPU) -> u8 {
+        match self.replay(cpu, |input| matches!(input, Input::Acknowledge(_))) {
+            Some(Input::Acknowledge(data)) => data,
+            _ => {
+                self.diverge(cpu.cycles());
+                0xff
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Rewind;
+
+    /// A [`Bus`] with a noisy port, memory mapped registers, slow memory and
+    /// a periodic interrupt.
+    struct Noisy(u32);
+
+    impl Bus for Noisy {
+        fn read(&mut self, _cpu: &CPU, _port: u8) -> u8 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 17;
+            self.0 ^= self.0 << 5;
+            self.0 as u8
+        }
+
+        fn write(&mut self, _cpu: &CPU, _port: u8, _data: u8) {}
+
+        fn load(&mut self, cpu: &CPU, addr: u16) -> Option<u8> {
+            (addr == 0x3000).then_some(cpu.cycles() as u8)
+        }
+
+        fn store(&mut self, _cpu: &CPU, addr: u16, _data: u8) -> bool {
+            addr == 0x3100
+        }
+
+        fn wait_states(&mut self, _cpu: &CPU, _kind: CycleKind, addr: u16) -> u8 {
+            u8::from(addr >= 0x1000)
+        }
+
+        fn interrupt_requested(&mut self, cpu: &CPU) -> bool {
+            cpu.cycles() % 997 < 20
+        }
+
+        fn acknowledge(&mut self, _cpu: &CPU) -> u8 {
+            // RST 2
+            0xd7
+        }
+    }
+
+    /// Returns a [`CPU`] running a loop which reads the noisy port and memory
+    /// mapped registers, with handlers for RST 1 and 2.
+    fn program() -> CPU {
+        let mut program = vec![0; 0x40];
+        // JMP 0x0040
+        program[..3].copy_from_slice(&[0xc3, 0x40, 0x00]);
+        // INR C; EI; RET
+        program[0x08..0x0b].copy_from_slice(&[0x0c, 0xfb, 0xc9]);
+        // INR B; EI; RET
+        program[0x10..0x13].copy_from_slice(&[0x04, 0xfb, 0xc9]);
+        program.extend([
+            0x31, 0x00, 0x20, // LXI SP,0x2000
+            0xfb, // EI
+            0xdb, 0x01, // IN 0x01
+            0x32, 0x00, 0x31, // STA 0x3100
+            0x32, 0x00, 0x10, // STA 0x1000
+            0x3a, 0x00, 0x30, // LDA 0x3000
+            0x81, // ADD C
+            0x32, 0x01, 0x10, // STA 0x1001
+            0xc3, 0x43, 0x00, // JMP 0x0043
+        ]);
+
+        CPU::new(&program)
+    }
+
+    /// Returns a [`CPU`] which ran [`program`] for a while and the recording
+    /// of the second half of the run, with interrupts injected along the way.
+    fn record() -> (CPU, Recording) {
+        let mut cpu = program();
+        let mut recorder = Recorder::new(Noisy(1));
+
+        for _ in 0..1_000 {
+            cpu.cycle(&mut recorder);
+        }
+
+        recorder.record(&cpu);
+        for idx in 0..20_000 {
+            if idx % 500 == 0 {
+                // RST 1
+                recorder.interrupt(&mut cpu, &[0xcf]);
+            }
+            if idx % 700 == 0 {
+                // CALL 0x0010
+                recorder.assert_interrupt(&mut cpu, &[0xcd, 0x10, 0x00]);
+            }
+            if idx % 700 == 350 {
+                recorder.deassert_interrupt(&mut cpu);
+            }
+
+            cpu.cycle(&mut recorder);
+        }
+
+        let recording = recorder.stop(&cpu).unwrap();
+        (cpu, recording)
+    }
+
+    #[test]
+    fn replay_reproduces_the_run() {
+        let (cpu, recording) = record();
+
+        let recorded = |check: fn(&Input) -> bool| {
+            recording
+                .inputs()
+                .iter()
+                .any(|recorded| check(&recorded.input))
+        };
+        assert!(recorded(|input| matches!(input, Input::Interrupted(_))));
+        assert!(recorded(|input| matches!(input, Input::Asserted(_))));
+        assert!(recorded(|input| matches!(input, Input::Acknowledge(_))));
+        assert!(recorded(|input| matches!(input, Input::Load { .. })));
+
+        let mut replayed = recording.start().unwrap();
+        let mut replay = Replay::new(recording);
+        replay.run(&mut replayed, u64::MAX);
+
+        assert_eq!(replay.diverged(), None);
+        assert!(replay.finished());
+        assert_eq!(replayed.save_state(), cpu.save_state());
+    }
+
+    #[test]
+    fn rewinding_keeps_the_recording() {
+        let mut cpu = program();
+        let mut recorder = Recorder::new(Noisy(1));
+        let mut rewind = Rewind::new(1_000, 8);
+
+        recorder.record(&cpu);
+        let start = recorder.recording.as_ref().unwrap().state.clone();
+        for idx in 0..10_000 {
+            if idx % 500 == 0 {
+                recorder.interrupt(&mut cpu, &[0xcf]);
+            }
+
+            rewind.record(&cpu, &());
+            cpu.cycle(&mut recorder);
+        }
+
+        rewind.rewind(&mut cpu, &mut (), 3);
+        recorder.rewind(&cpu);
+        for _ in 0..5_000 {
+            cpu.cycle(&mut recorder);
+        }
+
+        let recording = recorder.stop(&cpu).unwrap();
+        assert_eq!(recording.state, start);
+
+        let mut replayed = recording.start().unwrap();
+        let mut replay = Replay::new(recording);
+        replay.run(&mut replayed, u64::MAX);
+
+        assert_eq!(replay.diverged(), None);
+        assert_eq!(replayed.save_state(), cpu.save_state());
+    }
+
+    #[test]
+    fn recordings_round_trip() {
+        let (_, recording) = record();
+        let data = recording.save();
+
+        assert_eq!(Recording::load(&data), Ok(recording));
+        assert_eq!(
+            Recording::load(&data[..data.len() - 1]),
+            Err(StateError::Truncated)
+        );
+    }
+
+    #[test]
+    fn replay_from_elsewhere_diverges() {
+        let (_, recording) = record();
+
+        let mut replay = Replay::new(recording);
+        replay.run(&mut program(), u64::MAX);
+
+        assert!(replay.diverged().is_some());
+    }
+}
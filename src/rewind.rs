@@ -0,0 +1,154 @@
+use crate::CPU;
+use std::collections::VecDeque;
+
+/// Records snapshots of a [`CPU`] so it can be stepped back in time.
+///
+/// Snapshots are clones of the [`CPU`], whose memory pages are shared with
+/// the running [`CPU`] and earlier snapshots until written to, so each one
+/// only costs the pages dirtied since the last.
+///
+/// Each snapshot also holds a clone of a state of type `T`, such as that of
+/// the devices on the [`Bus`](crate::Bus), which is restored along with the
+/// [`CPU`] so that the two stay consistent.
+pub struct Rewind<T = ()> {
+    /// Number of T-states between snapshots.
+    interval: u64,
+    /// Maximum number of snapshots kept.
+    capacity: usize,
+    /// Snapshots, oldest first.
+    snapshots: VecDeque<(CPU, T)>,
+    /// Cycle at which the next snapshot is due.
+    next: u64,
+}
+
+impl<T: Clone> Rewind<T> {
+    /// Creates a new [`Rewind`] taking a snapshot every `interval` T-states and
+    /// keeping the last `capacity` of them.
+    pub fn new(interval: u64, capacity: usize) -> Self {
+        Self {
+            interval: interval.max(1),
+            capacity,
+            snapshots: VecDeque::with_capacity(capacity),
+            next: 0,
+        }
+    }
+
+    /// Takes a snapshot of `cpu` and `state` if one is due, dropping the
+    /// oldest snapshot once the buffer is full. Meant to be called between
+    /// instructions, e.g. once per frame.
+    pub fn record(&mut self, cpu: &CPU, state: &T) {
+        if cpu.cycles() < self.next || self.capacity == 0 {
+            return;
+        }
+
+        if self.snapshots.len() == self.capacity {
+            self.snapshots.pop_front();
+        }
+
+        self.snapshots.push_back((cpu.clone(), state.clone()));
+        self.next = cpu.cycles() + self.interval;
+    }
+
+    /// Restores `cpu` and `state` to the snapshot taken `frames` snapshots
+    /// ago, counting the most recent as one, and discards it along with the
+    /// snapshots after it. Rewinds as far as possible when fewer are recorded.
+    ///
+    /// Returns the number of snapshots stepped back, zero if there are none.
+    pub fn rewind(&mut self, cpu: &mut CPU, state: &mut T, frames: usize) -> usize {
+        let frames = frames.min(self.snapshots.len());
+        if frames == 0 {
+            return 0;
+        }
+
+        self.snapshots.truncate(self.snapshots.len() - frames + 1);
+        if let Some(snapshot) = self.snapshots.pop_back() {
+            (*cpu, *state) = snapshot;
+        }
+        self.next = cpu.cycles() + self.interval;
+
+        frames
+    }
+
+    /// Returns the number of snapshots recorded.
+    pub fn len(&self) -> usize {
+        self.snapshots.len()
+    }
+
+    /// Returns true if no snapshot is recorded.
+    pub fn is_empty(&self) -> bool {
+        self.snapshots.is_empty()
+    }
+
+    /// Discards every snapshot.
+    pub fn clear(&mut self) {
+        self.snapshots.clear();
+        self.next = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns a [`CPU`] counting in register B and storing the count after
+    /// its code.
+    fn counter() -> CPU {
+        CPU::new(&[
+            0x04, // INR B
+            0x78, // MOV A,B
+            0x32, 0x00, 0x01, // STA 0x0100
+            0xc3, 0x00, 0x00, // JMP 0x0000
+        ])
+    }
+
+    #[test]
+    fn rewind_restores_the_cpu_and_state() {
+        let mut cpu = counter();
+        let mut rewind = Rewind::new(1, 8);
+        let mut count = 0;
+
+        // One snapshot per loop, before each INR
+        for _ in 0..6 {
+            rewind.record(&cpu, &count);
+            for _ in 0..4 {
+                cpu.cycle(&mut ());
+            }
+            count += 1;
+        }
+        assert_eq!(rewind.len(), 6);
+
+        assert_eq!(rewind.rewind(&mut cpu, &mut count, 2), 2);
+        assert_eq!((count, cpu.memory().read(0x0100)), (4, 4));
+        assert_eq!(rewind.len(), 4);
+
+        assert_eq!(rewind.rewind(&mut cpu, &mut count, 10), 4);
+        assert_eq!((count, cpu.pc(), cpu.memory().read(0x0100)), (0, 0, 0));
+        assert_eq!(rewind.rewind(&mut cpu, &mut count, 1), 0);
+    }
+
+    #[test]
+    fn snapshots_do_not_see_later_writes() {
+        let mut cpu = counter();
+        let mut rewind = Rewind::new(1, 4);
+
+        rewind.record(&cpu, &());
+        for _ in 0..30 {
+            cpu.cycle(&mut ());
+        }
+        cpu.memory_mut().write(0x0000, 0x05);
+
+        // Writes after a snapshot is restored reach neither the older
+        // snapshots nor clones taken in between
+        rewind.record(&cpu, &());
+        rewind.rewind(&mut cpu, &mut (), 1);
+        cpu.memory_mut().write(0x0200, 0xaa);
+        let snapshot = cpu.clone();
+        cpu.memory_mut().write(0x0201, 0xbb);
+        assert_eq!(rewind.rewind(&mut cpu, &mut (), 1), 1);
+
+        assert_eq!(cpu.memory().read_range(0x0000..0x0001), [0x04]);
+        assert_eq!(cpu.memory().read_range(0x0100..0x0101), [0x00]);
+        assert_eq!(cpu.memory().read_range(0x0200..0x0202), [0x00, 0x00]);
+        assert_eq!(snapshot.memory().read_range(0x0200..0x0202), [0xaa, 0x00]);
+    }
+}
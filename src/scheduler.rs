@@ -0,0 +1,149 @@
+use crate::{Bus, CPU, Compiled, Native};
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+
+/// A device whose state advances with the clock of the [`CPU`].
+///
+/// A [`Scheduler`] ticks the device after every instruction and fires the
+/// events it schedules once the [`CPU`] reaches them.
+pub trait Device {
+    /// Events scheduled by the device.
+    type Event;
+
+    /// Advances the device by `cycles` T-states.
+    fn tick(&mut self, _cpu: &mut CPU, _cycles: u64) {}
+
+    /// Handles `event`, which was scheduled for cycle `at`. Events fire at the
+    /// first instruction boundary at or after `at`, so periodic events should
+    /// be rescheduled relative to `at` rather than [`CPU::cycles`].
+    fn fire(
+        &mut self,
+        cpu: &mut CPU,
+        scheduler: &mut Scheduler<Self::Event>,
+        at: u64,
+        event: Self::Event,
+    );
+}
+
+/// An event scheduled for cycle `at`. Events for the same cycle fire in the
+/// order they were scheduled.
+struct Entry<E> {
+    at: u64,
+    order: u64,
+    event: E,
+}
+
+impl<E> PartialEq for Entry<E> {
+    fn eq(&self, other: &Self) -> bool {
+        (self.at, self.order) == (other.at, other.order)
+    }
+}
+
+impl<E> Eq for Entry<E> {}
+
+impl<E> PartialOrd for Entry<E> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<E> Ord for Entry<E> {
+    /// Reversed so the earliest event is at the top of the heap.
+    fn cmp(&self, other: &Self) -> Ordering {
+        (other.at, other.order).cmp(&(self.at, self.order))
+    }
+}
+
+/// Runs a [`CPU`] while firing the events of a [`Device`] at the cycles they
+/// were scheduled for.
+pub struct Scheduler<E> {
+    events: BinaryHeap<Entry<E>>,
+    /// Number of events scheduled so far.
+    scheduled: u64,
+}
+
+impl<E> Default for Scheduler<E> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<E> Scheduler<E> {
+    /// Creates a new [`Scheduler`] with no events.
+    pub fn new() -> Self {
+        Self {
+            events: BinaryHeap::new(),
+            scheduled: 0,
+        }
+    }
+
+    /// Schedules `event` to fire at cycle `at`, counted in T-states since
+    /// power on.
+    pub fn schedule(&mut self, at: u64, event: E) {
+        self.events.push(Entry {
+            at,
+            order: self.scheduled,
+            event,
+        });
+        self.scheduled += 1;
+    }
+
+    /// Returns the cycle of the earliest scheduled event, if there is one.
+    pub fn next(&self) -> Option<u64> {
+        self.events.peek().map(|entry| entry.at)
+    }
+
+    /// Runs `cpu` until it reaches cycle `until`, with `device` as its
+    /// [`Bus`]. Events due by then fire before the next instruction.
+    pub fn run<D>(&mut self, cpu: &mut CPU, device: &mut D, until: u64)
+    where
+        D: Bus + Device<Event = E>,
+    {
+        loop {
+            self.fire(cpu, device);
+
+            if cpu.cycles() >= until {
+                break;
+            }
+
+            let start = cpu.cycles();
+            cpu.cycle(device);
+            device.tick(cpu, cpu.cycles() - start);
+        }
+    }
+
+    /// Same as [`Scheduler::run`], running compiled code through `native`
+    /// where it can. `device` is ticked after every block of compiled code
+    /// rather than every instruction.
+    pub fn run_native<D, C>(
+        &mut self,
+        native: &mut Native<C>,
+        cpu: &mut CPU,
+        device: &mut D,
+        until: u64,
+    ) where
+        D: Bus + Device<Event = E>,
+        C: Compiled,
+    {
+        loop {
+            self.fire(cpu, device);
+
+            if cpu.cycles() >= until {
+                break;
+            }
+
+            let end = self.next().map_or(until, |at| at.min(until));
+            let cycles = native.cycle(cpu, device, end);
+            device.tick(cpu, cycles);
+        }
+    }
+
+    /// Fires every event due by the current cycle of `cpu`.
+    fn fire<D: Device<Event = E>>(&mut self, cpu: &mut CPU, device: &mut D) {
+        while self.next().is_some_and(|at| at <= cpu.cycles()) {
+            if let Some(Entry { at, event, .. }) = self.events.pop() {
+                device.fire(cpu, self, at, event);
+            }
+        }
+    }
+}
@@ -0,0 +1,298 @@
+use crate::alu::Alu;
+use crate::memory::Memory;
+use crate::{CPU, Supplier};
+
+/// Identifies a save-state.
+const MAGIC: [u8; 4] = *b"8080";
+/// Version of the save-state format written by [`CPU::save_state`].
+const VERSION: u16 = 1;
+
+impl CPU {
+    /// Serializes the full state of the [`CPU`], memory included, into a
+    /// versioned binary blob which [`CPU::load_state`] restores.
+    ///
+    /// Machine cycles of the current instruction not yet returned by
+    /// [`CPU::step`] are not saved.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut state = Vec::with_capacity(self.memory.state_len() + 64);
+
+        state.extend_from_slice(&MAGIC);
+        state.extend_from_slice(&VERSION.to_le_bytes());
+
+        state.extend_from_slice(&self.pc.to_le_bytes());
+        state.extend_from_slice(&self.sp().to_le_bytes());
+        state.push(self.flag_bits());
+        state.extend_from_slice(&self.registers);
+        state.push(u8::from(self.halt));
+        state.push(self.interrupt);
+        save_supplier(&mut state, self.pending_interrupt);
+        save_supplier(&mut state, self.int.map(Supplier::Cpu));
+        state.push(u8::from(self.hold));
+        state.push(u8::from(self.hlda));
+        state.extend_from_slice(&self.dma.to_le_bytes());
+        state.extend_from_slice(&self.cycles.to_le_bytes());
+        state.extend_from_slice(&self.instructions.to_le_bytes());
+        state.extend_from_slice(&self.rate.to_le_bytes());
+
+        self.memory.save(&mut state);
+
+        state
+    }
+
+    /// Restores a state saved by [`CPU::save_state`]. The [`CPU`] is left
+    /// untouched if `state` is invalid.
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), StateError> {
+        let mut reader = Reader::new(state);
+
+        if reader.bytes(MAGIC.len())? != MAGIC {
+            return Err(StateError::BadMagic);
+        }
+
+        let version = reader.u16()?;
+        if version != VERSION {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+
+        let pc = reader.u16()?;
+        let sp = reader.u16()?;
+        let flag = reader.u8()?;
+        let registers = reader.array()?;
+        let halt = reader.bool()?;
+        let interrupt = match reader.u8()? {
+            interrupt @ (0 | 1 | 2 | 4) => interrupt,
+            _ => return Err(StateError::Corrupt),
+        };
+        let pending_interrupt = load_supplier(&mut reader)?;
+        let int = match load_supplier(&mut reader)? {
+            Some(Supplier::Cpu(instruction)) => Some(instruction),
+            Some(Supplier::Bus) => return Err(StateError::Corrupt),
+            None => None,
+        };
+        let hold = reader.bool()?;
+        let hlda = reader.bool()?;
+        let dma = reader.u64()?;
+        let cycles = reader.u64()?;
+        let instructions = reader.u64()?;
+        let rate = reader.u32()?;
+        let memory = Memory::load(&mut reader)?;
+
+        if !reader.is_empty() {
+            return Err(StateError::Corrupt);
+        }
+
+        self.pc = pc;
+        self.set_sp(sp);
+        // Unused flag bits are fixed
+        self.flag = (flag & 0b1101_0111) | 0b0000_0010;
+        self.alu = Alu::default();
+        self.registers = registers;
+        self.halt = halt;
+        self.interrupt = interrupt;
+        self.pending_interrupt = pending_interrupt;
+        self.int = int;
+        self.hold = hold;
+        self.hlda = hlda;
+        self.dma = dma;
+        self.cycles = cycles;
+        self.instructions = instructions;
+        self.rate = rate;
+        self.machine_cycles.clear();
+        self.quiet = false;
+        self.memory = memory;
+
+        Ok(())
+    }
+}
+
+fn save_supplier(state: &mut Vec<u8>, supplier: Option<Supplier>) {
+    match supplier {
+        None => state.push(0),
+        Some(Supplier::Cpu(instruction)) => {
+            state.push(1);
+            state.extend_from_slice(&instruction);
+        }
+        Some(Supplier::Bus) => state.push(2),
+    }
+}
+
+fn load_supplier(reader: &mut Reader) -> Result<Option<Supplier>, StateError> {
+    match reader.u8()? {
+        0 => Ok(None),
+        1 => {
+            let instruction = [reader.u8()?, reader.u8()?, reader.u8()?];
+            Ok(Some(Supplier::Cpu(instruction)))
+        }
+        2 => Ok(Some(Supplier::Bus)),
+        _ => Err(StateError::Corrupt),
+    }
+}
+
+/// Reads little endian values out of a save-state.
+pub(crate) struct Reader<'a> {
+    state: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(state: &'a [u8]) -> Self {
+        Self { state }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.state.is_empty()
+    }
+
+    pub(crate) fn bytes(&mut self, len: usize) -> Result<&'a [u8], StateError> {
+        if self.state.len() < len {
+            return Err(StateError::Truncated);
+        }
+
+        let (bytes, rest) = self.state.split_at(len);
+        self.state = rest;
+
+        Ok(bytes)
+    }
+
+    pub(crate) fn u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub(crate) fn bool(&mut self) -> Result<bool, StateError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(StateError::Corrupt),
+        }
+    }
+
+    pub(crate) fn u16(&mut self) -> Result<u16, StateError> {
+        Ok(u16::from_le_bytes(self.array()?))
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<u32, StateError> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    pub(crate) fn u64(&mut self) -> Result<u64, StateError> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], StateError> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.bytes(N)?);
+
+        Ok(array)
+    }
+}
+
+/// Errors from restoring a state with [`CPU::load_state`] or a recording with
+/// [`Recording::load`](crate::Recording::load).
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum StateError {
+    /// The data does not start with the magic number of its format.
+    BadMagic,
+    /// The data was written in a version of its format which is not
+    /// supported.
+    UnsupportedVersion(u16),
+    /// The data ends early.
+    Truncated,
+    /// The data holds values which are out of range.
+    Corrupt,
+}
+
+impl std::fmt::Display for StateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::BadMagic => write!(f, "Data is not in the expected format"),
+            Self::UnsupportedVersion(version) => {
+                write!(f, "Format version {version} is not supported")
+            }
+            Self::Truncated => write!(f, "Data ends early"),
+            Self::Corrupt => write!(f, "Data is corrupt"),
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MemoryMap;
+
+    /// Returns a [`CPU`] clocked at 1 MHz with ROM, a mirror and a banked
+    /// window, which has run a loop storing to them for a while.
+    fn running() -> CPU {
+        let program = [
+            0x3e, 0x01, // MVI A,0x01
+            0xd3, 0x10, // OUT 0x10
+            0x3c, // INR A
+            0x32, 0x00, 0x80, // STA 0x8000
+            0x32, 0x10, 0x90, // STA 0x9010
+            0xc3, 0x04, 0x00, // JMP 0x0004
+        ];
+        let map = MemoryMap::new()
+            .rom(0x0000..=0x00ff)
+            .mirror(0x9000..=0x90ff, 0x8000..=0x80ff)
+            .banked(0x8000..=0x80ff, 2, 0x10);
+        let mut cpu = CPU::builder()
+            .load(0x0000, &program)
+            .memory_map(map)
+            .rate(1_000_000)
+            .build()
+            .unwrap();
+
+        for _ in 0..100 {
+            cpu.cycle(&mut ());
+        }
+
+        cpu
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut cpu = running();
+        let state = cpu.save_state();
+
+        let mut loaded = CPU::new(&[]);
+        loaded.load_state(&state).unwrap();
+        assert_eq!(loaded.save_state(), state);
+        assert_eq!(loaded.memory().bank(0x10), Some(1));
+        assert_eq!(loaded.rate(), 1_000_000);
+
+        for _ in 0..100 {
+            cpu.cycle(&mut ());
+            loaded.cycle(&mut ());
+        }
+        assert_eq!(loaded.save_state(), cpu.save_state());
+    }
+
+    #[test]
+    fn invalid_states_leave_the_cpu_untouched() {
+        let state = running().save_state();
+        let mut cpu = CPU::new(&[0x3c, 0x3c, 0x3c]);
+        cpu.cycle(&mut ());
+        let before = cpu.save_state();
+
+        let mut magic = state.clone();
+        magic[0] ^= 0xff;
+        let mut version = state.clone();
+        version[4..6].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        let mut trailing = state.clone();
+        trailing.push(0);
+        let mut interrupt = state.clone();
+        interrupt[19] = 3;
+
+        for (state, error) in [
+            (&state[..3], StateError::Truncated),
+            (&state[..state.len() - 1], StateError::Truncated),
+            (&magic[..], StateError::BadMagic),
+            (&version[..], StateError::UnsupportedVersion(VERSION + 1)),
+            (&trailing[..], StateError::Corrupt),
+            (&interrupt[..], StateError::Corrupt),
+        ] {
+            assert_eq!(cpu.load_state(state), Err(error));
+            assert_eq!(cpu.save_state(), before);
+        }
+    }
+}
@@ -0,0 +1,667 @@
+// Compiled by intel8080::Recompiler. Do not edit.
+
+#[allow(unused_imports)]
+use intel8080::{Bus, CPU, Compiled, CycleKind, Flag, Register::*, RegisterPair::*};
+
+pub struct Small;
+
+impl Compiled for Small {
+    const ROM: &'static [(u16, &'static [u8])] = &[
+        (0x0000, &[
+            0xc3, 0x40, 0x00,
+        ]),
+        (0x0008, &[
+            0xc9,
+        ]),
+        (0x0010, &[
+            0xc9,
+        ]),
+        (0x0018, &[
+            0xc9,
+        ]),
+        (0x0020, &[
+            0xc9,
+        ]),
+        (0x0028, &[
+            0xc9,
+        ]),
+        (0x0030, &[
+            0xc9,
+        ]),
+        (0x0038, &[
+            0x0c, 0x79, 0xd3, 0x03, 0xfb, 0xc9,
+        ]),
+        (0x0040, &[
+            0x31, 0x00, 0x90, 0xfb, 0x06, 0x10, 0x21, 0x00, 0x80, 0x11, 0x00, 0x81, 0x78, 0x07, 0xee, 0x5a,
+            0x77, 0x34, 0x27, 0x8e, 0xcd, 0x90, 0x00, 0xcc, 0x90, 0x00, 0x12, 0x13, 0x23, 0x05, 0xc2, 0x4c,
+            0x00, 0xe7, 0xeb, 0x39, 0xe5, 0xe3, 0xd1, 0x22, 0x00, 0x82, 0x2a, 0x00, 0x82, 0x32, 0x02, 0x82,
+            0x3a, 0x00, 0x80, 0xdb, 0x01, 0xd3, 0x02, 0x2f, 0x37, 0x1f, 0x3f, 0x17, 0x0f, 0x21, 0xb0, 0x00,
+            0xe9,
+        ]),
+        (0x0090, &[
+            0xfe, 0x80, 0xd8, 0xd6, 0x11, 0xb0, 0xc9,
+        ]),
+        (0x00b0, &[
+            0x0b, 0x99, 0x21, 0x00, 0x80, 0x36, 0x3c, 0x35, 0xa6, 0x0a, 0xba, 0xf9, 0xf3, 0x76, 0xc9,
+        ]),
+    ];
+
+    fn run<B: Bus>(cpu: &mut CPU, bus: &mut B, end: u64) -> bool {
+        match cpu.pc() {
+            0x0000 => block_0000(cpu, bus, end),
+            0x0008 => block_0008(cpu, bus, end),
+            0x0010 => block_0010(cpu, bus, end),
+            0x0018 => block_0018(cpu, bus, end),
+            0x0020 => block_0020(cpu, bus, end),
+            0x0028 => block_0028(cpu, bus, end),
+            0x0030 => block_0030(cpu, bus, end),
+            0x0038 => block_0038(cpu, bus, end),
+            0x003c => block_003c(cpu, bus, end),
+            0x0040 => block_0040(cpu, bus, end),
+            0x004c => block_004c(cpu, bus, end),
+            0x0057 => block_0057(cpu, bus, end),
+            0x005a => block_005a(cpu, bus, end),
+            0x0061 => block_0061(cpu, bus, end),
+            0x0062 => block_0062(cpu, bus, end),
+            0x0077 => block_0077(cpu, bus, end),
+            0x0090 => block_0090(cpu, bus, end),
+            0x0093 => block_0093(cpu, bus, end),
+            0x00b0 => block_00b0(cpu, bus, end),
+            0x00be => block_00be(cpu, bus, end),
+            _ => false,
+        }
+    }
+}
+
+fn block_0000<B: Bus>(cpu: &mut CPU, bus: &mut B, end: u64) -> bool {
+    if !cpu.native_boundary(bus, end) {
+        return false;
+    }
+    // 0x0000 JMP 0x0040
+    cpu.native_fetch(bus, 0xc3);
+    cpu.set_pc(0x0040);
+    true
+}
+
+fn block_0008<B: Bus>(cpu: &mut CPU, bus: &mut B, end: u64) -> bool {
+    if !cpu.native_boundary(bus, end) {
+        return false;
+    }
+    // 0x0008 RET
+    cpu.native_fetch(bus, 0xc9);
+    let pc = cpu.native_pop(bus);
+    cpu.set_pc(pc);
+    true
+}
+
+fn block_0010<B: Bus>(cpu: &mut CPU, bus: &mut B, end: u64) -> bool {
+    if !cpu.native_boundary(bus, end) {
+        return false;
+    }
+    // 0x0010 RET
+    cpu.native_fetch(bus, 0xc9);
+    let pc = cpu.native_pop(bus);
+    cpu.set_pc(pc);
+    true
+}
+
+fn block_0018<B: Bus>(cpu: &mut CPU, bus: &mut B, end: u64) -> bool {
+    if !cpu.native_boundary(bus, end) {
+        return false;
+    }
+    // 0x0018 RET
+    cpu.native_fetch(bus, 0xc9);
+    let pc = cpu.native_pop(bus);
+    cpu.set_pc(pc);
+    true
+}
+
+fn block_0020<B: Bus>(cpu: &mut CPU, bus: &mut B, end: u64) -> bool {
+    if !cpu.native_boundary(bus, end) {
+        return false;
+    }
+    // 0x0020 RET
+    cpu.native_fetch(bus, 0xc9);
+    let pc = cpu.native_pop(bus);
+    cpu.set_pc(pc);
+    true
+}
+
+fn block_0028<B: Bus>(cpu: &mut CPU, bus: &mut B, end: u64) -> bool {
+    if !cpu.native_boundary(bus, end) {
+        return false;
+    }
+    // 0x0028 RET
+    cpu.native_fetch(bus, 0xc9);
+    let pc = cpu.native_pop(bus);
+    cpu.set_pc(pc);
+    true
+}
+
+fn block_0030<B: Bus>(cpu: &mut CPU, bus: &mut B, end: u64) -> bool {
+    if !cpu.native_boundary(bus, end) {
+        return false;
+    }
+    // 0x0030 RET
+    cpu.native_fetch(bus, 0xc9);
+    let pc = cpu.native_pop(bus);
+    cpu.set_pc(pc);
+    true
+}
+
+fn block_0038<B: Bus>(cpu: &mut CPU, bus: &mut B, end: u64) -> bool {
+    if !cpu.native_boundary(bus, end) {
+        return false;
+    }
+    // 0x0038 INR C
+    cpu.native_fetch(bus, 0x0c);
+    let value = cpu.native_increment(cpu.register(C));
+    cpu.set_register(C, value);
+    cpu.set_pc(0x0039);
+    if !cpu.native_boundary(bus, end) {
+        return true;
+    }
+    // 0x0039 MOV A,C
+    cpu.native_fetch(bus, 0x79);
+    cpu.set_register(A, cpu.register(C));
+    cpu.set_pc(0x003a);
+    if !cpu.native_boundary(bus, end) {
+        return true;
+    }
+    // 0x003a OUT 0x03
+    cpu.native_fetch(bus, 0xd3);
+    cpu.native_output(bus, 0x03, cpu.register(A));
+    cpu.set_pc(0x003c);
+    true
+}
+
+fn block_003c<B: Bus>(cpu: &mut CPU, bus: &mut B, end: u64) -> bool {
+    if !cpu.native_boundary(bus, end) {
+        return false;
+    }
+    // 0x003c EI
+    cpu.native_fetch(bus, 0xfb);
+    cpu.native_enable_interrupts();
+    cpu.set_pc(0x003d);
+    if !cpu.native_boundary(bus, end) {
+        return true;
+    }
+    // 0x003d RET
+    cpu.native_fetch(bus, 0xc9);
+    let pc = cpu.native_pop(bus);
+    cpu.set_pc(pc);
+    true
+}
+
+fn block_0040<B: Bus>(cpu: &mut CPU, bus: &mut B, end: u64) -> bool {
+    if !cpu.native_boundary(bus, end) {
+        return false;
+    }
+    // 0x0040 LXI SP,0x9000
+    cpu.native_fetch(bus, 0x31);
+    cpu.set_register_pair(SP, 0x9000);
+    cpu.set_pc(0x0043);
+    if !cpu.native_boundary(bus, end) {
+        return true;
+    }
+    // 0x0043 EI
+    cpu.native_fetch(bus, 0xfb);
+    cpu.native_enable_interrupts();
+    cpu.set_pc(0x0044);
+    if !cpu.native_boundary(bus, end) {
+        return true;
+    }
+    // 0x0044 MVI B,0x10
+    cpu.native_fetch(bus, 0x06);
+    cpu.set_register(B, 0x10);
+    cpu.set_pc(0x0046);
+    if !cpu.native_boundary(bus, end) {
+        return true;
+    }
+    // 0x0046 LXI H,0x8000
+    cpu.native_fetch(bus, 0x21);
+    cpu.set_register_pair(HL, 0x8000);
+    cpu.set_pc(0x0049);
+    if !cpu.native_boundary(bus, end) {
+        return true;
+    }
+    // 0x0049 LXI D,0x8100
+    cpu.native_fetch(bus, 0x11);
+    cpu.set_register_pair(DE, 0x8100);
+    cpu.set_pc(0x004c);
+    true
+}
+
+fn block_004c<B: Bus>(cpu: &mut CPU, bus: &mut B, end: u64) -> bool {
+    if !cpu.native_boundary(bus, end) {
+        return false;
+    }
+    // 0x004c MOV A,B
+    cpu.native_fetch(bus, 0x78);
+    cpu.set_register(A, cpu.register(B));
+    cpu.set_pc(0x004d);
+    if !cpu.native_boundary(bus, end) {
+        return true;
+    }
+    // 0x004d RLC
+    cpu.native_fetch(bus, 0x07);
+    let a = cpu.register(A);
+    cpu.set_register(A, a.rotate_left(1));
+    cpu.set_flag(Flag::Carry, a & 0x80 != 0);
+    cpu.set_pc(0x004e);
+    if !cpu.native_boundary(bus, end) {
+        return true;
+    }
+    // 0x004e XRI 0x5a
+    cpu.native_fetch(bus, 0xee);
+    cpu.native_xor(0x5a);
+    cpu.set_pc(0x0050);
+    if !cpu.native_boundary(bus, end) {
+        return true;
+    }
+    // 0x0050 MOV M,A
+    cpu.native_fetch(bus, 0x77);
+    cpu.native_write(bus, cpu.register_pair(HL), cpu.register(A));
+    cpu.set_pc(0x0051);
+    if !cpu.native_boundary(bus, end) {
+        return true;
+    }
+    // 0x0051 INR M
+    cpu.native_fetch(bus, 0x34);
+    let addr = cpu.register_pair(HL);
+    let value = cpu.native_read(bus, addr);
+    let value = cpu.native_increment(value);
+    cpu.native_write(bus, addr, value);
+    cpu.set_pc(0x0052);
+    if !cpu.native_boundary(bus, end) {
+        return true;
+    }
+    // 0x0052 DAA
+    cpu.native_fetch(bus, 0x27);
+    cpu.native_daa();
+    cpu.set_pc(0x0053);
+    if !cpu.native_boundary(bus, end) {
+        return true;
+    }
+    // 0x0053 ADC M
+    cpu.native_fetch(bus, 0x8e);
+    let data = cpu.native_read(bus, cpu.register_pair(HL));
+    cpu.native_add(data, cpu.flag(Flag::Carry));
+    cpu.set_pc(0x0054);
+    if !cpu.native_boundary(bus, end) {
+        return true;
+    }
+    // 0x0054 CALL 0x0090
+    cpu.native_fetch(bus, 0xcd);
+    cpu.native_push(bus, 0x0057);
+    cpu.set_pc(0x0090);
+    true
+}
+
+fn block_0057<B: Bus>(cpu: &mut CPU, bus: &mut B, end: u64) -> bool {
+    if !cpu.native_boundary(bus, end) {
+        return false;
+    }
+    // 0x0057 CZ 0x0090
+    cpu.native_fetch(bus, 0xcc);
+    if cpu.flag(Flag::Zero) {
+        cpu.native_push(bus, 0x005a);
+        cpu.set_pc(0x0090);
+    } else {
+        cpu.native_not_taken(0xcc);
+        cpu.set_pc(0x005a);
+    }
+    true
+}
+
+fn block_005a<B: Bus>(cpu: &mut CPU, bus: &mut B, end: u64) -> bool {
+    if !cpu.native_boundary(bus, end) {
+        return false;
+    }
+    // 0x005a STAX D
+    cpu.native_fetch(bus, 0x12);
+    cpu.native_write(bus, cpu.register_pair(DE), cpu.register(A));
+    cpu.set_pc(0x005b);
+    if !cpu.native_boundary(bus, end) {
+        return true;
+    }
+    // 0x005b INX D
+    cpu.native_fetch(bus, 0x13);
+    cpu.set_register_pair(DE, cpu.register_pair(DE).wrapping_add(1));
+    cpu.set_pc(0x005c);
+    if !cpu.native_boundary(bus, end) {
+        return true;
+    }
+    // 0x005c INX H
+    cpu.native_fetch(bus, 0x23);
+    cpu.set_register_pair(HL, cpu.register_pair(HL).wrapping_add(1));
+    cpu.set_pc(0x005d);
+    if !cpu.native_boundary(bus, end) {
+        return true;
+    }
+    // 0x005d DCR B
+    cpu.native_fetch(bus, 0x05);
+    let value = cpu.native_decrement(cpu.register(B));
+    cpu.set_register(B, value);
+    cpu.set_pc(0x005e);
+    if !cpu.native_boundary(bus, end) {
+        return true;
+    }
+    // 0x005e JNZ 0x004c
+    cpu.native_fetch(bus, 0xc2);
+    cpu.set_pc(if !cpu.flag(Flag::Zero) { 0x004c } else { 0x0061 });
+    true
+}
+
+fn block_0061<B: Bus>(cpu: &mut CPU, bus: &mut B, end: u64) -> bool {
+    if !cpu.native_boundary(bus, end) {
+        return false;
+    }
+    // 0x0061 RST 4
+    cpu.native_fetch(bus, 0xe7);
+    cpu.native_push(bus, 0x0062);
+    cpu.set_pc(0x0020);
+    true
+}
+
+fn block_0062<B: Bus>(cpu: &mut CPU, bus: &mut B, end: u64) -> bool {
+    if !cpu.native_boundary(bus, end) {
+        return false;
+    }
+    // 0x0062 XCHG
+    cpu.native_fetch(bus, 0xeb);
+    let de = cpu.register_pair(DE);
+    cpu.set_register_pair(DE, cpu.register_pair(HL));
+    cpu.set_register_pair(HL, de);
+    cpu.set_pc(0x0063);
+    if !cpu.native_boundary(bus, end) {
+        return true;
+    }
+    // 0x0063 DAD SP
+    cpu.native_fetch(bus, 0x39);
+    let (hl, carry) = cpu.register_pair(HL).overflowing_add(cpu.register_pair(SP));
+    cpu.set_register_pair(HL, hl);
+    cpu.set_flag(Flag::Carry, carry);
+    cpu.set_pc(0x0064);
+    if !cpu.native_boundary(bus, end) {
+        return true;
+    }
+    // 0x0064 PUSH H
+    cpu.native_fetch(bus, 0xe5);
+    cpu.native_push(bus, cpu.register_pair(HL));
+    cpu.set_pc(0x0065);
+    if !cpu.native_boundary(bus, end) {
+        return true;
+    }
+    // 0x0065 XTHL
+    cpu.native_fetch(bus, 0xe3);
+    let sp = cpu.sp();
+    let data = cpu.native_read_word(bus, CycleKind::StackRead, sp);
+    cpu.native_write_word(bus, CycleKind::StackWrite, sp, cpu.register_pair(HL));
+    cpu.set_register_pair(HL, data);
+    cpu.set_pc(0x0066);
+    if !cpu.native_boundary(bus, end) {
+        return true;
+    }
+    // 0x0066 POP D
+    cpu.native_fetch(bus, 0xd1);
+    let value = cpu.native_pop(bus);
+    cpu.set_register_pair(DE, value);
+    cpu.set_pc(0x0067);
+    if !cpu.native_boundary(bus, end) {
+        return true;
+    }
+    // 0x0067 SHLD 0x8200
+    cpu.native_fetch(bus, 0x22);
+    cpu.native_write_word(bus, CycleKind::MemoryWrite, 0x8200, cpu.register_pair(HL));
+    cpu.set_pc(0x006a);
+    if !cpu.native_boundary(bus, end) {
+        return true;
+    }
+    // 0x006a LHLD 0x8200
+    cpu.native_fetch(bus, 0x2a);
+    let data = cpu.native_read_word(bus, CycleKind::MemoryRead, 0x8200);
+    cpu.set_register_pair(HL, data);
+    cpu.set_pc(0x006d);
+    if !cpu.native_boundary(bus, end) {
+        return true;
+    }
+    // 0x006d STA 0x8202
+    cpu.native_fetch(bus, 0x32);
+    cpu.native_write(bus, 0x8202, cpu.register(A));
+    cpu.set_pc(0x0070);
+    if !cpu.native_boundary(bus, end) {
+        return true;
+    }
+    // 0x0070 LDA 0x8000
+    cpu.native_fetch(bus, 0x3a);
+    let data = cpu.native_read(bus, 0x8000);
+    cpu.set_register(A, data);
+    cpu.set_pc(0x0073);
+    if !cpu.native_boundary(bus, end) {
+        return true;
+    }
+    // 0x0073 IN 0x01
+    cpu.native_fetch(bus, 0xdb);
+    let data = cpu.native_input(bus, 0x01);
+    cpu.set_register(A, data);
+    cpu.set_pc(0x0075);
+    if !cpu.native_boundary(bus, end) {
+        return true;
+    }
+    // 0x0075 OUT 0x02
+    cpu.native_fetch(bus, 0xd3);
+    cpu.native_output(bus, 0x02, cpu.register(A));
+    cpu.set_pc(0x0077);
+    true
+}
+
+fn block_0077<B: Bus>(cpu: &mut CPU, bus: &mut B, end: u64) -> bool {
+    if !cpu.native_boundary(bus, end) {
+        return false;
+    }
+    // 0x0077 CMA
+    cpu.native_fetch(bus, 0x2f);
+    cpu.set_register(A, !cpu.register(A));
+    cpu.set_pc(0x0078);
+    if !cpu.native_boundary(bus, end) {
+        return true;
+    }
+    // 0x0078 STC
+    cpu.native_fetch(bus, 0x37);
+    cpu.set_flag(Flag::Carry, true);
+    cpu.set_pc(0x0079);
+    if !cpu.native_boundary(bus, end) {
+        return true;
+    }
+    // 0x0079 RAR
+    cpu.native_fetch(bus, 0x1f);
+    let a = cpu.register(A);
+    cpu.set_register(A, (a >> 1) | (u8::from(cpu.flag(Flag::Carry)) << 7));
+    cpu.set_flag(Flag::Carry, a & 0x01 != 0);
+    cpu.set_pc(0x007a);
+    if !cpu.native_boundary(bus, end) {
+        return true;
+    }
+    // 0x007a CMC
+    cpu.native_fetch(bus, 0x3f);
+    cpu.set_flag(Flag::Carry, !cpu.flag(Flag::Carry));
+    cpu.set_pc(0x007b);
+    if !cpu.native_boundary(bus, end) {
+        return true;
+    }
+    // 0x007b RAL
+    cpu.native_fetch(bus, 0x17);
+    let a = cpu.register(A);
+    cpu.set_register(A, (a << 1) | u8::from(cpu.flag(Flag::Carry)));
+    cpu.set_flag(Flag::Carry, a & 0x80 != 0);
+    cpu.set_pc(0x007c);
+    if !cpu.native_boundary(bus, end) {
+        return true;
+    }
+    // 0x007c RRC
+    cpu.native_fetch(bus, 0x0f);
+    let a = cpu.register(A);
+    cpu.set_register(A, a.rotate_right(1));
+    cpu.set_flag(Flag::Carry, a & 0x01 != 0);
+    cpu.set_pc(0x007d);
+    if !cpu.native_boundary(bus, end) {
+        return true;
+    }
+    // 0x007d LXI H,0x00b0
+    cpu.native_fetch(bus, 0x21);
+    cpu.set_register_pair(HL, 0x00b0);
+    cpu.set_pc(0x0080);
+    if !cpu.native_boundary(bus, end) {
+        return true;
+    }
+    // 0x0080 PCHL
+    cpu.native_fetch(bus, 0xe9);
+    cpu.set_pc(cpu.register_pair(HL));
+    true
+}
+
+fn block_0090<B: Bus>(cpu: &mut CPU, bus: &mut B, end: u64) -> bool {
+    if !cpu.native_boundary(bus, end) {
+        return false;
+    }
+    // 0x0090 CPI 0x80
+    cpu.native_fetch(bus, 0xfe);
+    cpu.native_compare(0x80);
+    cpu.set_pc(0x0092);
+    if !cpu.native_boundary(bus, end) {
+        return true;
+    }
+    // 0x0092 RC
+    cpu.native_fetch(bus, 0xd8);
+    if cpu.flag(Flag::Carry) {
+        let pc = cpu.native_pop(bus);
+        cpu.set_pc(pc);
+    } else {
+        cpu.native_not_taken(0xd8);
+        cpu.set_pc(0x0093);
+    }
+    true
+}
+
+fn block_0093<B: Bus>(cpu: &mut CPU, bus: &mut B, end: u64) -> bool {
+    if !cpu.native_boundary(bus, end) {
+        return false;
+    }
+    // 0x0093 SUI 0x11
+    cpu.native_fetch(bus, 0xd6);
+    cpu.native_sub(0x11, false);
+    cpu.set_pc(0x0095);
+    if !cpu.native_boundary(bus, end) {
+        return true;
+    }
+    // 0x0095 ORA B
+    cpu.native_fetch(bus, 0xb0);
+    cpu.native_or(cpu.register(B));
+    cpu.set_pc(0x0096);
+    if !cpu.native_boundary(bus, end) {
+        return true;
+    }
+    // 0x0096 RET
+    cpu.native_fetch(bus, 0xc9);
+    let pc = cpu.native_pop(bus);
+    cpu.set_pc(pc);
+    true
+}
+
+fn block_00b0<B: Bus>(cpu: &mut CPU, bus: &mut B, end: u64) -> bool {
+    if !cpu.native_boundary(bus, end) {
+        return false;
+    }
+    // 0x00b0 DCX B
+    cpu.native_fetch(bus, 0x0b);
+    cpu.set_register_pair(BC, cpu.register_pair(BC).wrapping_sub(1));
+    cpu.set_pc(0x00b1);
+    if !cpu.native_boundary(bus, end) {
+        return true;
+    }
+    // 0x00b1 SBB C
+    cpu.native_fetch(bus, 0x99);
+    cpu.native_sub(cpu.register(C), cpu.flag(Flag::Carry));
+    cpu.set_pc(0x00b2);
+    if !cpu.native_boundary(bus, end) {
+        return true;
+    }
+    // 0x00b2 LXI H,0x8000
+    cpu.native_fetch(bus, 0x21);
+    cpu.set_register_pair(HL, 0x8000);
+    cpu.set_pc(0x00b5);
+    if !cpu.native_boundary(bus, end) {
+        return true;
+    }
+    // 0x00b5 MVI M,0x3c
+    cpu.native_fetch(bus, 0x36);
+    cpu.native_write(bus, cpu.register_pair(HL), 0x3c);
+    cpu.set_pc(0x00b7);
+    if !cpu.native_boundary(bus, end) {
+        return true;
+    }
+    // 0x00b7 DCR M
+    cpu.native_fetch(bus, 0x35);
+    let addr = cpu.register_pair(HL);
+    let value = cpu.native_read(bus, addr);
+    let value = cpu.native_decrement(value);
+    cpu.native_write(bus, addr, value);
+    cpu.set_pc(0x00b8);
+    if !cpu.native_boundary(bus, end) {
+        return true;
+    }
+    // 0x00b8 ANA M
+    cpu.native_fetch(bus, 0xa6);
+    let data = cpu.native_read(bus, cpu.register_pair(HL));
+    cpu.native_and(data);
+    cpu.set_pc(0x00b9);
+    if !cpu.native_boundary(bus, end) {
+        return true;
+    }
+    // 0x00b9 LDAX B
+    cpu.native_fetch(bus, 0x0a);
+    let data = cpu.native_read(bus, cpu.register_pair(BC));
+    cpu.set_register(A, data);
+    cpu.set_pc(0x00ba);
+    if !cpu.native_boundary(bus, end) {
+        return true;
+    }
+    // 0x00ba CMP D
+    cpu.native_fetch(bus, 0xba);
+    cpu.native_compare(cpu.register(D));
+    cpu.set_pc(0x00bb);
+    if !cpu.native_boundary(bus, end) {
+        return true;
+    }
+    // 0x00bb SPHL
+    cpu.native_fetch(bus, 0xf9);
+    cpu.set_sp(cpu.register_pair(HL));
+    cpu.set_pc(0x00bc);
+    if !cpu.native_boundary(bus, end) {
+        return true;
+    }
+    // 0x00bc DI
+    cpu.native_fetch(bus, 0xf3);
+    cpu.native_disable_interrupts();
+    cpu.set_pc(0x00bd);
+    if !cpu.native_boundary(bus, end) {
+        return true;
+    }
+    // 0x00bd HLT
+    cpu.native_fetch(bus, 0x76);
+    cpu.native_halt();
+    cpu.set_pc(0x00be);
+    true
+}
+
+fn block_00be<B: Bus>(cpu: &mut CPU, bus: &mut B, end: u64) -> bool {
+    if !cpu.native_boundary(bus, end) {
+        return false;
+    }
+    // 0x00be RET
+    cpu.native_fetch(bus, 0xc9);
+    let pc = cpu.native_pop(bus);
+    cpu.set_pc(pc);
+    true
+}
@@ -0,0 +1,204 @@
+//! Checks code compiled by a `Recompiler` against the interpreter.
+//!
+//! `recompiled/small.rs` is the output of the `Recompiler` for `PROGRAM`, and
+//! is checked in so that it is compiled along with this test. Run the tests
+//! with `BLESS` set to write it again after changing the `Recompiler`.
+
+use intel8080::{Bus, CPU, MemoryMap, Native, Recompiler};
+use std::path::Path;
+
+// Formatted as emitted, so that it can be compared with the output
+#[rustfmt::skip]
+#[path = "recompiled/small.rs"]
+mod small;
+
+/// A small program in ROM exercising every kind of instruction the
+/// `Recompiler` emits, as runs of bytes by address. Memory outside it holds
+/// RET, so RST 4 returns straight away.
+const PROGRAM: &[(u16, &[u8])] = &[
+    (
+        0x0000,
+        &[
+            0xc3, 0x40, 0x00, // JMP 0x0040
+        ],
+    ),
+    (
+        0x0038,
+        &[
+            0x0c, // INR C
+            0x79, // MOV A,C
+            0xd3, 0x03, // OUT 0x03
+            0xfb, // EI
+            0xc9, // RET
+        ],
+    ),
+    (
+        0x0040,
+        &[
+            0x31, 0x00, 0x90, // LXI SP,0x9000
+            0xfb, // EI
+            0x06, 0x10, // MVI B,0x10
+            0x21, 0x00, 0x80, // LXI H,0x8000
+            0x11, 0x00, 0x81, // LXI D,0x8100
+            0x78, // MOV A,B
+            0x07, // RLC
+            0xee, 0x5a, // XRI 0x5a
+            0x77, // MOV M,A
+            0x34, // INR M
+            0x27, // DAA
+            0x8e, // ADC M
+            0xcd, 0x90, 0x00, // CALL 0x0090
+            0xcc, 0x90, 0x00, // CZ 0x0090
+            0x12, // STAX D
+            0x13, // INX D
+            0x23, // INX H
+            0x05, // DCR B
+            0xc2, 0x4c, 0x00, // JNZ 0x004c
+            0xe7, // RST 4
+            0xeb, // XCHG
+            0x39, // DAD SP
+            0xe5, // PUSH H
+            0xe3, // XTHL
+            0xd1, // POP D
+            0x22, 0x00, 0x82, // SHLD 0x8200
+            0x2a, 0x00, 0x82, // LHLD 0x8200
+            0x32, 0x02, 0x82, // STA 0x8202
+            0x3a, 0x00, 0x80, // LDA 0x8000
+            0xdb, 0x01, // IN 0x01
+            0xd3, 0x02, // OUT 0x02
+            0x2f, // CMA
+            0x37, // STC
+            0x1f, // RAR
+            0x3f, // CMC
+            0x17, // RAL
+            0x0f, // RRC
+            0x21, 0xb0, 0x00, // LXI H,0x00b0
+            0xe9, // PCHL
+        ],
+    ),
+    (
+        0x0090,
+        &[
+            0xfe, 0x80, // CPI 0x80
+            0xd8, // RC
+            0xd6, 0x11, // SUI 0x11
+            0xb0, // ORA B
+            0xc9, // RET
+        ],
+    ),
+    (
+        0x00b0,
+        &[
+            0x0b, // DCX B
+            0x99, // SBB C
+            0x21, 0x00, 0x80, // LXI H,0x8000
+            0x36, 0x3c, // MVI M,0x3c
+            0x35, // DCR M
+            0xa6, // ANA M
+            0x0a, // LDAX B
+            0xba, // CMP D
+            0xf9, // SPHL
+            0xf3, // DI
+            0x76, // HLT
+        ],
+    ),
+];
+
+/// T-states to run for, well past the HLT ending `PROGRAM`.
+const CYCLES: u64 = 20_000;
+
+/// Records port accesses and requests an interrupt every few hundred T-states.
+#[derive(Default)]
+struct Io {
+    /// Port accesses as (T-state, PC, port, data).
+    accesses: Vec<(u64, u16, u8, u8)>,
+    /// Opcodes fetched through the bus, which compiled code does not fetch.
+    fetches: u64,
+    /// T-state from which an interrupt is requested.
+    interrupt: u64,
+}
+
+impl Bus for Io {
+    fn read(&mut self, cpu: &CPU, port: u8) -> u8 {
+        let data = cpu.cycles() as u8;
+        self.accesses.push((cpu.cycles(), cpu.pc(), port, data));
+        data
+    }
+
+    fn write(&mut self, cpu: &CPU, port: u8, data: u8) {
+        self.accesses.push((cpu.cycles(), cpu.pc(), port, data));
+    }
+
+    fn fetch(&mut self, _cpu: &CPU, _addr: u16) -> Option<u8> {
+        self.fetches += 1;
+        None
+    }
+
+    fn interrupt_requested(&mut self, cpu: &CPU) -> bool {
+        cpu.cycles() >= self.interrupt
+    }
+
+    fn acknowledge(&mut self, cpu: &CPU) -> u8 {
+        self.interrupt = cpu.cycles() + 300;
+        0xff
+    }
+}
+
+/// Returns a [`CPU`] with `PROGRAM` in ROM, behind `wait_states` wait states.
+fn cpu(wait_states: u8) -> CPU {
+    let map = MemoryMap::new()
+        .rom(0x0000..=0x00ff)
+        .wait_states(0x0000..=0xffff, wait_states);
+
+    PROGRAM
+        .iter()
+        .fold(CPU::builder().fill(0xc9), |builder, &(addr, bytes)| {
+            builder.load(addr, bytes)
+        })
+        .memory_map(map)
+        .build()
+        .unwrap()
+}
+
+/// Runs `PROGRAM` through the interpreter and through the compiled code, and
+/// checks that both end in the same state having made the same port accesses.
+fn check(wait_states: u8) {
+    let mut interpreted = cpu(wait_states);
+    let mut interpreted_io = Io::default();
+    while interpreted.cycles() < CYCLES {
+        interpreted.cycle(&mut interpreted_io);
+    }
+
+    let mut native = cpu(wait_states);
+    let mut native_io = Io::default();
+    Native::<small::Small>::new().run(&mut native, &mut native_io, CYCLES);
+
+    assert!(native.halted());
+    assert_eq!(native.save_state(), interpreted.save_state());
+    assert_eq!(native_io.accesses, interpreted_io.accesses);
+    assert!(native_io.accesses.len() > 10);
+    // Nearly everything ran as compiled code
+    assert!(native_io.fetches * 10 < native.instructions());
+}
+
+#[test]
+fn recompiled_source() {
+    let source = Recompiler::new(&cpu(0)).entry(0x00b0).compile("Small");
+    let path = Path::new(file!()).with_file_name("recompiled/small.rs");
+
+    if std::env::var_os("BLESS").is_some() {
+        std::fs::write(path, &source).unwrap();
+    } else {
+        assert_eq!(source, include_str!("recompiled/small.rs"));
+    }
+}
+
+#[test]
+fn recompiled_matches_interpreter() {
+    check(0);
+}
+
+#[test]
+fn recompiled_matches_interpreter_with_wait_states() {
+    check(2);
+}